@@ -3,22 +3,48 @@ create table if not exists bc_billa_category (
     bc_id uuid default gen_random_uuid() primary key,
     bc_text character varying(256) not null
 );
-cs_crawl_session drop table if exists bcw_billa_crawl;
+create unique index bc_billa_category_bc_text_idx on bc_billa_category(bc_text);
+drop table if exists bcw_billa_crawl;
 create table if not exists bcw_billa_crawl (
     bcw_id uuid default gen_random_uuid() primary key,
-    bcw_created timestamp default current_timestamp
+    bcw_created timestamp default current_timestamp,
+    bcw_status character varying(16) default 'running',
+    bcw_finished timestamp,
+    bcw_pages integer not null default 0,
+    bcw_products integer not null default 0
+);
+drop table if exists rb_raw_blob;
+create table if not exists rb_raw_blob (
+    -- sha-256 of the body, hex encoded
+    rb_hash character(64) primary key,
+    rb_created timestamp default current_timestamp,
+    rb_size bigint not null,
+    rb_compressed_size bigint not null,
+    -- the zstd compressed body, null if it's offloaded to rb_location
+    rb_body bytea,
+    rb_location character varying(512)
 );
 drop table if exists br_billa_raw;
+-- partitioned by month, see src/partition.rs
 create table if not exists br_billa_raw (
-    br_id uuid default gen_random_uuid() primary key,
+    br_id uuid default gen_random_uuid(),
     br_raw text,
-    br_created timestamp default current_timestamp,
+    br_created timestamp not null default current_timestamp,
     br_url character varying(256) not null,
     br_err text default null,
     br_bcw_crawl uuid not null,
-);
+    -- if the products and prices of the document are stored
+    br_stored boolean not null default false,
+    -- the body, br_raw of older documents
+    br_rb_blob character(64),
+    -- the file the body was archived to by prune
+    br_archive character varying(512),
+    primary key (br_id, br_created)
+) partition by range (br_created);
 ALTER TABLE br_billa_raw
 ADD CONSTRAINT br_bcw_crawler_fk FOREIGN KEY (br_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+ALTER TABLE br_billa_raw
+ADD CONSTRAINT br_rb_blob_fk FOREIGN KEY (br_rb_blob) REFERENCES rb_raw_blob(rb_hash);
 drop table if exists bpo_billa_product;
 create table if not exists bpo_billa_product (
     bpo_id uuid default gen_random_uuid() primary key,
@@ -30,42 +56,74 @@ create table if not exists bpo_billa_product (
     bpo_brand character varying(256),
     bpo_badge character varying(256),
     bpo_unit character varying(256),
-    bpo_price_factor float,
+    bpo_price_factor numeric,
     bpo_grammage character varying(256),
-    bpo_bc_category uuid not null
+    bpo_bc_category uuid not null,
+    bpo_delisted timestamp default null,
+    bpo_relisted timestamp default null,
+    bpo_search tsvector generated always as (
+        setweight(to_tsvector('german', bpo_name), 'A') ||
+        setweight(to_tsvector('german', coalesce(bpo_brand, '')), 'B') ||
+        setweight(to_tsvector('german', coalesce(bpo_description, '')), 'C')
+    ) stored
 );
+create index bpo_billa_product_search_idx on bpo_billa_product using gin(bpo_search);
 create unique index bpo_billa_product_bpo_billa_id_idx on bpo_billa_product(bpo_billa_id);
 ALTER TABLE bpo_billa_product
 ADD CONSTRAINT bpo_billa_category_fk FOREIGN KEY (bpo_bc_category) REFERENCES bc_billa_category(bc_id);
+drop view if exists bp_billa_price_series;
 drop table if exists bp_billa_price;
+-- partitioned by month, see src/partition.rs
 create table if not exists bp_billa_price (
-    bp_id uuid default gen_random_uuid() primary key,
-    bp_created timestamp default current_timestamp,
-    bp_normal float,
+    bp_id uuid default gen_random_uuid(),
+    -- br_created of bp_br_raw, so a price is in the partition of its document
+    bp_created timestamp not null default current_timestamp,
+    bp_normal numeric,
     bp_unit character varying(256),
     bp_bpo_product uuid not null,
-    bp_br_raw uuid not null
-);
+    bp_br_raw uuid not null,
+    bp_valid_from timestamp not null default current_timestamp,
+    bp_valid_to timestamp default null,
+    bp_last_bcw_crawl uuid not null,
+    bp_discounted boolean not null default false,
+    primary key (bp_id, bp_created)
+) partition by range (bp_created);
+-- unique indexes of partitioned tables need the partition key, a product can only have one open
+-- interval as the prices of a store are saved one after another
+create index bp_billa_price_open_idx on bp_billa_price(bp_bpo_product) where bp_valid_to is null;
 ALTER TABLE bp_billa_price
 ADD CONSTRAINT bp_billa_price_fk FOREIGN KEY (bp_bpo_product) REFERENCES bpo_billa_product(bpo_id);
 ALTER TABLE bp_billa_price
-ADD CONSTRAINT bp_billa_raw_fk FOREIGN KEY (bp_br_raw) REFERENCES br_billa_raw(br_id);
+ADD CONSTRAINT bp_billa_raw_fk FOREIGN KEY (bp_br_raw, bp_created) REFERENCES br_billa_raw(br_id, br_created);
+ALTER TABLE bp_billa_price
+ADD CONSTRAINT bp_billa_last_crawl_fk FOREIGN KEY (bp_last_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
 drop table if exists sc_spar_category;
 create table if not exists sc_spar_category (
     sc_id uuid default gen_random_uuid() primary key,
     sc_text character varying(256) not null
 );
+create unique index sc_spar_category_sc_text_idx on sc_spar_category(sc_text);
 drop table if exists sr_spar_raw;
+-- partitioned by month, see src/partition.rs
 create table if not exists sr_spar_raw (
-    sr_id uuid default gen_random_uuid() primary key,
+    sr_id uuid default gen_random_uuid(),
     sr_raw text,
-    sr_created timestamp default current_timestamp,
+    sr_created timestamp not null default current_timestamp,
     sr_url character varying(256) not null,
     sr_err text default null,
-    sr_cs_crawl_session uuid not null
-);
+    sr_cs_crawl_session uuid not null,
+    -- if the products and prices of the document are stored
+    sr_stored boolean not null default false,
+    -- the body, sr_raw of older documents
+    sr_rb_blob character(64),
+    -- the file the body was archived to by prune
+    sr_archive character varying(512),
+    primary key (sr_id, sr_created)
+) partition by range (sr_created);
 ALTER TABLE sr_spar_raw
 ADD CONSTRAINT sr_spar_raw_crawler_fk FOREIGN KEY (sr_cs_crawl_session) REFERENCES bcw_billa_crawl(bcw_id);
+ALTER TABLE sr_spar_raw
+ADD CONSTRAINT sr_rb_blob_fk FOREIGN KEY (sr_rb_blob) REFERENCES rb_raw_blob(rb_hash);
 drop table if exists sp_spar_product;
 create table if not exists sp_spar_product (
     sp_id uuid default gen_random_uuid() primary key,
@@ -75,21 +133,171 @@ create table if not exists sp_spar_product (
     sp_online_shop_url character varying(256) not null,
     sp_name character varying(256) not null,
     sp_brand character varying(256),
-    sp_sc_category uuid not null
+    sp_sc_category uuid not null,
+    sp_delisted timestamp default null,
+    sp_relisted timestamp default null,
+    sp_search tsvector generated always as (
+        setweight(to_tsvector('german', sp_name), 'A') ||
+        setweight(to_tsvector('german', coalesce(sp_brand, '')), 'B') ||
+        setweight(to_tsvector('german', coalesce(sp_description, '')), 'C')
+    ) stored
 );
+create index sp_spar_product_search_idx on sp_spar_product using gin(sp_search);
+create unique index sp_spar_product_sp_spar_id_idx on sp_spar_product(sp_spar_id);
 ALTER TABLE sp_spar_product
 ADD CONSTRAINT sp_spar_product_category_fk FOREIGN KEY (sp_sc_category) REFERENCES sc_spar_category(sc_id);
+drop view if exists spr_spar_price_series;
 drop table if exists spr_spar_price;
+-- partitioned by month, see src/partition.rs
 create table if not exists spr_spar_price (
-    spr_id uuid default gen_random_uuid() primary key,
-    spr_p_created timestamp default current_timestamp,
-    spr_price float,
+    spr_id uuid default gen_random_uuid(),
+    -- sr_created of spr_sr_raw, so a price is in the partition of its document
+    spr_p_created timestamp not null default current_timestamp,
+    spr_price numeric,
     spr_sales_unit character varying(256),
     spr_price_unit character varying(256),
     spr_sp_product uuid not null,
-    spr_sr_raw uuid not null
-);
+    spr_sr_raw uuid not null,
+    spr_valid_from timestamp not null default current_timestamp,
+    spr_valid_to timestamp default null,
+    spr_last_cs_crawl_session uuid not null,
+    primary key (spr_id, spr_p_created)
+) partition by range (spr_p_created);
+create index spr_spar_price_open_idx on spr_spar_price(spr_sp_product) where spr_valid_to is null;
 ALTER TABLE spr_spar_price
 ADD CONSTRAINT spr_spar_price_product_fk FOREIGN KEY (spr_sp_product) REFERENCES sp_spar_product(sp_id);
 ALTER TABLE spr_spar_price
-ADD CONSTRAINT spr_spar_price_raw_fk FOREIGN KEY (spr_sr_raw) REFERENCES sr_spar_raw(sr_id);
\ No newline at end of file
+ADD CONSTRAINT spr_spar_price_raw_fk FOREIGN KEY (spr_sr_raw, spr_p_created) REFERENCES sr_spar_raw(sr_id, sr_created);
+ALTER TABLE spr_spar_price
+ADD CONSTRAINT spr_spar_price_last_crawl_fk FOREIGN KEY (spr_last_cs_crawl_session) REFERENCES bcw_billa_crawl(bcw_id);
+drop table if exists wl_watch;
+create table if not exists wl_watch (
+    wl_id uuid default gen_random_uuid() primary key,
+    wl_created timestamp default current_timestamp,
+    wl_store character varying(16),
+    wl_article_id character varying(256),
+    wl_search text,
+    wl_rule character varying(16) not null,
+    wl_threshold numeric
+);
+drop table if exists cp_crawl_progress;
+create table if not exists cp_crawl_progress (
+    cp_bcw_crawl uuid not null,
+    cp_store character varying(16) not null,
+    cp_category character varying(256) not null,
+    cp_next_page integer,
+    cp_updated timestamp default current_timestamp,
+    primary key (cp_bcw_crawl, cp_store, cp_category)
+);
+ALTER TABLE cp_crawl_progress
+ADD CONSTRAINT cp_crawl_progress_crawl_fk FOREIGN KEY (cp_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+drop view if exists rs_request_stats;
+drop table if exists rj_request_journal;
+create table if not exists rj_request_journal (
+    rj_id uuid default gen_random_uuid() primary key,
+    rj_created timestamp default current_timestamp,
+    rj_bcw_crawl uuid not null,
+    rj_store character varying(16) not null,
+    rj_url character varying(256) not null,
+    -- null if no response arrived, see rj_error
+    rj_status integer,
+    rj_headers jsonb not null default '{}',
+    rj_duration_ms bigint not null,
+    rj_bytes bigint not null,
+    rj_attempt integer not null,
+    rj_user_agent character varying(256) not null,
+    rj_error text
+);
+create index rj_request_journal_crawl_idx on rj_request_journal(rj_bcw_crawl);
+ALTER TABLE rj_request_journal
+ADD CONSTRAINT rj_request_journal_crawl_fk FOREIGN KEY (rj_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+drop view if exists qs_queue_status;
+drop table if exists qj_queue_job;
+create table if not exists qj_queue_job (
+    qj_id uuid default gen_random_uuid() primary key,
+    qj_created timestamp default current_timestamp,
+    qj_bcw_crawl uuid not null,
+    qj_store character varying(16) not null,
+    qj_category character varying(256) not null,
+    qj_page integer not null,
+    qj_status character varying(16) not null default 'queued',
+    qj_attempts integer not null default 0,
+    qj_run_after timestamp not null default current_timestamp,
+    qj_locked_until timestamp,
+    qj_worker character varying(256),
+    qj_product_count integer,
+    qj_error text,
+    qj_finished timestamp,
+    -- digest of the page before, to detect a shop repeating it
+    qj_previous_page bigint,
+    qj_category_started timestamp not null default current_timestamp
+);
+create unique index qj_queue_job_page_idx on qj_queue_job(qj_bcw_crawl, qj_store, qj_category, qj_page);
+create index qj_queue_job_claim_idx on qj_queue_job(qj_run_after) where qj_status in ('queued', 'running');
+ALTER TABLE qj_queue_job
+ADD CONSTRAINT qj_queue_job_crawl_fk FOREIGN KEY (qj_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+drop table if exists qc_queue_crawl;
+create table if not exists qc_queue_crawl (
+    qc_bcw_crawl uuid not null,
+    qc_store character varying(16) not null,
+    qc_finished timestamp,
+    qc_complete boolean,
+    primary key (qc_bcw_crawl, qc_store)
+);
+ALTER TABLE qc_queue_crawl
+ADD CONSTRAINT qc_queue_crawl_crawl_fk FOREIGN KEY (qc_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+-- a price row is valid from the crawl of its raw document up to its last seen crawl
+create or replace view bp_billa_price_series as
+select crawl.bcw_id, crawl.bcw_created, bp_billa_price.*
+from bp_billa_price
+    join br_billa_raw on bp_br_raw = br_id
+    join bcw_billa_crawl first_crawl on br_bcw_crawl = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on bp_last_bcw_crawl = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from br_billa_raw crawled
+        where crawled.br_bcw_crawl = crawl.bcw_id
+    );
+create or replace view spr_spar_price_series as
+select crawl.bcw_id, crawl.bcw_created, spr_spar_price.*
+from spr_spar_price
+    join sr_spar_raw on spr_sr_raw = sr_id
+    join bcw_billa_crawl first_crawl on sr_cs_crawl_session = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on spr_last_cs_crawl_session = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from sr_spar_raw crawled
+        where crawled.sr_cs_crawl_session = crawl.bcw_id
+    );
+-- progress of the stores of a crawl session run by the job queue
+create or replace view qs_queue_status as
+select qc_bcw_crawl,
+    qc_store,
+    count(qj_id) filter (where qj_status = 'queued') as qs_queued,
+    count(qj_id) filter (where qj_status = 'running') as qs_running,
+    count(qj_id) filter (where qj_status = 'done') as qs_done,
+    count(qj_id) filter (where qj_status = 'failed') as qs_failed,
+    count(qj_id) filter (where qj_attempts > 1) as qs_retried,
+    coalesce(sum(qj_product_count), 0) as qs_products,
+    qc_finished,
+    qc_complete
+from qc_queue_crawl
+    left join qj_queue_job on qj_bcw_crawl = qc_bcw_crawl and qj_store = qc_store
+group by qc_bcw_crawl, qc_store, qc_finished, qc_complete;
+-- requests of the stores of a crawl session, to see when the shops block, throttle or slow down
+create or replace view rs_request_stats as
+select rj_bcw_crawl,
+    rj_store,
+    min(rj_created) as rs_started,
+    count(*) as rs_requests,
+    count(*) filter (where rj_status is null) as rs_failed,
+    count(*) filter (where rj_status in (403, 429)) as rs_blocked,
+    count(*) filter (where rj_status >= 500) as rs_server_errors,
+    count(*) filter (where rj_attempt > 1) as rs_retries,
+    avg(rj_duration_ms)::bigint as rs_avg_ms,
+    (percentile_cont(0.95) within group (order by rj_duration_ms))::bigint as rs_p95_ms,
+    sum(rj_bytes)::bigint as rs_bytes
+from rj_request_journal
+group by rj_bcw_crawl, rj_store;
@@ -0,0 +1,59 @@
+create table if not exists crawl (
+    id blob primary key,
+    created text not null default current_timestamp
+);
+create table if not exists document (
+    id blob primary key,
+    store text not null,
+    crawl_id blob not null references crawl(id),
+    url text not null,
+    body text,
+    error text,
+    created text not null default current_timestamp
+);
+create table if not exists category (
+    id blob primary key,
+    store text not null,
+    name text not null,
+    unique (store, name)
+);
+create table if not exists product (
+    id blob primary key,
+    store text not null,
+    article_id text not null,
+    category_id blob not null references category(id),
+    name text not null,
+    description text not null,
+    brand text not null,
+    url text not null,
+    grammage text not null,
+    unit text not null,
+    details text not null,
+    created text not null default current_timestamp,
+    delisted text,
+    relisted text,
+    unique (store, article_id)
+);
+create table if not exists price (
+    id integer primary key,
+    product_id blob not null references product(id),
+    document_id blob not null references document(id),
+    price text not null,
+    unit_price text,
+    details text not null,
+    created text not null default current_timestamp
+);
+create table if not exists request (
+    id integer primary key,
+    crawl_id blob not null references crawl(id),
+    store text not null,
+    url text not null,
+    status integer,
+    headers text not null,
+    duration_ms integer not null,
+    bytes integer not null,
+    attempt integer not null,
+    user_agent text not null,
+    error text,
+    created text not null default current_timestamp
+);
@@ -0,0 +1,131 @@
+-- Stores prices as validity intervals instead of one row per crawl.
+--
+-- Consecutive observations of a product with the same price are collapsed into the first one,
+-- which is kept valid until the next different price and remembers the last crawl it was seen in.
+begin;
+
+alter table bp_billa_price
+    add column bp_valid_from timestamp,
+    add column bp_valid_to timestamp default null,
+    add column bp_last_bcw_crawl uuid;
+
+create temporary table bp_island on commit drop as
+with observation as (
+    select bp_id, bp_bpo_product, bp_normal, bp_unit, bcw_id, bcw_created,
+        case
+            when lag(bp_normal) over w is not distinct from bp_normal
+                and lag(bp_unit) over w is not distinct from bp_unit
+            then 0
+            else 1
+        end as changed
+    from bp_billa_price
+        join br_billa_raw on bp_br_raw = br_id
+        join bcw_billa_crawl on br_bcw_crawl = bcw_id
+    window w as (partition by bp_bpo_product order by bcw_created, bp_id)
+),
+numbered as (
+    select *, sum(changed) over (partition by bp_bpo_product order by bcw_created, bp_id rows unbounded preceding) as island
+    from observation
+),
+island as (
+    select bp_bpo_product, island,
+        (array_agg(bp_id order by bcw_created, bp_id))[1] as first_price,
+        min(bcw_created) as valid_from,
+        (array_agg(bcw_id order by bcw_created desc, bp_id desc))[1] as last_crawl
+    from numbered
+    group by bp_bpo_product, island
+)
+select first_price, valid_from, last_crawl,
+    lead(valid_from) over (partition by bp_bpo_product order by island) as valid_to
+from island;
+
+delete from bp_billa_price where bp_id not in (select first_price from bp_island);
+
+update bp_billa_price
+set bp_valid_from = valid_from, bp_valid_to = valid_to, bp_last_bcw_crawl = last_crawl
+from bp_island
+where bp_id = first_price;
+
+alter table bp_billa_price
+    alter column bp_valid_from set not null,
+    alter column bp_valid_from set default current_timestamp,
+    alter column bp_last_bcw_crawl set not null,
+    add constraint bp_billa_last_crawl_fk foreign key (bp_last_bcw_crawl) references bcw_billa_crawl(bcw_id);
+create unique index bp_billa_price_open_idx on bp_billa_price(bp_bpo_product) where bp_valid_to is null;
+
+alter table spr_spar_price
+    add column spr_valid_from timestamp,
+    add column spr_valid_to timestamp default null,
+    add column spr_last_cs_crawl_session uuid;
+
+create temporary table spr_island on commit drop as
+with observation as (
+    select spr_id, spr_sp_product, bcw_id, bcw_created,
+        case
+            when lag(spr_price) over w is not distinct from spr_price
+                and lag(spr_sales_unit) over w is not distinct from spr_sales_unit
+                and lag(spr_price_unit) over w is not distinct from spr_price_unit
+            then 0
+            else 1
+        end as changed
+    from spr_spar_price
+        join sr_spar_raw on spr_sr_raw = sr_id
+        join bcw_billa_crawl on sr_cs_crawl_session = bcw_id
+    window w as (partition by spr_sp_product order by bcw_created, spr_id)
+),
+numbered as (
+    select *, sum(changed) over (partition by spr_sp_product order by bcw_created, spr_id rows unbounded preceding) as island
+    from observation
+),
+island as (
+    select spr_sp_product, island,
+        (array_agg(spr_id order by bcw_created, spr_id))[1] as first_price,
+        min(bcw_created) as valid_from,
+        (array_agg(bcw_id order by bcw_created desc, spr_id desc))[1] as last_crawl
+    from numbered
+    group by spr_sp_product, island
+)
+select first_price, valid_from, last_crawl,
+    lead(valid_from) over (partition by spr_sp_product order by island) as valid_to
+from island;
+
+delete from spr_spar_price where spr_id not in (select first_price from spr_island);
+
+update spr_spar_price
+set spr_valid_from = valid_from, spr_valid_to = valid_to, spr_last_cs_crawl_session = last_crawl
+from spr_island
+where spr_id = first_price;
+
+alter table spr_spar_price
+    alter column spr_valid_from set not null,
+    alter column spr_valid_from set default current_timestamp,
+    alter column spr_last_cs_crawl_session set not null,
+    add constraint spr_spar_price_last_crawl_fk foreign key (spr_last_cs_crawl_session) references bcw_billa_crawl(bcw_id);
+create unique index spr_spar_price_open_idx on spr_spar_price(spr_sp_product) where spr_valid_to is null;
+
+create or replace view bp_billa_price_series as
+select crawl.bcw_id, crawl.bcw_created, bp_billa_price.*
+from bp_billa_price
+    join br_billa_raw on bp_br_raw = br_id
+    join bcw_billa_crawl first_crawl on br_bcw_crawl = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on bp_last_bcw_crawl = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from br_billa_raw crawled
+        where crawled.br_bcw_crawl = crawl.bcw_id
+    );
+create or replace view spr_spar_price_series as
+select crawl.bcw_id, crawl.bcw_created, spr_spar_price.*
+from spr_spar_price
+    join sr_spar_raw on spr_sr_raw = sr_id
+    join bcw_billa_crawl first_crawl on sr_cs_crawl_session = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on spr_last_cs_crawl_session = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from sr_spar_raw crawled
+        where crawled.sr_cs_crawl_session = crawl.bcw_id
+    );
+
+commit;
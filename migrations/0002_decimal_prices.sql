@@ -0,0 +1,42 @@
+-- Stores prices as exact decimals instead of floats.
+--
+-- The crawler wrote the prices as `f32`, going through `real` turns them back into the decimal
+-- the shop published, e.g. 2.2899999618530273 into 2.29.
+begin;
+
+drop view if exists bp_billa_price_series;
+drop view if exists spr_spar_price_series;
+
+alter table bpo_billa_product
+    alter column bpo_price_factor type numeric using bpo_price_factor::real::numeric;
+alter table bp_billa_price
+    alter column bp_normal type numeric using bp_normal::real::numeric;
+alter table spr_spar_price
+    alter column spr_price type numeric using spr_price::real::numeric;
+
+create or replace view bp_billa_price_series as
+select crawl.bcw_id, crawl.bcw_created, bp_billa_price.*
+from bp_billa_price
+    join br_billa_raw on bp_br_raw = br_id
+    join bcw_billa_crawl first_crawl on br_bcw_crawl = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on bp_last_bcw_crawl = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from br_billa_raw crawled
+        where crawled.br_bcw_crawl = crawl.bcw_id
+    );
+create or replace view spr_spar_price_series as
+select crawl.bcw_id, crawl.bcw_created, spr_spar_price.*
+from spr_spar_price
+    join sr_spar_raw on spr_sr_raw = sr_id
+    join bcw_billa_crawl first_crawl on sr_cs_crawl_session = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on spr_last_cs_crawl_session = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from sr_spar_raw crawled
+        where crawled.sr_cs_crawl_session = crawl.bcw_id
+    );
+
+commit;
@@ -0,0 +1,11 @@
+-- Marks products which disappear from a store and the ones which come back.
+begin;
+
+alter table bpo_billa_product
+    add column bpo_delisted timestamp default null,
+    add column bpo_relisted timestamp default null;
+alter table sp_spar_product
+    add column sp_delisted timestamp default null,
+    add column sp_relisted timestamp default null;
+
+commit;
@@ -0,0 +1,20 @@
+-- German full-text search over the name, brand and description of the products.
+begin;
+
+alter table bpo_billa_product
+    add column bpo_search tsvector generated always as (
+        setweight(to_tsvector('german', bpo_name), 'A') ||
+        setweight(to_tsvector('german', coalesce(bpo_brand, '')), 'B') ||
+        setweight(to_tsvector('german', coalesce(bpo_description, '')), 'C')
+    ) stored;
+create index bpo_billa_product_search_idx on bpo_billa_product using gin(bpo_search);
+
+alter table sp_spar_product
+    add column sp_search tsvector generated always as (
+        setweight(to_tsvector('german', sp_name), 'A') ||
+        setweight(to_tsvector('german', coalesce(sp_brand, '')), 'B') ||
+        setweight(to_tsvector('german', coalesce(sp_description, '')), 'C')
+    ) stored;
+create index sp_spar_product_search_idx on sp_spar_product using gin(sp_search);
+
+commit;
@@ -0,0 +1,32 @@
+-- Remembers if a Billa price is a promotion and adds the watchlist of the alerts.
+begin;
+
+drop view if exists bp_billa_price_series;
+
+alter table bp_billa_price
+    add column bp_discounted boolean not null default false;
+
+create or replace view bp_billa_price_series as
+select crawl.bcw_id, crawl.bcw_created, bp_billa_price.*
+from bp_billa_price
+    join br_billa_raw on bp_br_raw = br_id
+    join bcw_billa_crawl first_crawl on br_bcw_crawl = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on bp_last_bcw_crawl = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from br_billa_raw crawled
+        where crawled.br_bcw_crawl = crawl.bcw_id
+    );
+
+create table if not exists wl_watch (
+    wl_id uuid default gen_random_uuid() primary key,
+    wl_created timestamp default current_timestamp,
+    wl_store character varying(16),
+    wl_article_id character varying(256),
+    wl_search text,
+    wl_rule character varying(16) not null,
+    wl_threshold numeric
+);
+
+commit;
@@ -0,0 +1,50 @@
+-- Adds the job queue which lets several workers crawl the pages of a crawl session.
+begin;
+
+create table if not exists qj_queue_job (
+    qj_id uuid default gen_random_uuid() primary key,
+    qj_created timestamp default current_timestamp,
+    qj_bcw_crawl uuid not null,
+    qj_store character varying(16) not null,
+    qj_category character varying(256) not null,
+    qj_page integer not null,
+    qj_status character varying(16) not null default 'queued',
+    qj_attempts integer not null default 0,
+    qj_run_after timestamp not null default current_timestamp,
+    qj_locked_until timestamp,
+    qj_worker character varying(256),
+    qj_product_count integer,
+    qj_error text,
+    qj_finished timestamp
+);
+create unique index qj_queue_job_page_idx on qj_queue_job(qj_bcw_crawl, qj_store, qj_category, qj_page);
+create index qj_queue_job_claim_idx on qj_queue_job(qj_run_after) where qj_status in ('queued', 'running');
+ALTER TABLE qj_queue_job
+ADD CONSTRAINT qj_queue_job_crawl_fk FOREIGN KEY (qj_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+create table if not exists qc_queue_crawl (
+    qc_bcw_crawl uuid not null,
+    qc_store character varying(16) not null,
+    qc_finished timestamp,
+    qc_complete boolean,
+    primary key (qc_bcw_crawl, qc_store)
+);
+ALTER TABLE qc_queue_crawl
+ADD CONSTRAINT qc_queue_crawl_crawl_fk FOREIGN KEY (qc_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+
+-- progress of the stores of a crawl session run by the job queue
+create or replace view qs_queue_status as
+select qc_bcw_crawl,
+    qc_store,
+    count(qj_id) filter (where qj_status = 'queued') as qs_queued,
+    count(qj_id) filter (where qj_status = 'running') as qs_running,
+    count(qj_id) filter (where qj_status = 'done') as qs_done,
+    count(qj_id) filter (where qj_status = 'failed') as qs_failed,
+    count(qj_id) filter (where qj_attempts > 1) as qs_retried,
+    coalesce(sum(qj_product_count), 0) as qs_products,
+    qc_finished,
+    qc_complete
+from qc_queue_crawl
+    left join qj_queue_job on qj_bcw_crawl = qc_bcw_crawl and qj_store = qc_store
+group by qc_bcw_crawl, qc_store, qc_finished, qc_complete;
+
+commit;
@@ -0,0 +1,25 @@
+-- Remembers how a crawl session ended and how far its categories got, so an interrupted crawl can
+-- be resumed. The status of older crawl sessions is unknown.
+begin;
+
+alter table bcw_billa_crawl
+    add column bcw_status character varying(16),
+    add column bcw_finished timestamp,
+    add column bcw_pages integer not null default 0,
+    add column bcw_products integer not null default 0;
+
+alter table bcw_billa_crawl
+    alter column bcw_status set default 'running';
+
+create table if not exists cp_crawl_progress (
+    cp_bcw_crawl uuid not null,
+    cp_store character varying(16) not null,
+    cp_category character varying(256) not null,
+    cp_next_page integer,
+    cp_updated timestamp default current_timestamp,
+    primary key (cp_bcw_crawl, cp_store, cp_category)
+);
+ALTER TABLE cp_crawl_progress
+ADD CONSTRAINT cp_crawl_progress_crawl_fk FOREIGN KEY (cp_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+
+commit;
@@ -0,0 +1,11 @@
+-- Marks the raw documents whose products and prices were stored with them. Older documents are
+-- left unmarked and checked by the repair command.
+begin;
+
+alter table br_billa_raw
+    add column br_stored boolean not null default false;
+
+alter table sr_spar_raw
+    add column sr_stored boolean not null default false;
+
+commit;
@@ -0,0 +1,38 @@
+-- Journals every request of a crawl session with its status, selected headers, duration, size,
+-- attempt and user agent.
+begin;
+
+create table if not exists rj_request_journal (
+    rj_id uuid default gen_random_uuid() primary key,
+    rj_created timestamp default current_timestamp,
+    rj_bcw_crawl uuid not null,
+    rj_store character varying(16) not null,
+    rj_url character varying(256) not null,
+    rj_status integer,
+    rj_headers jsonb not null default '{}',
+    rj_duration_ms bigint not null,
+    rj_bytes bigint not null,
+    rj_attempt integer not null,
+    rj_user_agent character varying(256) not null,
+    rj_error text
+);
+create index rj_request_journal_crawl_idx on rj_request_journal(rj_bcw_crawl);
+ALTER TABLE rj_request_journal
+ADD CONSTRAINT rj_request_journal_crawl_fk FOREIGN KEY (rj_bcw_crawl) REFERENCES bcw_billa_crawl(bcw_id);
+
+create or replace view rs_request_stats as
+select rj_bcw_crawl,
+    rj_store,
+    min(rj_created) as rs_started,
+    count(*) as rs_requests,
+    count(*) filter (where rj_status is null) as rs_failed,
+    count(*) filter (where rj_status in (403, 429)) as rs_blocked,
+    count(*) filter (where rj_status >= 500) as rs_server_errors,
+    count(*) filter (where rj_attempt > 1) as rs_retries,
+    avg(rj_duration_ms)::bigint as rs_avg_ms,
+    (percentile_cont(0.95) within group (order by rj_duration_ms))::bigint as rs_p95_ms,
+    sum(rj_bytes)::bigint as rs_bytes
+from rj_request_journal
+group by rj_bcw_crawl, rj_store;
+
+commit;
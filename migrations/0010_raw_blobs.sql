@@ -0,0 +1,24 @@
+-- Stores the bodies of raw documents once per content as zstd compressed blobs. The bodies of
+-- existing documents stay in br_raw / sr_raw until they are moved with the compact command.
+begin;
+
+create table if not exists rb_raw_blob (
+    rb_hash character(64) primary key,
+    rb_created timestamp default current_timestamp,
+    rb_size bigint not null,
+    rb_compressed_size bigint not null,
+    rb_body bytea,
+    rb_location character varying(512)
+);
+
+alter table br_billa_raw
+    add column br_rb_blob character(64);
+alter table br_billa_raw
+    add constraint br_rb_blob_fk foreign key (br_rb_blob) references rb_raw_blob(rb_hash);
+
+alter table sr_spar_raw
+    add column sr_rb_blob character(64);
+alter table sr_spar_raw
+    add constraint sr_rb_blob_fk foreign key (sr_rb_blob) references rb_raw_blob(rb_hash);
+
+commit;
@@ -0,0 +1,11 @@
+-- Keeps where the prune command archived the body of a raw document to, the document itself and
+-- the prices referring to it stay.
+begin;
+
+alter table br_billa_raw
+    add column br_archive character varying(512);
+
+alter table sr_spar_raw
+    add column sr_archive character varying(512);
+
+commit;
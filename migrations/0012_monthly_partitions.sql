@@ -0,0 +1,178 @@
+-- Partitions the raw and price tables by month of their created timestamp. The tables are copied
+-- into partitioned ones, a price gets the created timestamp of its raw document so both are in the
+-- partitions of the same month. Run it while nothing is crawling, the crawler creates the
+-- partitions of later months itself.
+begin;
+
+drop view if exists bp_billa_price_series;
+drop view if exists spr_spar_price_series;
+
+alter table bp_billa_price drop constraint bp_billa_raw_fk;
+alter table spr_spar_price drop constraint spr_spar_price_raw_fk;
+
+alter table br_billa_raw rename to br_billa_raw_old;
+alter index br_billa_raw_pkey rename to br_billa_raw_old_pkey;
+alter table bp_billa_price rename to bp_billa_price_old;
+alter index bp_billa_price_pkey rename to bp_billa_price_old_pkey;
+drop index bp_billa_price_open_idx;
+alter table sr_spar_raw rename to sr_spar_raw_old;
+alter index sr_spar_raw_pkey rename to sr_spar_raw_old_pkey;
+alter table spr_spar_price rename to spr_spar_price_old;
+alter index spr_spar_price_pkey rename to spr_spar_price_old_pkey;
+drop index spr_spar_price_open_idx;
+
+create table br_billa_raw (
+    br_id uuid default gen_random_uuid(),
+    br_raw text,
+    br_created timestamp not null default current_timestamp,
+    br_url character varying(256) not null,
+    br_err text default null,
+    br_bcw_crawl uuid not null,
+    br_stored boolean not null default false,
+    br_rb_blob character(64),
+    br_archive character varying(512),
+    primary key (br_id, br_created)
+) partition by range (br_created);
+
+create table bp_billa_price (
+    bp_id uuid default gen_random_uuid(),
+    bp_created timestamp not null default current_timestamp,
+    bp_normal numeric,
+    bp_unit character varying(256),
+    bp_bpo_product uuid not null,
+    bp_br_raw uuid not null,
+    bp_valid_from timestamp not null default current_timestamp,
+    bp_valid_to timestamp default null,
+    bp_last_bcw_crawl uuid not null,
+    bp_discounted boolean not null default false,
+    primary key (bp_id, bp_created)
+) partition by range (bp_created);
+
+create table sr_spar_raw (
+    sr_id uuid default gen_random_uuid(),
+    sr_raw text,
+    sr_created timestamp not null default current_timestamp,
+    sr_url character varying(256) not null,
+    sr_err text default null,
+    sr_cs_crawl_session uuid not null,
+    sr_stored boolean not null default false,
+    sr_rb_blob character(64),
+    sr_archive character varying(512),
+    primary key (sr_id, sr_created)
+) partition by range (sr_created);
+
+create table spr_spar_price (
+    spr_id uuid default gen_random_uuid(),
+    spr_p_created timestamp not null default current_timestamp,
+    spr_price numeric,
+    spr_sales_unit character varying(256),
+    spr_price_unit character varying(256),
+    spr_sp_product uuid not null,
+    spr_sr_raw uuid not null,
+    spr_valid_from timestamp not null default current_timestamp,
+    spr_valid_to timestamp default null,
+    spr_last_cs_crawl_session uuid not null,
+    primary key (spr_id, spr_p_created)
+) partition by range (spr_p_created);
+
+-- a partition per table and month from the oldest document up to the next month
+do $$
+declare
+    month date;
+    tbl text;
+begin
+    select date_trunc('month', coalesce(min(created), localtimestamp))::date into month
+    from (
+        select br_created as created from br_billa_raw_old
+        union all
+        select sr_created from sr_spar_raw_old
+    ) documents;
+
+    while month <= date_trunc('month', localtimestamp) + interval '1 month' loop
+        foreach tbl in array array['br_billa_raw', 'bp_billa_price', 'sr_spar_raw', 'spr_spar_price'] loop
+            execute format(
+                'create table %I partition of %I for values from (%L) to (%L)',
+                tbl || '_' || to_char(month, 'YYYY_MM'),
+                tbl,
+                month,
+                month + interval '1 month'
+            );
+        end loop;
+        month := month + interval '1 month';
+    end loop;
+end $$;
+
+insert into br_billa_raw (br_id, br_raw, br_created, br_url, br_err, br_bcw_crawl, br_stored, br_rb_blob, br_archive)
+select br_id, br_raw, coalesce(br_created, localtimestamp), br_url, br_err, br_bcw_crawl, br_stored, br_rb_blob, br_archive
+from br_billa_raw_old;
+
+insert into bp_billa_price (bp_id, bp_created, bp_normal, bp_unit, bp_bpo_product, bp_br_raw, bp_valid_from, bp_valid_to, bp_last_bcw_crawl, bp_discounted)
+select bp_id, br_created, bp_normal, bp_unit, bp_bpo_product, bp_br_raw, bp_valid_from, bp_valid_to, bp_last_bcw_crawl, bp_discounted
+from bp_billa_price_old
+join br_billa_raw on bp_br_raw = br_id;
+
+insert into sr_spar_raw (sr_id, sr_raw, sr_created, sr_url, sr_err, sr_cs_crawl_session, sr_stored, sr_rb_blob, sr_archive)
+select sr_id, sr_raw, coalesce(sr_created, localtimestamp), sr_url, sr_err, sr_cs_crawl_session, sr_stored, sr_rb_blob, sr_archive
+from sr_spar_raw_old;
+
+insert into spr_spar_price (spr_id, spr_p_created, spr_price, spr_sales_unit, spr_price_unit, spr_sp_product, spr_sr_raw, spr_valid_from, spr_valid_to, spr_last_cs_crawl_session)
+select spr_id, sr_created, spr_price, spr_sales_unit, spr_price_unit, spr_sp_product, spr_sr_raw, spr_valid_from, spr_valid_to, spr_last_cs_crawl_session
+from spr_spar_price_old
+join sr_spar_raw on spr_sr_raw = sr_id;
+
+drop table bp_billa_price_old;
+drop table br_billa_raw_old;
+drop table spr_spar_price_old;
+drop table sr_spar_raw_old;
+
+create index bp_billa_price_open_idx on bp_billa_price(bp_bpo_product) where bp_valid_to is null;
+create index spr_spar_price_open_idx on spr_spar_price(spr_sp_product) where spr_valid_to is null;
+
+alter table br_billa_raw
+    add constraint br_bcw_crawler_fk foreign key (br_bcw_crawl) references bcw_billa_crawl(bcw_id);
+alter table br_billa_raw
+    add constraint br_rb_blob_fk foreign key (br_rb_blob) references rb_raw_blob(rb_hash);
+alter table bp_billa_price
+    add constraint bp_billa_price_fk foreign key (bp_bpo_product) references bpo_billa_product(bpo_id);
+alter table bp_billa_price
+    add constraint bp_billa_raw_fk foreign key (bp_br_raw, bp_created) references br_billa_raw(br_id, br_created);
+alter table bp_billa_price
+    add constraint bp_billa_last_crawl_fk foreign key (bp_last_bcw_crawl) references bcw_billa_crawl(bcw_id);
+alter table sr_spar_raw
+    add constraint sr_spar_raw_crawler_fk foreign key (sr_cs_crawl_session) references bcw_billa_crawl(bcw_id);
+alter table sr_spar_raw
+    add constraint sr_rb_blob_fk foreign key (sr_rb_blob) references rb_raw_blob(rb_hash);
+alter table spr_spar_price
+    add constraint spr_spar_price_product_fk foreign key (spr_sp_product) references sp_spar_product(sp_id);
+alter table spr_spar_price
+    add constraint spr_spar_price_raw_fk foreign key (spr_sr_raw, spr_p_created) references sr_spar_raw(sr_id, sr_created);
+alter table spr_spar_price
+    add constraint spr_spar_price_last_crawl_fk foreign key (spr_last_cs_crawl_session) references bcw_billa_crawl(bcw_id);
+
+-- a price row is valid from the crawl of its raw document up to its last seen crawl
+create or replace view bp_billa_price_series as
+select crawl.bcw_id, crawl.bcw_created, bp_billa_price.*
+from bp_billa_price
+    join br_billa_raw on bp_br_raw = br_id
+    join bcw_billa_crawl first_crawl on br_bcw_crawl = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on bp_last_bcw_crawl = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from br_billa_raw crawled
+        where crawled.br_bcw_crawl = crawl.bcw_id
+    );
+create or replace view spr_spar_price_series as
+select crawl.bcw_id, crawl.bcw_created, spr_spar_price.*
+from spr_spar_price
+    join sr_spar_raw on spr_sr_raw = sr_id
+    join bcw_billa_crawl first_crawl on sr_cs_crawl_session = first_crawl.bcw_id
+    join bcw_billa_crawl last_crawl on spr_last_cs_crawl_session = last_crawl.bcw_id
+    join bcw_billa_crawl crawl on crawl.bcw_created between first_crawl.bcw_created and last_crawl.bcw_created
+where exists (
+        select 1
+        from sr_spar_raw crawled
+        where crawled.sr_cs_crawl_session = crawl.bcw_id
+    );
+
+commit;
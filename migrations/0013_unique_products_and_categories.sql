@@ -0,0 +1,35 @@
+-- Makes the spar products and the categories of both stores unique, so concurrent crawls can add
+-- them with "on conflict do nothing". Duplicates added by concurrent crawls before are merged into
+-- the oldest of them.
+begin;
+
+create temporary table duplicate (old_id uuid primary key, new_id uuid not null) on commit drop;
+
+insert into duplicate
+select sp_id, first_value(sp_id) over (partition by sp_spar_id order by sp_created, sp_id)
+from sp_spar_product;
+delete from duplicate where old_id = new_id;
+update spr_spar_price set spr_sp_product = new_id from duplicate where spr_sp_product = old_id;
+delete from sp_spar_product using duplicate where sp_id = old_id;
+truncate duplicate;
+
+insert into duplicate
+select bc_id, first_value(bc_id) over (partition by bc_text order by bc_id)
+from bc_billa_category;
+delete from duplicate where old_id = new_id;
+update bpo_billa_product set bpo_bc_category = new_id from duplicate where bpo_bc_category = old_id;
+delete from bc_billa_category using duplicate where bc_id = old_id;
+truncate duplicate;
+
+insert into duplicate
+select sc_id, first_value(sc_id) over (partition by sc_text order by sc_id)
+from sc_spar_category;
+delete from duplicate where old_id = new_id;
+update sp_spar_product set sp_sc_category = new_id from duplicate where sp_sc_category = old_id;
+delete from sc_spar_category using duplicate where sc_id = old_id;
+
+create unique index bc_billa_category_bc_text_idx on bc_billa_category(bc_text);
+create unique index sc_spar_category_sc_text_idx on sc_spar_category(sc_text);
+create unique index sp_spar_product_sp_spar_id_idx on sp_spar_product(sp_spar_id);
+
+commit;
@@ -0,0 +1,8 @@
+-- Carries the pagination of a category from job to job: the digest of the previous page, to detect
+-- a shop repeating it, and when the first page was claimed, for the category timeout.
+begin;
+
+alter table qj_queue_job add column qj_previous_page bigint;
+alter table qj_queue_job add column qj_category_started timestamp not null default current_timestamp;
+
+commit;
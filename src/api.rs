@@ -0,0 +1,462 @@
+//! JSON over HTTP for front-ends, served from the postgres tables.
+//!
+//! Lists are paginated with the query parameters `page` (starting at 1) and `per_page`.
+
+use std::net::TcpListener;
+
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use strum::IntoEnumIterator;
+use tracing::error;
+
+use crate::diff::{diff_store, Change};
+use crate::model::Store;
+use crate::query::{self, ProductFilter, ProductSummary, Window};
+
+const DEFAULT_PER_PAGE: u32 = 50;
+const MAX_PER_PAGE: u32 = 500;
+
+pub fn router(pool: PgPool) -> Router {
+    Router::new()
+        .route("/stores", get(stores))
+        .route("/stores/:store/categories", get(categories))
+        .route("/products", get(products))
+        .route("/products/:store/:article_id/history", get(history))
+        .route("/crawls/latest/changes", get(latest_changes))
+        .with_state(pool)
+}
+
+/// Serves the API on `listener` until the process stops.
+pub async fn serve(pool: PgPool, listener: TcpListener) -> Result<()> {
+    axum::Server::from_tcp(listener)?
+        .serve(router(pool).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+enum ApiError {
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(err: E) -> Self {
+        ApiError::Internal(err.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            // the details may contain queries and connection strings, they're only logged
+            ApiError::Internal(err) => {
+                error!(?err, "request failed");
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal error".to_string(),
+                )
+            }
+        };
+
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Pagination {
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+impl Pagination {
+    fn page(&self) -> u32 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    fn per_page(&self) -> u32 {
+        self.per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE)
+    }
+
+    /// In u64, as pages beyond `u32::MAX / MAX_PER_PAGE` overflow u32.
+    fn offset(&self) -> u64 {
+        u64::from(self.page() - 1).saturating_mul(self.per_page().into())
+    }
+
+    /// Cuts the current page out of all `items`.
+    fn apply<T>(&self, items: Vec<T>) -> Page<T> {
+        let total = items.len() as i64;
+        let items = items
+            .into_iter()
+            .skip(usize::try_from(self.offset()).unwrap_or(usize::MAX))
+            .take(self.per_page() as usize)
+            .collect();
+
+        self.page_of(items, total)
+    }
+
+    fn page_of<T>(&self, items: Vec<T>, total: i64) -> Page<T> {
+        Page {
+            items,
+            page: self.page(),
+            per_page: self.per_page(),
+            total,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: u32,
+    pub per_page: u32,
+    /// Number of items on all pages.
+    pub total: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct StoreSummary {
+    store: Store,
+    categories: usize,
+    products: i64,
+}
+
+async fn stores(
+    State(pool): State<PgPool>,
+    Query(pagination): Query<Pagination>,
+) -> ApiResult<Page<StoreSummary>> {
+    let mut stores = Vec::new();
+
+    for store in Store::iter() {
+        let categories = query::categories(&pool, store).await?;
+
+        stores.push(StoreSummary {
+            store,
+            categories: categories.len(),
+            products: categories.iter().map(|category| category.products).sum(),
+        });
+    }
+
+    Ok(Json(pagination.apply(stores)))
+}
+
+async fn categories(
+    State(pool): State<PgPool>,
+    Path(store): Path<Store>,
+    Query(pagination): Query<Pagination>,
+) -> ApiResult<Page<query::Category>> {
+    let categories = query::categories(&pool, store).await?;
+
+    Ok(Json(pagination.apply(categories)))
+}
+
+#[derive(Debug, Deserialize)]
+struct ProductParams {
+    store: Option<Store>,
+    category: Option<String>,
+    brand: Option<String>,
+    min_price: Option<Decimal>,
+    max_price: Option<Decimal>,
+    /// Full-text search over name, brand and description.
+    q: Option<String>,
+}
+
+async fn products(
+    State(pool): State<PgPool>,
+    Query(params): Query<ProductParams>,
+    Query(pagination): Query<Pagination>,
+) -> ApiResult<Page<ProductSummary>> {
+    let filter = ProductFilter {
+        store: params.store,
+        category: params.category,
+        brand: params.brand,
+        min_price: params.min_price,
+        max_price: params.max_price,
+        text: params.q,
+    };
+
+    let (products, total) = query::search_products(
+        &pool,
+        &filter,
+        pagination.per_page().into(),
+        i64::try_from(pagination.offset()).unwrap_or(i64::MAX),
+    )
+    .await?;
+
+    Ok(Json(pagination.page_of(products, total)))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryParams {
+    /// Comma separated windows, e.g. `7d,30d`.
+    windows: Option<String>,
+}
+
+async fn history(
+    State(pool): State<PgPool>,
+    Path((store, article_id)): Path<(Store, String)>,
+    Query(params): Query<HistoryParams>,
+) -> std::result::Result<Response, ApiError> {
+    let windows = params
+        .windows
+        .as_deref()
+        .unwrap_or("7d,30d,365d")
+        .split(',')
+        .filter(|window| !window.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<Window>>>();
+    let windows = match windows {
+        Ok(windows) => windows,
+        Err(err) => {
+            let body = Json(json!({ "error": err.to_string() }));
+
+            return Ok((StatusCode::BAD_REQUEST, body).into_response());
+        }
+    };
+
+    let Some(history) = query::history(&pool, store, &article_id).await? else {
+        return Err(ApiError::NotFound(format!(
+            "no prices of {} {}",
+            store, article_id
+        )));
+    };
+
+    Ok(Json(history.report(&windows)).into_response())
+}
+
+/// The crawl sessions the changes of a store are between.
+#[derive(Debug, Serialize)]
+struct StoreCrawls {
+    store: Store,
+    from: Uuid,
+    to: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+struct Changes {
+    crawls: Vec<StoreCrawls>,
+    #[serde(flatten)]
+    changes: Page<Change>,
+}
+
+/// Changes of every store between its latest two complete crawl sessions, stores can be crawled
+/// in sessions of their own.
+async fn latest_changes(
+    State(pool): State<PgPool>,
+    Query(pagination): Query<Pagination>,
+) -> ApiResult<Changes> {
+    let mut crawls = Vec::new();
+    let mut changes = Vec::new();
+    for store in Store::iter() {
+        let complete = query::complete_crawls(&pool, store).await?;
+        let [to, from, ..] = complete.as_slice() else {
+            continue;
+        };
+
+        changes.extend(diff_store(&pool, store, from.id, to.id).await?);
+        crawls.push(StoreCrawls {
+            store,
+            from: from.id,
+            to: to.id,
+        });
+    }
+
+    if crawls.is_empty() {
+        return Err(ApiError::NotFound(
+            "no store has two complete crawls".to_string(),
+        ));
+    }
+
+    Ok(Json(Changes {
+        crawls,
+        changes: pagination.apply(changes),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::model::{CrawlStatus, CrawlSummary};
+    use crate::storage::Storage;
+    use crate::stores::spar::SparCrawl;
+    use crate::stores::ExecuteCrawler;
+    use crate::test_support::{crawl_billa, mock_billa, mock_store, TestDatabase};
+
+    async fn get(base_url: &str, path: &str) -> (StatusCode, Value) {
+        let response = reqwest::get(format!("{}{}", base_url, path)).await.unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+
+        (status, response.json().await.unwrap())
+    }
+
+    async fn finish(db: &TestDatabase, crawl_id: Uuid, status: CrawlStatus) {
+        let summary = CrawlSummary {
+            status,
+            pages: 0,
+            products: 0,
+        };
+
+        db.storage.finish_crawl(crawl_id, summary).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoints_serve_crawled_data() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        for _ in 0..2 {
+            let crawl_id = crawl_billa(&db.storage, &server).await;
+            finish(&db, crawl_id, CrawlStatus::Complete).await;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(db.pool.clone(), listener));
+
+        let (status, stores) = get(&base_url, "/stores").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stores["total"], 2);
+        assert_eq!(stores["items"][0]["store"], "billa");
+        assert_eq!(stores["items"][0]["products"], 7);
+
+        let (_, categories) = get(&base_url, "/stores/billa/categories").await;
+        let bread = categories["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|category| category["name"] == "Bread")
+            .unwrap();
+        assert_eq!(bread["products"], 2);
+
+        let (_, products) = get(&base_url, "/products?store=billa&page=2&per_page=3").await;
+        assert_eq!(products["total"], 7);
+        assert_eq!(products["page"], 2);
+        assert_eq!(products["items"].as_array().unwrap().len(), 3);
+        // beyond u32 once multiplied with per_page
+        let (status, products) = get(&base_url, "/products?page=4294967295&per_page=500").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(products["items"].as_array().unwrap().is_empty());
+        let (status, stores) = get(&base_url, "/stores?page=4294967295&per_page=500").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(stores["items"].as_array().unwrap().is_empty());
+
+        let (_, products) = get(&base_url, "/products?q=toast").await;
+        assert_eq!(products["total"], 1);
+        assert_eq!(products["items"][0]["article_id"], "00-384201");
+        assert_eq!(products["items"][0]["price"], "2.79");
+
+        let (status, history) =
+            get(&base_url, "/products/billa/00-384201/history?windows=7d").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(history["prices"].as_array().unwrap().len(), 2);
+        assert_eq!(history["stats"][1]["window"], "1w");
+
+        let (status, _) = get(&base_url, "/products/billa/unknown/history").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get(&base_url, "/products/billa/00-384201/history?windows=1y").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get(
+            &base_url,
+            "/products/billa/00-384201/history?windows=9999999999999999h",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get(
+            &base_url,
+            "/products/billa/00-384201/history?windows=20000000w",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, changes) = get(&base_url, "/crawls/latest/changes").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(changes["total"], 0);
+
+        // the error of the database isn't passed on
+        sqlx::query("drop table sc_spar_category cascade")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let (status, error) = get(&base_url, "/stores/spar/categories").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error["error"], "internal error");
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn changes_are_compared_per_store() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let billa = mock_billa().await;
+        let spar = mock_store(
+            "spar",
+            "/fact-finder/rest/v4/search/products_lmos_at",
+            "filter",
+            "category-path:",
+        )
+        .await;
+
+        // every store in sessions of its own, as the daemon crawls them
+        let mut sessions = Vec::new();
+        for status in [
+            CrawlStatus::Complete,
+            CrawlStatus::Complete,
+            CrawlStatus::Incomplete,
+        ] {
+            let crawl_id = crawl_billa(&db.storage, &billa).await;
+            finish(&db, crawl_id, status).await;
+
+            let spar_id = db.storage.create_crawl().await.unwrap();
+            SparCrawl::execute(
+                &db.storage,
+                spar_id,
+                Fetcher::new(Mode::Live).unwrap(),
+                &spar.uri(),
+            )
+            .await
+            .unwrap();
+            finish(&db, spar_id, status).await;
+
+            sessions.push((crawl_id, spar_id));
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(db.pool.clone(), listener));
+
+        let (status, changes) = get(&base_url, "/crawls/latest/changes").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(changes["total"], 0);
+        assert_eq!(
+            changes["crawls"],
+            json!([
+                { "store": "billa", "from": sessions[0].0, "to": sessions[1].0 },
+                { "store": "spar", "from": sessions[0].1, "to": sessions[1].1 },
+            ])
+        );
+
+        db.close().await;
+    }
+}
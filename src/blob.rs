@@ -0,0 +1,454 @@
+//! Raw documents in postgres are stored once per content: the body is hashed with SHA-256 and
+//! compressed with zstd into `rb_raw_blob`, the documents of every crawl session refer to the blob
+//! by its hash. A page which didn't change since the last crawl doesn't take any more space.
+//!
+//! The compressed bodies are kept in the table or offloaded to a directory or an S3 compatible
+//! blob store, see [`BlobStore`]. Every blob keeps the url of its body, so documents are read the
+//! same way wherever they were written to. Documents of older versions keep their body as text in
+//! `br_raw` / `sr_raw` until they are compacted, see [`compact`].
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use sqlx::{PgConnection, PgPool};
+
+use crate::model::Store;
+use crate::prune;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Where the bodies of new blobs are written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BlobStore {
+    /// In the column `rb_body` of the blob.
+    #[default]
+    Database,
+    /// `file://<dir>`, a file per blob.
+    Directory(PathBuf),
+    /// `s3://<bucket>/<prefix>`, needs the `s3` feature. The credentials, region and endpoint of
+    /// S3 compatible stores are read from the `AWS_*` environment variables.
+    S3 { bucket: String, prefix: String },
+}
+
+impl FromStr for BlobStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "postgres" {
+            Ok(BlobStore::Database)
+        } else if let Some(dir) = s.strip_prefix("file://") {
+            Ok(BlobStore::Directory(std::path::absolute(dir)?))
+        } else if let Some(path) = s.strip_prefix("s3://") {
+            let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+            if bucket.is_empty() {
+                bail!("no bucket in {}", s);
+            }
+
+            Ok(BlobStore::S3 {
+                bucket: bucket.to_string(),
+                prefix: prefix.trim_end_matches('/').to_string(),
+            })
+        } else {
+            bail!(
+                "expected postgres, file://<dir> or s3://<bucket>/<prefix>, got {}",
+                s
+            )
+        }
+    }
+}
+
+impl BlobStore {
+    /// Url of the body of the blob `hash`, the blobs are spread over directories by the first
+    /// two characters of their hash.
+    fn location(&self, hash: &str) -> Option<String> {
+        let name = format!("{}/{}.zst", &hash[..2], hash);
+
+        match self {
+            BlobStore::Database => None,
+            BlobStore::Directory(dir) => Some(format!("file://{}", dir.join(name).display())),
+            BlobStore::S3 { bucket, prefix } if prefix.is_empty() => {
+                Some(format!("s3://{}/{}", bucket, name))
+            }
+            BlobStore::S3 { bucket, prefix } => {
+                Some(format!("s3://{}/{}/{}", bucket, prefix, name))
+            }
+        }
+    }
+}
+
+/// SHA-256 of the body, hex encoded.
+pub fn hash(body: &str) -> String {
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+/// Saves `body` as blob into `store` unless a blob with the same content exists, returns its
+/// hash.
+///
+/// An existing blob gets a new `rb_created`, so [`crate::prune`] doesn't delete it before the
+/// document referring to it is inserted.
+pub async fn save(conn: &mut PgConnection, body: &str, store: &BlobStore) -> Result<String> {
+    let hash = hash(body);
+
+    let exists: Option<(String,)> = sqlx::query_as(
+        "update rb_raw_blob set rb_created = current_timestamp where rb_hash = $1 returning rb_hash",
+    )
+    .bind(&hash)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if exists.is_some() {
+        return Ok(hash);
+    }
+
+    let compressed = zstd::encode_all(body.as_bytes(), COMPRESSION_LEVEL)?;
+    let location = store.location(&hash);
+    if let Some(location) = &location {
+        write(location, &compressed).await?;
+    }
+
+    sqlx::query(
+        "insert into rb_raw_blob (rb_hash, rb_size, rb_compressed_size, rb_body, rb_location)
+        values ( $1, $2, $3, $4, $5 )
+        on conflict (rb_hash) do update set rb_created = current_timestamp",
+    )
+    .bind(&hash)
+    .bind(body.len() as i64)
+    .bind(compressed.len() as i64)
+    .bind(location.is_none().then_some(&compressed))
+    .bind(&location)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(hash)
+}
+
+/// The body of a document as selected from postgres.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct StoredBody {
+    /// The text of documents saved before the blobs.
+    pub raw: Option<String>,
+    /// `rb_body`
+    pub blob: Option<Vec<u8>>,
+    /// `rb_location`
+    pub location: Option<String>,
+    /// The file the body was archived to, see [`crate::prune`].
+    #[sqlx(default)]
+    pub archive: Option<String>,
+}
+
+impl StoredBody {
+    /// `None` for the documents of errors.
+    pub async fn text(self) -> Result<Option<String>> {
+        let compressed = match (self.raw, self.blob, self.location) {
+            (Some(raw), _, _) => return Ok(Some(raw)),
+            (None, Some(blob), _) => blob,
+            (None, None, Some(location)) => read(&location).await?,
+            (None, None, None) => return Ok(None),
+        };
+
+        let body = zstd::decode_all(compressed.as_slice())?;
+
+        Ok(Some(String::from_utf8(body)?))
+    }
+}
+
+/// Selects the [`StoredBody`] of a document of `store`, the id of the document is `$1`.
+pub fn body_query(store: Store) -> &'static str {
+    match store {
+        Store::Billa => {
+            "select br_raw as raw, rb_body as blob, rb_location as location, br_archive as archive
+            from br_billa_raw
+            left join rb_raw_blob on br_rb_blob = rb_hash
+            where br_id = $1"
+        }
+        Store::Spar => {
+            "select sr_raw as raw, rb_body as blob, rb_location as location, sr_archive as archive
+            from sr_spar_raw
+            left join rb_raw_blob on sr_rb_blob = rb_hash
+            where sr_id = $1"
+        }
+    }
+}
+
+/// The body of the document `document_id`, `None` if it doesn't exist or is an error. Archived
+/// bodies are read from their archive.
+pub async fn document(pool: &PgPool, store: Store, document_id: Uuid) -> Result<Option<String>> {
+    let body: Option<StoredBody> = sqlx::query_as(body_query(store))
+        .bind(document_id)
+        .fetch_optional(pool)
+        .await?;
+
+    match body {
+        Some(StoredBody {
+            archive: Some(archive),
+            ..
+        }) => Ok(Some(prune::archived_document(&archive, document_id).await?)),
+        Some(body) => body.text().await,
+        None => Ok(None),
+    }
+}
+
+/// Moves the text of documents saved before the blobs into blobs written to `blob_store`, `batch`
+/// documents per transaction. Returns the number of documents moved.
+pub async fn compact(pool: &PgPool, blob_store: &BlobStore, batch: i64) -> Result<u64> {
+    let queries = [
+        (
+            "select br_id, br_raw from br_billa_raw where br_raw is not null limit $1 for update skip locked",
+            "update br_billa_raw set br_raw = null, br_rb_blob = $2 where br_id = $1",
+        ),
+        (
+            "select sr_id, sr_raw from sr_spar_raw where sr_raw is not null limit $1 for update skip locked",
+            "update sr_spar_raw set sr_raw = null, sr_rb_blob = $2 where sr_id = $1",
+        ),
+    ];
+
+    let mut compacted = 0;
+    for (select, update) in queries {
+        loop {
+            let mut tx = pool.begin().await?;
+
+            let documents: Vec<(Uuid, String)> = sqlx::query_as(select)
+                .bind(batch)
+                .fetch_all(&mut tx)
+                .await?;
+            for (id, raw) in &documents {
+                let hash = save(&mut tx, raw, blob_store).await?;
+
+                sqlx::query(update)
+                    .bind(id)
+                    .bind(hash)
+                    .execute(&mut tx)
+                    .await?;
+            }
+
+            tx.commit().await?;
+            compacted += documents.len() as u64;
+
+            if (documents.len() as i64) < batch {
+                break;
+            }
+        }
+    }
+
+    Ok(compacted)
+}
+
+async fn write(location: &str, compressed: &[u8]) -> Result<()> {
+    if let Some(path) = location.strip_prefix("file://") {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        tokio::fs::write(&path, compressed)
+            .await
+            .with_context(|| format!("writing blob {}", location))?;
+
+        return Ok(());
+    }
+
+    #[cfg(feature = "s3")]
+    if let Some((store, path)) = s3::object(location)? {
+        use object_store::ObjectStore;
+
+        store.put(&path, compressed.to_vec().into()).await?;
+
+        return Ok(());
+    }
+
+    bail!(
+        "can't write blob {}, s3 needs a build with the s3 feature",
+        location
+    )
+}
+
+/// Deletes the body of an offloaded blob, a missing body is ignored.
+pub(crate) async fn remove(location: &str) -> Result<()> {
+    if let Some(path) = location.strip_prefix("file://") {
+        return match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("removing blob {}", location))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    #[cfg(feature = "s3")]
+    if let Some((store, path)) = s3::object(location)? {
+        use object_store::ObjectStore;
+
+        return match store.delete(&path).await {
+            Err(object_store::Error::NotFound { .. }) => Ok(()),
+            result => Ok(result?),
+        };
+    }
+
+    bail!(
+        "can't remove blob {}, s3 needs a build with the s3 feature",
+        location
+    )
+}
+
+async fn read(location: &str) -> Result<Vec<u8>> {
+    if let Some(path) = location.strip_prefix("file://") {
+        return tokio::fs::read(path)
+            .await
+            .with_context(|| format!("reading blob {}", location));
+    }
+
+    #[cfg(feature = "s3")]
+    if let Some((store, path)) = s3::object(location)? {
+        use object_store::ObjectStore;
+
+        return Ok(store.get(&path).await?.bytes().await?.to_vec());
+    }
+
+    bail!(
+        "can't read blob {}, s3 needs a build with the s3 feature",
+        location
+    )
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use anyhow::Result;
+    use object_store::aws::{AmazonS3, AmazonS3Builder};
+    use object_store::path::Path;
+
+    /// The bucket and path of an `s3://<bucket>/<path>` url, `None` for other urls.
+    pub fn object(location: &str) -> Result<Option<(AmazonS3, Path)>> {
+        let Some(url) = location.strip_prefix("s3://") else {
+            return Ok(None);
+        };
+        let (bucket, path) = url.split_once('/').unwrap_or((url, ""));
+
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+
+        Ok(Some((store, Path::from(path))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::test_support::{fixture, TestDatabase};
+
+    #[tokio::test]
+    async fn identical_documents_share_a_compressed_blob() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let body = fixture("billa", "B2-1_1.json");
+
+        let mut documents = Vec::new();
+        for _ in 0..2 {
+            let crawl_id = db.crawl_id().await;
+            documents.push(
+                db.storage
+                    .save_document(Store::Billa, crawl_id, "page", &body)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let blobs: Vec<(String, i64, i64)> =
+            sqlx::query_as("select rb_hash, rb_size, rb_compressed_size from rb_raw_blob")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].0, hash(&body));
+        assert_eq!(blobs[0].1, body.len() as i64);
+        assert!(blobs[0].2 < blobs[0].1 / 2);
+
+        for document_id in documents {
+            let stored = document(&db.pool, Store::Billa, document_id).await.unwrap();
+            assert_eq!(stored.as_deref(), Some(body.as_str()));
+        }
+
+        // documents of older versions are moved into the existing blob
+        let crawl_id = db.crawl_id().await;
+        let (legacy,): (Uuid,) = sqlx::query_as(
+            "insert into br_billa_raw (br_raw, br_url, br_bcw_crawl) values ( $1, 'page', $2 ) returning br_id",
+        )
+        .bind(&body)
+        .bind(crawl_id)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(compact(&db.pool, &BlobStore::Database, 1).await.unwrap(), 1);
+
+        let raw: (Option<String>, Option<String>) =
+            sqlx::query_as("select br_raw, br_rb_blob from br_billa_raw where br_id = $1")
+                .bind(legacy)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(raw, (None, Some(hash(&body))));
+        let stored = document(&db.pool, Store::Billa, legacy).await.unwrap();
+        assert_eq!(stored.as_deref(), Some(body.as_str()));
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn blobs_are_offloaded_to_a_directory() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let store = format!("file://{}", dir.path().display())
+            .parse::<BlobStore>()
+            .unwrap();
+        let body = fixture("billa", "B2-2_1.json");
+
+        let mut conn = db.pool.acquire().await.unwrap();
+        let hash = save(&mut conn, &body, &store).await.unwrap();
+        drop(conn);
+
+        let path = dir.path().join(&hash[..2]).join(format!("{}.zst", hash));
+        assert!(path.exists());
+
+        let stored: StoredBody = sqlx::query_as(
+            "select null::text as raw, rb_body as blob, rb_location as location from rb_raw_blob where rb_hash = $1",
+        )
+        .bind(&hash)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert!(stored.blob.is_none());
+        assert_eq!(stored.text().await.unwrap(), Some(body));
+
+        db.close().await;
+    }
+
+    #[test]
+    fn parse_blob_stores() {
+        assert_eq!(
+            "s3://crawls/raw/".parse::<BlobStore>().unwrap(),
+            BlobStore::S3 {
+                bucket: "crawls".to_string(),
+                prefix: "raw".to_string()
+            }
+        );
+        assert_eq!(
+            BlobStore::S3 {
+                bucket: "crawls".to_string(),
+                prefix: String::new()
+            }
+            .location("ab12"),
+            Some("s3://crawls/ab/ab12.zst".to_string())
+        );
+        assert_eq!(
+            "postgres".parse::<BlobStore>().unwrap(),
+            BlobStore::Database
+        );
+        assert!("s3://".parse::<BlobStore>().is_err());
+        assert!("ftp://host".parse::<BlobStore>().is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use strum::IntoEnumIterator;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::http::{Fetcher, Mode};
+use crate::metrics::metrics;
+use crate::model::{CategoryProgress, CrawlStatus, CrawlSummary, ListingChanges, Store};
+use crate::shutdown::Shutdown;
+use crate::storage::{Storage, StoreLock};
+use crate::stores::billa::{self, BillaCrawl};
+use crate::stores::spar::{self, SparCrawl};
+use crate::stores::ExecuteCrawler;
+
+/// Outcome of [`run`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrawlReport {
+    pub crawl_id: Uuid,
+    pub status: CrawlStatus,
+    pub stores: Vec<StoreReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoreReport {
+    pub store: Store,
+    /// If every category of the store was crawled, listings are only updated for complete crawls.
+    pub complete: bool,
+    /// If a shutdown stopped the crawl of the store.
+    pub interrupted: bool,
+    pub pages: usize,
+    pub products: usize,
+    pub listings: Option<ListingChanges>,
+}
+
+impl StoreReport {
+    /// A store whose crawl failed with an error.
+    fn failed(store: Store) -> Self {
+        StoreReport {
+            store,
+            complete: false,
+            interrupted: false,
+            pages: 0,
+            products: 0,
+            listings: None,
+        }
+    }
+}
+
+/// What to do if another process crawls a store right now.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnLocked {
+    /// Wait until the other crawl of the store finished
+    Wait,
+    /// Crawl only the other stores
+    Skip,
+    #[default]
+    Fail,
+}
+
+/// How [`run`] crawls.
+#[derive(Debug, Clone, Default)]
+pub struct CrawlOptions {
+    pub mode: Mode,
+    pub on_locked: OnLocked,
+    /// Once requested no further pages are requested and the crawl session is marked as
+    /// interrupted.
+    pub shutdown: Shutdown,
+    /// Continues this interrupted crawl session instead of starting a new one.
+    pub resume: Option<Uuid>,
+}
+
+/// Crawls all stores in a new crawl session.
+pub async fn run<S: Storage>(storage: &S, options: &CrawlOptions) -> Result<CrawlReport> {
+    run_stores(storage, options, &Store::iter().collect::<Vec<_>>()).await
+}
+
+/// Crawls `stores` in a new crawl session.
+///
+/// Every store is locked for the crawl, so two processes never crawl the same store at once. A
+/// resumed crawl session skips the stores which were finished before the interruption and the
+/// categories which were done.
+pub async fn run_stores<S: Storage>(
+    storage: &S,
+    options: &CrawlOptions,
+    stores: &[Store],
+) -> Result<CrawlReport> {
+    let mut locks = Vec::new();
+    for store in Store::iter().filter(|store| stores.contains(store)) {
+        if let Some(crawl_id) = options.resume {
+            if is_done(storage, store, crawl_id).await? {
+                info!(%store, "done before the interruption");
+
+                continue;
+            }
+        }
+
+        if let Some(lock) = lock_store(storage, store, options.on_locked).await? {
+            locks.push((store, lock));
+        }
+    }
+    let stores = locks.iter().map(|(store, _)| *store).collect::<Vec<_>>();
+
+    let crawl_id = match options.resume {
+        Some(crawl_id) => {
+            storage.resume_crawl(crawl_id).await?;
+
+            crawl_id
+        }
+        None => storage.create_crawl().await?,
+    };
+
+    let span = info_span!("crawl", %crawl_id);
+    info!(parent: &span, ?stores, resumed = options.resume.is_some(), "crawl started");
+
+    let spar = async {
+        if !stores.contains(&Store::Spar) {
+            return Ok(None);
+        }
+
+        SparCrawl::crawl(
+            storage,
+            crawl_id,
+            Fetcher::new(options.mode.clone())?,
+            SparCrawl::BASE_URL,
+            &options.shutdown,
+        )
+        .instrument(info_span!(parent: &span, "store", store = %Store::Spar))
+        .await
+        .map(Some)
+    };
+    let billa = async {
+        if !stores.contains(&Store::Billa) {
+            return Ok(None);
+        }
+
+        BillaCrawl::crawl(
+            storage,
+            crawl_id,
+            Fetcher::new(options.mode.clone())?,
+            BillaCrawl::BASE_URL,
+            &options.shutdown,
+        )
+        .instrument(info_span!(parent: &span, "store", store = %Store::Billa))
+        .await
+        .map(Some)
+    };
+    let (spar, billa): (Result<_>, Result<_>) = tokio::join!(spar, billa);
+
+    // a failed store counts as incomplete, its error is returned once the session is finished
+    let mut failed = None;
+    let mut reports = Vec::new();
+    for (store, outcome) in [(Store::Spar, spar), (Store::Billa, billa)] {
+        let outcome = match outcome {
+            Ok(Some(outcome)) => outcome,
+            Ok(None) => continue,
+            Err(err) => {
+                error!(parent: &span, %store, ?err, "store failed");
+                failed.get_or_insert(err);
+                reports.push(StoreReport::failed(store));
+
+                continue;
+            }
+        };
+
+        let mut report = StoreReport {
+            store,
+            complete: outcome.complete,
+            interrupted: outcome.interrupted,
+            pages: outcome.pages,
+            products: outcome.products,
+            listings: None,
+        };
+        if outcome.complete {
+            match storage.complete_crawl(store, crawl_id).await {
+                Ok(listings) => {
+                    metrics().crawl_succeeded(store);
+                    report.listings = listings;
+                }
+                Err(err) => {
+                    error!(parent: &span, %store, ?err, "listings not updated");
+                    failed.get_or_insert(err);
+                    report.complete = false;
+                }
+            }
+        }
+
+        reports.push(report);
+    }
+
+    // a session without any crawled store, e.g. as all were skipped, isn't complete
+    let status = if reports.iter().any(|report| report.interrupted) {
+        CrawlStatus::Interrupted
+    } else if !reports.is_empty() && reports.iter().all(|report| report.complete) {
+        CrawlStatus::Complete
+    } else {
+        CrawlStatus::Incomplete
+    };
+    let summary = CrawlSummary {
+        status,
+        pages: reports.iter().map(|report| report.pages).sum(),
+        products: reports.iter().map(|report| report.products).sum(),
+    };
+    storage.finish_crawl(crawl_id, summary).await?;
+    info!(parent: &span, ?summary, "crawl finished");
+
+    for (_, lock) in locks {
+        lock.release().await?;
+    }
+
+    if let Some(err) = failed {
+        return Err(err);
+    }
+
+    Ok(CrawlReport {
+        crawl_id,
+        status,
+        stores: reports,
+    })
+}
+
+/// If every category of `store` was done in `crawl_id`.
+async fn is_done<S: Storage>(storage: &S, store: Store, crawl_id: Uuid) -> Result<bool> {
+    let categories = match store {
+        Store::Billa => billa::Category::iter().count(),
+        Store::Spar => spar::Category::iter().count(),
+    };
+    let progress = storage.progress(store, crawl_id).await?;
+
+    Ok(progress.len() == categories
+        && progress
+            .values()
+            .all(|progress| *progress == CategoryProgress::Done))
+}
+
+async fn lock_store<S: Storage>(
+    storage: &S,
+    store: Store,
+    on_locked: OnLocked,
+) -> Result<Option<StoreLock>> {
+    if let Some(lock) = storage.lock_store(store, false).await? {
+        return Ok(Some(lock));
+    }
+
+    match on_locked {
+        OnLocked::Wait => {
+            info!(%store, "waiting for the crawl of another process");
+
+            storage.lock_store(store, true).await
+        }
+        OnLocked::Skip => {
+            warn!(%store, "crawled by another process, skipped");
+
+            Ok(None)
+        }
+        OnLocked::Fail => bail!(
+            "{} is crawled by another process right now, see --on-locked to wait or skip it",
+            store
+        ),
+    }
+}
+
+impl fmt::Display for CrawlReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            CrawlStatus::Interrupted => writeln!(
+                f,
+                "crawl {} interrupted, continue it with --resume {}",
+                self.crawl_id, self.crawl_id
+            )?,
+            _ => writeln!(f, "crawl {}", self.crawl_id)?,
+        }
+
+        for report in &self.stores {
+            let Some(listings) = &report.listings else {
+                let reason = if report.complete {
+                    "listings aren't tracked by the storage"
+                } else if report.interrupted {
+                    "interrupted, listings unchanged"
+                } else {
+                    "incomplete, listings unchanged"
+                };
+                writeln!(f, "{}: {}", report.store, reason)?;
+
+                continue;
+            };
+
+            writeln!(
+                f,
+                "{}: {} added, {} removed, {} relisted",
+                report.store,
+                listings.added.len(),
+                listings.removed.len(),
+                listings.relisted.len()
+            )?;
+            for (sign, products) in [
+                ('+', &listings.added),
+                ('-', &listings.removed),
+                ('~', &listings.relisted),
+            ] {
+                for product in products {
+                    writeln!(f, "  {} {} {}", sign, product.article_id, product.name)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestDatabase;
+
+    #[tokio::test]
+    async fn locked_stores_are_skipped_or_fail() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+
+        let lock = db
+            .storage
+            .lock_store(Store::Billa, false)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let options = CrawlOptions {
+            on_locked: OnLocked::Fail,
+            ..CrawlOptions::default()
+        };
+        let err = run_stores(&db.storage, &options, &[Store::Billa])
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("billa is crawled by another process"));
+
+        let options = CrawlOptions {
+            on_locked: OnLocked::Skip,
+            ..CrawlOptions::default()
+        };
+        let report = run_stores(&db.storage, &options, &[Store::Billa])
+            .await
+            .unwrap();
+        assert!(report.stores.is_empty());
+        assert_eq!(report.status, CrawlStatus::Incomplete);
+
+        let err = run_stores(
+            &db.storage,
+            &CrawlOptions {
+                resume: Some(report.crawl_id),
+                ..options
+            },
+            &[Store::Billa],
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("wasn't interrupted"));
+
+        lock.release().await.unwrap();
+        db.close().await;
+    }
+}
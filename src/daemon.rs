@@ -0,0 +1,477 @@
+//! Crawls the stores on cron schedules until the process stops.
+//!
+//! The next run of every store is kept in a JSON state file, so a restarted daemon continues with
+//! the planned runs and catches up once on runs it missed while it was down.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use tokio::sync::mpsc;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::crawl::CrawlReport;
+use crate::model::{CrawlStatus, Store};
+use crate::shutdown::Shutdown;
+
+/// A store with the cron expression of its crawls, parsed from `<store>=<expression>`.
+///
+/// The expression has the five fields of cron, minute, hour, day of month, month and day of
+/// week, or additionally the seconds in front of them.
+#[derive(Debug, Clone)]
+pub struct StoreSchedule {
+    pub store: Store,
+    pub expression: String,
+    schedule: cron::Schedule,
+}
+
+impl StoreSchedule {
+    /// The first time of the schedule after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule.after(&after).next()
+    }
+}
+
+impl FromStr for StoreSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (store, expression) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <store>=<cron expression>, got {:?}", s))?;
+        let store = store
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("unknown store {:?}", store))?;
+        let expression = expression.trim().to_string();
+
+        let fields = expression.split_whitespace().count();
+        let with_seconds = match fields {
+            5 => format!("0 {}", expression),
+            6 => expression.clone(),
+            _ => bail!("cron expression {:?} needs 5 or 6 fields", expression),
+        };
+        let schedule = cron::Schedule::from_str(&with_seconds)
+            .with_context(|| format!("invalid cron expression {:?}", expression))?;
+
+        Ok(StoreSchedule {
+            store,
+            expression,
+            schedule,
+        })
+    }
+}
+
+impl fmt::Display for StoreSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.store, self.expression)
+    }
+}
+
+/// Parses durations like `30s`, `5m` or `1h`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let unit = s.chars().last().ok_or_else(|| anyhow!("empty duration"))?;
+    let value: u64 = s[..s.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| anyhow!("invalid duration {:?}, expected e.g. 30s, 5m or 1h", s))?;
+
+    let seconds = match unit {
+        's' => value,
+        'm' => value * 60,
+        'h' => value * 60 * 60,
+        _ => bail!("invalid duration {:?}, expected e.g. 30s, 5m or 1h", s),
+    };
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// What the daemon remembers of a store across restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreState {
+    /// The expression the next run was planned with, it's planned again if it changes.
+    pub schedule: String,
+    pub next_run: DateTime<Utc>,
+    pub last_run: Option<LastRun>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastRun {
+    pub crawl_id: Option<Uuid>,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// If every category was crawled, `false` if the run failed.
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    pub stores: BTreeMap<Store, StoreState>,
+}
+
+impl State {
+    /// An empty state if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("invalid state file {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replaces the file at once, a crash while saving leaves the previous state.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+}
+
+pub struct Daemon {
+    schedules: Vec<StoreSchedule>,
+    /// Runs start up to this much later than planned, so they don't hit the shops on the dot.
+    jitter: Duration,
+    state_path: PathBuf,
+    state: State,
+    /// The stores whose run hasn't finished yet.
+    running: HashSet<Store>,
+}
+
+impl Daemon {
+    pub fn new(
+        schedules: Vec<StoreSchedule>,
+        jitter: Duration,
+        state_path: impl Into<PathBuf>,
+    ) -> Result<Self> {
+        let mut stores = HashSet::new();
+        for schedule in &schedules {
+            if !stores.insert(schedule.store) {
+                bail!("store {} is scheduled twice", schedule.store);
+            }
+        }
+
+        let state_path = state_path.into();
+        let state = State::load(&state_path)?;
+
+        let mut daemon = Daemon {
+            schedules,
+            jitter,
+            state_path,
+            state,
+            running: HashSet::new(),
+        };
+
+        let now = Utc::now();
+        for schedule in daemon.schedules.clone() {
+            let planned = daemon
+                .state
+                .stores
+                .get(&schedule.store)
+                .is_some_and(|state| state.schedule == schedule.expression);
+
+            if !planned {
+                daemon.plan(&schedule, now)?;
+            }
+        }
+        daemon.state.save(&daemon.state_path)?;
+
+        Ok(daemon)
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Plans the next run of the store after `after`.
+    fn plan(&mut self, schedule: &StoreSchedule, after: DateTime<Utc>) -> Result<()> {
+        let next_run = schedule
+            .next_after(after)
+            .ok_or_else(|| anyhow!("schedule {} has no next run", schedule))?;
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter.as_millis() as i64);
+        let next_run = next_run + chrono::Duration::milliseconds(jitter);
+
+        let last_run = self
+            .state
+            .stores
+            .get(&schedule.store)
+            .and_then(|state| state.last_run.clone());
+        self.state.stores.insert(
+            schedule.store,
+            StoreState {
+                schedule: schedule.expression.clone(),
+                next_run,
+                last_run,
+            },
+        );
+
+        info!(store = %schedule.store, %next_run, "next run planned");
+
+        Ok(())
+    }
+
+    /// The store whose run is planned next, with the time of that run.
+    fn next_due(&self) -> Result<(StoreSchedule, DateTime<Utc>)> {
+        let schedule = self
+            .schedules
+            .iter()
+            .min_by_key(|schedule| self.state.stores[&schedule.store].next_run)
+            .cloned()
+            .ok_or_else(|| anyhow!("no store is scheduled"))?;
+        let next_run = self.state.stores[&schedule.store].next_run;
+
+        Ok((schedule, next_run))
+    }
+
+    /// Handles the run of `schedule` planned for `next_run` at `now`, returns if it starts. It's
+    /// skipped if the previous run of the store hasn't finished yet, the next one is planned
+    /// either way.
+    fn start_due(
+        &mut self,
+        schedule: &StoreSchedule,
+        next_run: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool> {
+        let starts = self.running.insert(schedule.store);
+        if !starts {
+            warn!(store = %schedule.store, "previous run hasn't finished, run skipped");
+        }
+
+        self.plan(schedule, now.max(next_run))?;
+        self.state.save(&self.state_path)?;
+
+        Ok(starts)
+    }
+
+    /// Crawls the stores with `crawl` whenever they are due, a run is skipped if the previous
+    /// run of the store hasn't finished yet. Returns on errors of the state file or once
+    /// `shutdown` is requested and the running crawls finished.
+    pub async fn run<F, Fut>(mut self, shutdown: &Shutdown, crawl: F) -> Result<()>
+    where
+        F: Fn(Store) -> Fut,
+        Fut: Future<Output = Result<CrawlReport>> + Send + 'static,
+    {
+        let (finished_tx, mut finished_rx) = mpsc::unbounded_channel();
+
+        loop {
+            let (schedule, next_run) = self.next_due()?;
+            let store = schedule.store;
+            let wait = (next_run - Utc::now()).to_std().unwrap_or_default();
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {
+                    let started = Utc::now();
+                    if self.start_due(&schedule, next_run, started)? {
+                        let finished_tx = finished_tx.clone();
+                        let run = crawl(store);
+
+                        tokio::spawn(
+                            async move {
+                                let report = run.await;
+                                let _ = finished_tx.send((store, started, report));
+                            }
+                            .instrument(info_span!("scheduled run", %store)),
+                        );
+                    }
+                }
+                Some((store, started, report)) = finished_rx.recv() => {
+                    self.record_run(store, started, report)?;
+                }
+                _ = shutdown.requested() => {
+                    info!(running = self.running.len(), "waiting for the running crawls");
+
+                    while !self.running.is_empty() {
+                        let Some((store, started, report)) = finished_rx.recv().await else {
+                            break;
+                        };
+                        self.record_run(store, started, report)?;
+                    }
+
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn record_run(
+        &mut self,
+        store: Store,
+        started: DateTime<Utc>,
+        report: Result<CrawlReport>,
+    ) -> Result<()> {
+        self.running.remove(&store);
+
+        let last_run = match report {
+            Ok(report) => {
+                let complete = report.status == CrawlStatus::Complete;
+                info!(%store, crawl_id = %report.crawl_id, complete, "run finished\n{}", report);
+
+                LastRun {
+                    crawl_id: Some(report.crawl_id),
+                    started,
+                    finished: Utc::now(),
+                    complete,
+                }
+            }
+            Err(err) => {
+                error!(%store, ?err, "run failed");
+
+                LastRun {
+                    crawl_id: None,
+                    started,
+                    finished: Utc::now(),
+                    complete: false,
+                }
+            }
+        };
+
+        if let Some(state) = self.state.stores.get_mut(&store) {
+            state.last_run = Some(last_run);
+        }
+
+        self.state.save(&self.state_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::crawl::StoreReport;
+
+    #[test]
+    fn parse_schedules() {
+        let schedule: StoreSchedule = "billa=30 6 * * *".parse().unwrap();
+        assert_eq!(schedule.store, Store::Billa);
+        assert_eq!(schedule.to_string(), "billa=30 6 * * *");
+
+        let after = Utc.with_ymd_and_hms(2023, 6, 1, 7, 0, 0).unwrap();
+        assert_eq!(
+            schedule.next_after(after),
+            Some(Utc.with_ymd_and_hms(2023, 6, 2, 6, 30, 0).unwrap())
+        );
+
+        let every_second: StoreSchedule = "spar=* * * * * *".parse().unwrap();
+        assert_eq!(
+            every_second.next_after(after),
+            Some(Utc.with_ymd_and_hms(2023, 6, 1, 7, 0, 1).unwrap())
+        );
+
+        assert!("hofer=0 6 * * *".parse::<StoreSchedule>().is_err());
+        assert!("billa=0 6 * *".parse::<StoreSchedule>().is_err());
+        assert!("billa=0 25 * * *".parse::<StoreSchedule>().is_err());
+
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert!(parse_duration("5d").is_err());
+    }
+
+    #[test]
+    fn next_runs_are_kept_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let schedules = || vec!["billa=0 6 * * *".parse().unwrap()];
+
+        let daemon = Daemon::new(schedules(), Duration::from_secs(600), &path).unwrap();
+        let planned = daemon.state().stores[&Store::Billa].next_run;
+        let earliest = schedules()[0].next_after(Utc::now()).unwrap();
+        assert!(planned >= earliest);
+        assert!(planned <= earliest + chrono::Duration::seconds(600));
+
+        let restarted = Daemon::new(schedules(), Duration::ZERO, &path).unwrap();
+        assert_eq!(restarted.state().stores[&Store::Billa].next_run, planned);
+
+        let changed = Daemon::new(
+            vec!["billa=0 18 * * *".parse().unwrap()],
+            Duration::ZERO,
+            &path,
+        )
+        .unwrap();
+        assert_eq!(changed.state().stores[&Store::Billa].schedule, "0 18 * * *");
+        assert_eq!(State::load(&path).unwrap(), *changed.state());
+
+        let twice = vec![
+            "billa=0 6 * * *".parse().unwrap(),
+            "billa=0 7 * * *".parse().unwrap(),
+        ];
+        assert!(Daemon::new(twice, Duration::ZERO, &path).is_err());
+    }
+
+    #[test]
+    fn runs_of_a_store_dont_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let schedules: Vec<StoreSchedule> = vec![
+            "billa=* * * * * *".parse().unwrap(),
+            "spar=* * * * * *".parse().unwrap(),
+        ];
+        let mut daemon = Daemon::new(schedules.clone(), Duration::ZERO, &path).unwrap();
+
+        let start = Utc.with_ymd_and_hms(2023, 6, 1, 7, 0, 0).unwrap();
+        let second = |seconds| start + chrono::Duration::seconds(seconds);
+        for schedule in &schedules {
+            daemon.plan(schedule, start).unwrap();
+        }
+
+        let report = |store| CrawlReport {
+            crawl_id: Uuid::new_v4(),
+            status: CrawlStatus::Complete,
+            stores: vec![StoreReport {
+                store,
+                complete: true,
+                interrupted: false,
+                pages: 1,
+                products: 0,
+                listings: None,
+            }],
+        };
+
+        let mut started = Vec::new();
+        for _ in 0..4 {
+            let (schedule, next_run) = daemon.next_due().unwrap();
+            if daemon.start_due(&schedule, next_run, next_run).unwrap() {
+                started.push((schedule.store, next_run));
+            }
+
+            // spar finishes at once, billa is still crawling when it's due again
+            if schedule.store == Store::Spar {
+                daemon
+                    .record_run(Store::Spar, next_run, Ok(report(Store::Spar)))
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            started,
+            vec![
+                (Store::Billa, second(1)),
+                (Store::Spar, second(1)),
+                (Store::Spar, second(2)),
+            ]
+        );
+        assert_eq!(daemon.state().stores[&Store::Billa].next_run, second(3));
+
+        // a run which starts late plans the next one after it
+        let (schedule, next_run) = daemon.next_due().unwrap();
+        assert_eq!((schedule.store, next_run), (Store::Billa, second(3)));
+        assert!(!daemon.start_due(&schedule, next_run, second(10)).unwrap());
+        assert_eq!(daemon.state().stores[&Store::Billa].next_run, second(11));
+
+        let state = State::load(&path).unwrap();
+        assert!(state.stores[&Store::Billa].last_run.is_none());
+        let last_run = state.stores[&Store::Spar].last_run.clone().unwrap();
+        assert!(last_run.complete);
+        assert!(last_run.crawl_id.is_some());
+        assert_eq!(last_run.started, second(2));
+    }
+}
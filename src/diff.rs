@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use strum::IntoEnumIterator;
+
+use crate::model::{Details, Product, Store};
+use crate::output::{Format, Table};
+use crate::query::products;
+
+/// Everything that changed between the crawl sessions `from` and `to`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diff {
+    pub from: Uuid,
+    pub to: Uuid,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub store: Store,
+    pub article_id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added {
+        price: Decimal,
+    },
+    Removed {
+        price: Decimal,
+    },
+    PriceIncrease {
+        from: Decimal,
+        to: Decimal,
+    },
+    PriceDecrease {
+        from: Decimal,
+        to: Decimal,
+    },
+    /// Any other field of the product, e.g. its name or grammage.
+    Attribute {
+        attribute: String,
+        from: String,
+        to: String,
+    },
+}
+
+impl ChangeKind {
+    fn label(&self) -> &str {
+        match self {
+            ChangeKind::Added { .. } => "added",
+            ChangeKind::Removed { .. } => "removed",
+            ChangeKind::PriceIncrease { .. } => "price increase",
+            ChangeKind::PriceDecrease { .. } => "price decrease",
+            ChangeKind::Attribute { attribute, .. } => attribute,
+        }
+    }
+
+    fn values(&self) -> (String, String) {
+        match self {
+            ChangeKind::Added { price } => (String::new(), price.to_string()),
+            ChangeKind::Removed { price } => (price.to_string(), String::new()),
+            ChangeKind::PriceIncrease { from, to } | ChangeKind::PriceDecrease { from, to } => {
+                (from.to_string(), to.to_string())
+            }
+            ChangeKind::Attribute { from, to, .. } => (from.clone(), to.clone()),
+        }
+    }
+}
+
+/// Compares the products of every store in the crawl sessions `from` and `to`.
+pub async fn diff(pool: &PgPool, from: Uuid, to: Uuid) -> Result<Diff> {
+    let mut changes = Vec::new();
+
+    for store in Store::iter() {
+        changes.extend(diff_store(pool, store, from, to).await?);
+    }
+
+    Ok(Diff { from, to, changes })
+}
+
+/// Compares the products of `store` in the crawl sessions `from` and `to`.
+pub async fn diff_store(pool: &PgPool, store: Store, from: Uuid, to: Uuid) -> Result<Vec<Change>> {
+    let before = products(pool, store, from).await?;
+    let after = products(pool, store, to).await?;
+
+    Ok(diff_products(&before, &after))
+}
+
+/// Changes from `before` to `after`, ordered by store and article id.
+pub fn diff_products(before: &[Product], after: &[Product]) -> Vec<Change> {
+    let mut products: BTreeMap<_, (Option<&Product>, Option<&Product>)> = BTreeMap::new();
+    for product in before {
+        products
+            .entry((product.store.to_string(), &product.article_id))
+            .or_default()
+            .0 = Some(product);
+    }
+    for product in after {
+        products
+            .entry((product.store.to_string(), &product.article_id))
+            .or_default()
+            .1 = Some(product);
+    }
+
+    let mut changes = Vec::new();
+    for (before, after) in products.into_values() {
+        let change = |product: &Product, kind| Change {
+            store: product.store,
+            article_id: product.article_id.clone(),
+            name: product.name.clone(),
+            kind,
+        };
+
+        match (before, after) {
+            (None, Some(after)) => {
+                changes.push(change(after, ChangeKind::Added { price: after.price }))
+            }
+            (Some(before), None) => changes.push(change(
+                before,
+                ChangeKind::Removed {
+                    price: before.price,
+                },
+            )),
+            (Some(before), Some(after)) => {
+                let (from, to) = (before.price, after.price);
+                if to > from {
+                    changes.push(change(after, ChangeKind::PriceIncrease { from, to }));
+                } else if to < from {
+                    changes.push(change(after, ChangeKind::PriceDecrease { from, to }));
+                }
+
+                for ((attribute, from), (_, to)) in
+                    attributes(before).into_iter().zip(attributes(after))
+                {
+                    if from != to {
+                        changes.push(change(
+                            after,
+                            ChangeKind::Attribute {
+                                attribute: attribute.to_string(),
+                                from,
+                                to,
+                            },
+                        ));
+                    }
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    changes
+}
+
+/// Fields compared besides the price. The price per unit of Spar is left out as it follows the
+/// price.
+fn attributes(product: &Product) -> Vec<(&'static str, String)> {
+    let mut attributes = vec![
+        ("name", product.name.clone()),
+        ("description", product.description.clone()),
+        ("brand", product.brand.clone()),
+        ("category", product.category.clone()),
+        ("url", product.url.clone()),
+        ("grammage", product.grammage.clone()),
+        ("unit", product.unit.clone()),
+    ];
+
+    if let Details::Billa {
+        badge,
+        price_factor,
+        price_unit,
+        discounted,
+    } = &product.details
+    {
+        attributes.push(("badge", badge.clone()));
+        attributes.push(("price factor", price_factor.to_string()));
+        attributes.push(("price unit", price_unit.clone()));
+        attributes.push(("discounted", discounted.to_string()));
+    }
+
+    attributes
+}
+
+impl Diff {
+    pub fn render(&self, format: Format) -> Result<String> {
+        let output = match format {
+            Format::Json => serde_json::to_string_pretty(self)?,
+            Format::Table => format!(
+                "diff {} -> {}\n{}\n{}",
+                self.from,
+                self.to,
+                self.summary(),
+                self.table().to_text()
+            ),
+            Format::Markdown => format!(
+                "## Diff `{}` → `{}`\n\n{}\n\n{}",
+                self.from,
+                self.to,
+                self.summary(),
+                self.table().to_markdown()
+            ),
+        };
+
+        Ok(output)
+    }
+
+    fn summary(&self) -> String {
+        let count = |f: fn(&ChangeKind) -> bool| {
+            self.changes.iter().filter(|change| f(&change.kind)).count()
+        };
+
+        format!(
+            "{} added, {} removed, {} price increases, {} price decreases, {} attribute changes",
+            count(|kind| matches!(kind, ChangeKind::Added { .. })),
+            count(|kind| matches!(kind, ChangeKind::Removed { .. })),
+            count(|kind| matches!(kind, ChangeKind::PriceIncrease { .. })),
+            count(|kind| matches!(kind, ChangeKind::PriceDecrease { .. })),
+            count(|kind| matches!(kind, ChangeKind::Attribute { .. })),
+        )
+    }
+
+    fn table(&self) -> Table {
+        let mut table = Table::new(["store", "article", "name", "change", "from", "to"]);
+
+        for change in &self.changes {
+            let (from, to) = change.kind.values();
+
+            table.push([
+                change.store.to_string(),
+                change.article_id.clone(),
+                change.name.clone(),
+                change.kind.label().to_string(),
+                from,
+                to,
+            ]);
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn product(article_id: &str, price: Decimal, grammage: &str) -> Product {
+        Product {
+            store: Store::Spar,
+            article_id: article_id.to_string(),
+            name: format!("product {}", article_id),
+            description: String::new(),
+            brand: String::new(),
+            category: "Vegetables".to_string(),
+            url: String::new(),
+            grammage: grammage.to_string(),
+            price,
+            unit_price: None,
+            unit: "kg".to_string(),
+            details: Details::Spar {
+                price_per_unit: format!("{} €/kg", price),
+            },
+        }
+    }
+
+    #[test]
+    fn changes_between_products() {
+        let before = [
+            product("1", dec!(1.99), "1 kg"),
+            product("2", dec!(2.49), "500 g"),
+            product("3", dec!(0.99), "1 kg"),
+        ];
+        let after = [
+            product("2", dec!(2.29), "400 g"),
+            product("3", dec!(0.99), "1 kg"),
+            product("4", dec!(3.00), "1 kg"),
+        ];
+
+        let kinds = diff_products(&before, &after)
+            .into_iter()
+            .map(|change| (change.article_id, change.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ("1".to_string(), ChangeKind::Removed { price: dec!(1.99) }),
+                (
+                    "2".to_string(),
+                    ChangeKind::PriceDecrease {
+                        from: dec!(2.49),
+                        to: dec!(2.29)
+                    }
+                ),
+                (
+                    "2".to_string(),
+                    ChangeKind::Attribute {
+                        attribute: "grammage".to_string(),
+                        from: "500 g".to_string(),
+                        to: "400 g".to_string()
+                    }
+                ),
+                ("4".to_string(), ChangeKind::Added { price: dec!(3.00) }),
+            ]
+        );
+    }
+
+    #[test]
+    fn render_markdown() {
+        let diff = Diff {
+            from: Uuid::nil(),
+            to: Uuid::nil(),
+            changes: diff_products(&[product("1", dec!(1.99), "1 kg")], &[]),
+        };
+
+        let markdown = diff.render(Format::Markdown).unwrap();
+        assert!(markdown.contains("| store | article | name | change | from | to |"));
+        assert!(markdown.contains("| spar | 1 | product 1 | removed | 1.99 |  |"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&diff.render(Format::Json).unwrap()).unwrap();
+        assert_eq!(json["changes"][0]["change"], "removed");
+        assert_eq!(json["changes"][0]["price"], "1.99");
+    }
+}
@@ -0,0 +1,369 @@
+//! Products with their prices and categories as files for tools without access to postgres.
+//!
+//! The columns of every format are the fields of [`ExportRow`], in that order.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::Result;
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, TimestampMicrosecondArray,
+};
+use arrow_schema::{Field, Schema};
+use chrono::{NaiveDate, NaiveDateTime};
+use parquet::arrow::ArrowWriter;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use crate::model::{Details, Store};
+use crate::query::{crawls, products};
+
+/// Scale of the prices in Parquet files, prices per unit are rounded to it.
+const PARQUET_PRICE_SCALE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+/// Which crawl sessions are exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crawls {
+    Session(Uuid),
+    /// Crawl sessions started on the days from `from` up to `to`, both inclusive.
+    Range {
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    },
+}
+
+/// A product with its price in a crawl session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportRow {
+    pub store: Store,
+    pub crawl_id: Uuid,
+    pub crawled: NaiveDateTime,
+    pub article_id: String,
+    pub name: String,
+    pub brand: String,
+    pub description: String,
+    pub category: String,
+    pub grammage: String,
+    pub unit: String,
+    pub price: Decimal,
+    pub unit_price: Option<Decimal>,
+    /// If the price is a promotion, `None` for stores which don't tell.
+    pub discounted: Option<bool>,
+    pub url: String,
+}
+
+/// Rows of the products of `stores` in the crawl sessions, ordered by crawl session, store and
+/// article id. With `latest` only the row of the newest crawl session of every product is kept.
+pub async fn rows(
+    pool: &PgPool,
+    selection: Crawls,
+    stores: &[Store],
+    latest: bool,
+) -> Result<Vec<ExportRow>> {
+    let mut sessions = crawls(pool).await?;
+    sessions.reverse();
+    sessions.retain(|crawl| match selection {
+        Crawls::Session(id) => crawl.id == id,
+        Crawls::Range { from, to } => {
+            let day = crawl.created.date();
+
+            from.is_none_or(|from| day >= from) && to.is_none_or(|to| day <= to)
+        }
+    });
+
+    let mut rows = Vec::new();
+    for crawl in sessions {
+        for store in stores {
+            for product in products(pool, *store, crawl.id).await? {
+                let discounted = match product.details {
+                    Details::Billa { discounted, .. } => Some(discounted),
+                    Details::Spar { .. } => None,
+                };
+
+                rows.push(ExportRow {
+                    store: product.store,
+                    crawl_id: crawl.id,
+                    crawled: crawl.created,
+                    article_id: product.article_id,
+                    name: product.name,
+                    brand: product.brand,
+                    description: product.description,
+                    category: product.category,
+                    grammage: product.grammage,
+                    unit: product.unit,
+                    price: product.price,
+                    unit_price: product.unit_price,
+                    discounted,
+                    url: product.url,
+                });
+            }
+        }
+    }
+
+    if latest {
+        let mut newest = BTreeMap::new();
+        for row in rows {
+            newest.insert((row.store.to_string(), row.article_id.clone()), row);
+        }
+
+        rows = newest.into_values().collect();
+    }
+
+    Ok(rows)
+}
+
+pub fn write<W: Write + Send>(
+    rows: &[ExportRow],
+    format: ExportFormat,
+    mut writer: W,
+) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(writer);
+            if rows.is_empty() {
+                csv.write_record(COLUMNS)?;
+            }
+            for row in rows {
+                csv.serialize(row)?;
+            }
+            csv.flush()?;
+        }
+        ExportFormat::Jsonl => {
+            for row in rows {
+                serde_json::to_writer(&mut writer, row)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Parquet => {
+            let batch = record_batch(rows)?;
+            let mut parquet = ArrowWriter::try_new(writer, batch.schema(), None)?;
+            parquet.write(&batch)?;
+            parquet.close()?;
+        }
+    }
+
+    Ok(())
+}
+
+const COLUMNS: [&str; 14] = [
+    "store",
+    "crawl_id",
+    "crawled",
+    "article_id",
+    "name",
+    "brand",
+    "description",
+    "category",
+    "grammage",
+    "unit",
+    "price",
+    "unit_price",
+    "discounted",
+    "url",
+];
+
+fn record_batch(rows: &[ExportRow]) -> Result<RecordBatch> {
+    let strings = |f: fn(&ExportRow) -> String| -> ArrayRef {
+        Arc::new(StringArray::from(rows.iter().map(f).collect::<Vec<_>>()))
+    };
+    let decimals = |f: fn(&ExportRow) -> Option<Decimal>| -> Result<ArrayRef> {
+        let values = rows
+            .iter()
+            .map(|row| {
+                f(row).map(|price| {
+                    let mut price = price.round_dp(PARQUET_PRICE_SCALE);
+                    price.rescale(PARQUET_PRICE_SCALE);
+
+                    price.mantissa()
+                })
+            })
+            .collect::<Vec<_>>();
+        let array = Decimal128Array::from(values)
+            .with_precision_and_scale(18, PARQUET_PRICE_SCALE as i8)?;
+
+        Ok(Arc::new(array))
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        strings(|row| row.store.to_string()),
+        strings(|row| row.crawl_id.to_string()),
+        Arc::new(TimestampMicrosecondArray::from(
+            rows.iter()
+                .map(|row| row.crawled.and_utc().timestamp_micros())
+                .collect::<Vec<_>>(),
+        )),
+        strings(|row| row.article_id.clone()),
+        strings(|row| row.name.clone()),
+        strings(|row| row.brand.clone()),
+        strings(|row| row.description.clone()),
+        strings(|row| row.category.clone()),
+        strings(|row| row.grammage.clone()),
+        strings(|row| row.unit.clone()),
+        decimals(|row| Some(row.price))?,
+        decimals(|row| row.unit_price)?,
+        Arc::new(BooleanArray::from(
+            rows.iter().map(|row| row.discounted).collect::<Vec<_>>(),
+        )),
+        strings(|row| row.url.clone()),
+    ];
+
+    let fields = COLUMNS
+        .iter()
+        .zip(&columns)
+        .map(|(name, column)| {
+            let nullable = matches!(*name, "unit_price" | "discounted");
+
+            Field::new(*name, column.data_type().clone(), nullable)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::test_support::{crawl_billa, mock_billa, TestDatabase};
+
+    fn row(article_id: &str, unit_price: Option<Decimal>) -> ExportRow {
+        ExportRow {
+            store: Store::Billa,
+            crawl_id: Uuid::nil(),
+            crawled: NaiveDate::from_ymd_opt(2023, 6, 1)
+                .unwrap()
+                .and_hms_opt(8, 0, 0)
+                .unwrap(),
+            article_id: article_id.to_string(),
+            name: "Ölz Toastbrot".to_string(),
+            brand: "Ölz".to_string(),
+            description: "Klassisches, weiches Toastbrot".to_string(),
+            category: "Bread".to_string(),
+            grammage: "500 g".to_string(),
+            unit: "kg".to_string(),
+            price: dec!(2.79),
+            unit_price,
+            discounted: Some(false),
+            url: String::new(),
+        }
+    }
+
+    #[test]
+    fn write_formats() {
+        let rows = [row("00-384201", Some(dec!(5.58))), row("00-384202", None)];
+
+        let mut csv = Vec::new();
+        write(&rows, ExportFormat::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next().unwrap(), COLUMNS.join(","));
+        assert_eq!(
+            lines.next().unwrap(),
+            "billa,00000000-0000-0000-0000-000000000000,2023-06-01T08:00:00,00-384201,Ölz Toastbrot,Ölz,\"Klassisches, weiches Toastbrot\",Bread,500 g,kg,2.79,5.58,false,"
+        );
+
+        let mut empty = Vec::new();
+        write(&[], ExportFormat::Csv, &mut empty).unwrap();
+        assert_eq!(String::from_utf8(empty).unwrap(), COLUMNS.join(",") + "\n");
+
+        let mut jsonl = Vec::new();
+        write(&rows, ExportFormat::Jsonl, &mut jsonl).unwrap();
+        let lines = String::from_utf8(jsonl).unwrap();
+        let json: serde_json::Value = serde_json::from_str(lines.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(json["price"], "2.79");
+        assert_eq!(json["unit_price"], serde_json::Value::Null);
+
+        let mut parquet = tempfile::tempfile().unwrap();
+        write(&rows, ExportFormat::Parquet, &mut parquet).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(parquet)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        let schema = batches[0].schema();
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(columns, COLUMNS);
+        assert_eq!(batches[0].num_rows(), 2);
+        let prices = batches[0]
+            .column(10)
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .unwrap();
+        assert_eq!(prices.value_as_string(0), "2.7900");
+        assert!(batches[0].column(11).is_null(1));
+    }
+
+    #[tokio::test]
+    async fn rows_of_crawls() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        let mut crawl_ids = Vec::new();
+        for _ in 0..2 {
+            let crawl_id = crawl_billa(&db.storage, &server).await;
+            crawl_ids.push(crawl_id);
+        }
+
+        let all = Crawls::Range {
+            from: None,
+            to: None,
+        };
+        let rows_of_all = rows(&db.pool, all, &[Store::Billa, Store::Spar], false)
+            .await
+            .unwrap();
+        assert_eq!(rows_of_all.len(), 14);
+        assert_eq!(rows_of_all[0].crawl_id, crawl_ids[0]);
+
+        let latest = rows(&db.pool, all, &[Store::Billa], true).await.unwrap();
+        assert_eq!(latest.len(), 7);
+        assert!(latest.iter().all(|row| row.crawl_id == crawl_ids[1]));
+        let toast = latest
+            .iter()
+            .find(|row| row.article_id == "00-384201")
+            .unwrap();
+        assert_eq!(toast.price, dec!(2.79));
+        assert_eq!(toast.category, "Bread");
+
+        let session = rows(
+            &db.pool,
+            Crawls::Session(crawl_ids[0]),
+            &[Store::Billa],
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(session.len(), 7);
+        let before = Crawls::Range {
+            from: None,
+            to: NaiveDate::from_ymd_opt(2000, 1, 1),
+        };
+        assert!(rows(&db.pool, before, &[Store::Billa], false)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.close().await;
+    }
+}
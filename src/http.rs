@@ -0,0 +1,381 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::model::{JournalEntry, Store};
+use crate::utils::random_user_agent;
+
+/// Headers kept in the journal of the requests, they tell about caching, rate limits and blocking.
+pub const JOURNAL_HEADERS: [&str; 10] = [
+    "age",
+    "cf-cache-status",
+    "cf-ray",
+    "content-type",
+    "retry-after",
+    "server",
+    "x-cache",
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+];
+
+/// Delay before the first retry of a request, doubled for every further one.
+pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay before a retry, also for longer `Retry-After` headers.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(2 * 60);
+
+/// Characters of the url in front of the hash in the names of recorded responses.
+const RECORD_PREFIX_LEN: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub enum Mode {
+    /// Requests go to the shops.
+    #[default]
+    Live,
+    /// Requests go to the shops and every response is saved into the directory, repeated requests
+    /// of a url, e.g. retries, each into their own file.
+    Record(PathBuf),
+    /// Requests are answered with the responses saved into the directory by [`Mode::Record`], the
+    /// responses of a url in the order they were recorded. The last one answers every further
+    /// request.
+    Replay(PathBuf),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub url: String,
+    pub status: u16,
+    /// The headers in [`JOURNAL_HEADERS`], missing in older recordings.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+impl Response {
+    /// The delay the shop asks for in its `Retry-After` header, in seconds or as a date.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let value = self.headers.get("retry-after")?.trim();
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+
+        let date = DateTime::parse_from_rfc2822(value).ok()?;
+        Some(
+            date.signed_duration_since(Utc::now())
+                .to_std()
+                .unwrap_or_default(),
+        )
+    }
+}
+
+/// Sends the requests of the crawlers, see [`Mode`].
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client,
+    mode: Arc<Mode>,
+    /// How often each url was requested while recording or replaying.
+    requests: Arc<Mutex<HashMap<String, usize>>>,
+    user_agent: &'static str,
+    retry_delay: Duration,
+    max_retry_delay: Duration,
+}
+
+impl Fetcher {
+    pub fn new(mode: Mode) -> Result<Self> {
+        let user_agent = random_user_agent();
+        let client = Client::builder()
+            .user_agent(user_agent)
+            .gzip(true)
+            .build()?;
+
+        Ok(Fetcher {
+            client,
+            mode: Arc::new(mode),
+            requests: Arc::default(),
+            user_agent,
+            retry_delay: RETRY_DELAY,
+            max_retry_delay: MAX_RETRY_DELAY,
+        })
+    }
+
+    /// Overrides [`RETRY_DELAY`] and [`MAX_RETRY_DELAY`].
+    pub fn with_retry_delays(mut self, retry_delay: Duration, max_retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self.max_retry_delay = max_retry_delay;
+        self
+    }
+
+    pub fn user_agent(&self) -> &'static str {
+        self.user_agent
+    }
+
+    /// Delay before retrying a request which failed on its `attempt`, the `retry_after` of the
+    /// shop if it asked for one, otherwise doubled for every attempt. Replays don't wait.
+    pub fn retry_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if matches!(self.mode.as_ref(), Mode::Replay(_)) {
+            return Duration::ZERO;
+        }
+
+        let backoff = self
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+
+        retry_after.unwrap_or(backoff).min(self.max_retry_delay)
+    }
+
+    /// Journal entry of the request for `url` which got `res` after `duration`.
+    pub fn journal_entry(
+        &self,
+        store: Store,
+        url: &str,
+        res: &Result<Response>,
+        duration: Duration,
+        attempt: u32,
+    ) -> JournalEntry {
+        let (status, headers, bytes, error) = match res {
+            Ok(res) => (Some(res.status), res.headers.clone(), res.body.len(), None),
+            Err(err) => (None, BTreeMap::new(), 0, Some(format!("{:#}", err))),
+        };
+
+        JournalEntry {
+            store,
+            url: url.to_string(),
+            status,
+            headers,
+            duration_ms: duration.as_millis() as u64,
+            bytes,
+            attempt,
+            user_agent: self.user_agent.to_string(),
+            error,
+        }
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response> {
+        match self.mode.as_ref() {
+            Mode::Live => self.send(url).await,
+            Mode::Record(dir) => {
+                let response = self.send(url).await?;
+
+                let path = record_path(dir, url, self.next_request(url));
+                tokio::fs::create_dir_all(dir).await?;
+                tokio::fs::write(path, serde_json::to_vec(&response)?).await?;
+
+                Ok(response)
+            }
+            Mode::Replay(dir) => {
+                let mut request = self.next_request(url);
+                let mut path = record_path(dir, url, request);
+                if request > 1 && !tokio::fs::try_exists(&path).await? {
+                    request -= 1;
+                    path = record_path(dir, url, request);
+                    self.requests
+                        .lock()
+                        .unwrap()
+                        .insert(url.to_string(), request);
+                }
+
+                let content = tokio::fs::read(&path)
+                    .await
+                    .with_context(|| format!("no recorded response for {}", url))?;
+
+                Ok(serde_json::from_slice(&content)?)
+            }
+        }
+    }
+
+    /// Counts a request of `url`, returns its number starting at 1.
+    fn next_request(&self, url: &str) -> usize {
+        let mut requests = self.requests.lock().unwrap();
+        let count = requests.entry(url.to_string()).or_default();
+        *count += 1;
+
+        *count
+    }
+
+    async fn send(&self, url: &str) -> Result<Response> {
+        let res = self.client.get(url).send().await?;
+
+        let headers = JOURNAL_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = res.headers().get(*name)?.to_str().ok()?;
+
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+
+        Ok(Response {
+            url: url.to_string(),
+            status: res.status().as_u16(),
+            headers,
+            body: res.text().await?,
+        })
+    }
+}
+
+/// File name of the `request`th recorded response for `url`, stable across runs.
+///
+/// Named by the SHA-256 of the whole url, so urls which only differ in special characters don't
+/// collide and long urls stay below the length limit of file names. The start of the url in front
+/// of the hash keeps the files readable. Responses after the first one end in `_<request>`.
+fn record_path(dir: &Path, url: &str, request: usize) -> PathBuf {
+    let readable = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .chars()
+        .take(RECORD_PREFIX_LEN)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let hash = hex::encode(Sha256::digest(url.as_bytes()));
+
+    if request > 1 {
+        dir.join(format!("{}_{}_{}.json", readable, hash, request))
+    } else {
+        dir.join(format!("{}_{}.json", readable, hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_returns_recorded_responses() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/page"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{\"page\":1}"))
+            .mount(&server)
+            .await;
+        // fails on the first request only
+        Mock::given(method("GET"))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("retried"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/missing"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("not found"))
+            .mount(&server)
+            .await;
+
+        let page = format!("{}/page?id=1", server.uri());
+        let missing = format!("{}/missing", server.uri());
+        let flaky = format!("{}/flaky", server.uri());
+
+        let record = Fetcher::new(Mode::Record(dir.path().to_path_buf())).unwrap();
+        record.get(&page).await.unwrap();
+        record.get(&missing).await.unwrap();
+        record.get(&flaky).await.unwrap();
+        record.get(&flaky).await.unwrap();
+
+        drop(server);
+
+        let replay = Fetcher::new(Mode::Replay(dir.path().to_path_buf())).unwrap();
+
+        let response = replay.get(&page).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "{\"page\":1}");
+
+        let response = replay.get(&missing).await.unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, "not found");
+
+        // in the recorded order, the last one repeats
+        let statuses = [
+            replay.get(&flaky).await.unwrap().status,
+            replay.get(&flaky).await.unwrap().status,
+            replay.get(&flaky).await.unwrap().status,
+        ];
+        assert_eq!(statuses, [500, 200, 200]);
+        assert_eq!(replay.get(&page).await.unwrap().body, "{\"page\":1}");
+
+        assert!(replay.get(&format!("{}/other", page)).await.is_err());
+    }
+
+    #[test]
+    fn retries_back_off() {
+        let fetcher = Fetcher::new(Mode::Live)
+            .unwrap()
+            .with_retry_delays(Duration::from_secs(1), Duration::from_secs(60));
+
+        assert_eq!(fetcher.retry_delay(1, None), Duration::from_secs(1));
+        assert_eq!(fetcher.retry_delay(3, None), Duration::from_secs(4));
+        assert_eq!(fetcher.retry_delay(100, None), Duration::from_secs(60));
+        assert_eq!(
+            fetcher.retry_delay(1, Some(Duration::from_secs(30))),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            fetcher.retry_delay(1, Some(Duration::from_secs(3600))),
+            Duration::from_secs(60)
+        );
+
+        let replay = Fetcher::new(Mode::Replay(PathBuf::from("recorded"))).unwrap();
+        assert_eq!(replay.retry_delay(5, None), Duration::ZERO);
+
+        let mut response = Response {
+            url: "https://shop.billa.at".to_string(),
+            status: 429,
+            headers: BTreeMap::new(),
+            body: String::new(),
+        };
+        assert_eq!(response.retry_after(), None);
+        response
+            .headers
+            .insert("retry-after".to_string(), "30".to_string());
+        assert_eq!(response.retry_after(), Some(Duration::from_secs(30)));
+        response.headers.insert(
+            "retry-after".to_string(),
+            "Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+        );
+        assert_eq!(response.retry_after(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn record_paths_are_unique_and_short() {
+        let dir = Path::new("recorded");
+
+        assert_ne!(
+            record_path(dir, "https://shop.billa.at/search?q=a+b", 1),
+            record_path(dir, "https://shop.billa.at/search?q=a_b", 1)
+        );
+
+        let long = format!("https://shop.billa.at/search?q={}", "a".repeat(1000));
+        assert_ne!(record_path(dir, &long, 1), record_path(dir, &long, 2));
+        let name = record_path(dir, &long, 2);
+        assert!(name.file_name().unwrap().len() < 255);
+        assert!(name
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("shop_billa_at_search_q_"));
+    }
+}
@@ -0,0 +1,158 @@
+//! Reports on the journal of the requests, which every storage keeps with
+//! [`crate::storage::Storage::save_request`].
+//!
+//! The report reads the view `rs_request_stats` of postgres, the raw entries stay in
+//! `rj_request_journal` for further analysis.
+
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use crate::output::{Format, Table};
+
+/// The requests of a store in a crawl session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct RequestStats {
+    #[sqlx(rename = "rj_bcw_crawl")]
+    pub crawl_id: Uuid,
+    #[sqlx(rename = "rj_store")]
+    pub store: String,
+    #[sqlx(rename = "rs_started")]
+    pub started: Option<NaiveDateTime>,
+    #[sqlx(rename = "rs_requests")]
+    pub requests: i64,
+    /// Requests without a response.
+    #[sqlx(rename = "rs_failed")]
+    pub failed: i64,
+    /// Responses with 403 or 429.
+    #[sqlx(rename = "rs_blocked")]
+    pub blocked: i64,
+    #[sqlx(rename = "rs_server_errors")]
+    pub server_errors: i64,
+    /// Requests of a page which was requested before.
+    #[sqlx(rename = "rs_retries")]
+    pub retries: i64,
+    #[sqlx(rename = "rs_avg_ms")]
+    pub avg_ms: i64,
+    #[sqlx(rename = "rs_p95_ms")]
+    pub p95_ms: i64,
+    #[sqlx(rename = "rs_bytes")]
+    pub bytes: i64,
+}
+
+/// The stores of `crawl_id` or of every crawl session, the newest first.
+pub async fn stats(pool: &PgPool, crawl_id: Option<Uuid>) -> Result<Vec<RequestStats>> {
+    let stats = sqlx::query_as(
+        "select *
+        from rs_request_stats
+        where $1::uuid is null or rj_bcw_crawl = $1
+        order by rs_started desc, rj_store",
+    )
+    .bind(crawl_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(stats)
+}
+
+pub fn render_stats(stats: &[RequestStats], format: Format) -> Result<String> {
+    let mut table = Table::new([
+        "crawl", "store", "started", "requests", "failed", "blocked", "5xx", "retries", "avg ms",
+        "p95 ms", "bytes",
+    ]);
+    for stat in stats {
+        table.push([
+            stat.crawl_id.to_string(),
+            stat.store.clone(),
+            stat.started
+                .map(|started| started.format("%F %T").to_string())
+                .unwrap_or_default(),
+            stat.requests.to_string(),
+            stat.failed.to_string(),
+            stat.blocked.to_string(),
+            stat.server_errors.to_string(),
+            stat.retries.to_string(),
+            stat.avg_ms.to_string(),
+            stat.p95_ms.to_string(),
+            stat.bytes.to_string(),
+        ]);
+    }
+
+    let output = match format {
+        Format::Table => table.to_text(),
+        Format::Json => serde_json::to_string_pretty(stats)?,
+        Format::Markdown => table.to_markdown(),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::matchers::{method, query_param};
+    use wiremock::{Mock, ResponseTemplate};
+
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::storage::Storage;
+    use crate::stores::billa::BillaCrawl;
+    use crate::stores::ExecuteCrawler;
+    use crate::test_support::{mock_billa, TestDatabase};
+
+    #[tokio::test]
+    async fn every_request_is_journaled() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        Mock::given(method("GET"))
+            .and(query_param("category", "B2-2"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "30"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let crawl_id = db.storage.create_crawl().await.unwrap();
+        // the retry-after of 30 s is capped
+        let fetcher = Fetcher::new(Mode::Live)
+            .unwrap()
+            .with_retry_delays(Duration::from_millis(10), Duration::from_millis(50));
+        BillaCrawl::execute(&db.storage, crawl_id, fetcher.clone(), &server.uri())
+            .await
+            .unwrap();
+
+        let throttled: Vec<(Option<i32>, Option<String>, i32, String)> = sqlx::query_as(
+            "select rj_status, rj_headers->>'retry-after', rj_attempt, rj_user_agent
+            from rj_request_journal
+            where rj_bcw_crawl = $1 and rj_url like '%category=B2-2&%'
+            order by rj_created",
+        )
+        .bind(crawl_id)
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(throttled.len(), 2);
+        assert_eq!(throttled[0].0, Some(429));
+        assert_eq!(throttled[0].1.as_deref(), Some("30"));
+        assert_eq!(throttled[1].0, Some(200));
+        assert_eq!(throttled[1].2, 2);
+        assert_eq!(throttled[1].3, fetcher.user_agent());
+
+        let stats = stats(&db.pool, Some(crawl_id)).await.unwrap();
+        assert_eq!(stats.len(), 1);
+        // 10 pages and the throttled request
+        assert_eq!(stats[0].requests, 11);
+        assert_eq!((stats[0].blocked, stats[0].retries), (1, 1));
+        assert!(stats[0].bytes > 0);
+
+        let output = render_stats(&stats, Format::Table).unwrap();
+        assert!(output.contains(&crawl_id.to_string()));
+
+        db.close().await;
+    }
+}
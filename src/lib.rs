@@ -0,0 +1,27 @@
+//! Crawls the online shops of Austrian grocery stores and keeps the history of their prices in
+//! postgres, sqlite or JSON lines files, see [`storage::Storage`].
+
+pub mod api;
+pub mod blob;
+pub mod crawl;
+pub mod daemon;
+pub mod diff;
+pub mod export;
+pub mod http;
+pub mod journal;
+pub mod metrics;
+pub mod model;
+pub mod output;
+pub mod partition;
+pub mod prune;
+pub mod query;
+pub mod queue;
+pub mod repair;
+pub mod shutdown;
+pub mod storage;
+pub mod stores;
+pub mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod utils;
+pub mod watch;
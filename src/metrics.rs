@@ -0,0 +1,224 @@
+//! Prometheus metrics of the crawler, served on `/metrics` next to a `/health` check while a
+//! crawl runs.
+
+use std::net::TcpListener;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use serde_json::json;
+
+use crate::model::Store;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Labeled by store and status, `error` if no response arrived.
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    /// Labeled by store and category.
+    pub pages: IntCounterVec,
+    /// Labeled by store and category.
+    pub products: IntCounterVec,
+    /// Products of a page which couldn't be parsed, labeled by store.
+    pub parse_failures: IntCounterVec,
+    /// Storing a page with its products and prices, labeled by store.
+    pub insert_duration: HistogramVec,
+    /// Unix time of the last complete crawl, labeled by store.
+    pub last_successful_crawl: GaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("crawler_requests_total", "Requests to the shops"),
+            &["store", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "crawler_request_duration_seconds",
+                "Latency of the requests to the shops",
+            ),
+            &["store"],
+        )?;
+        let pages = IntCounterVec::new(
+            Opts::new("crawler_pages_total", "Downloaded pages"),
+            &["store", "category"],
+        )?;
+        let products = IntCounterVec::new(
+            Opts::new("crawler_products_total", "Products found on the pages"),
+            &["store", "category"],
+        )?;
+        let parse_failures = IntCounterVec::new(
+            Opts::new(
+                "crawler_parse_failures_total",
+                "Products of the pages which couldn't be parsed",
+            ),
+            &["store"],
+        )?;
+        let insert_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "crawler_db_insert_duration_seconds",
+                "Duration of storing a page with its products",
+            ),
+            &["store"],
+        )?;
+        let last_successful_crawl = GaugeVec::new(
+            Opts::new(
+                "crawler_last_successful_crawl_timestamp_seconds",
+                "Unix time of the last complete crawl",
+            ),
+            &["store"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(pages.clone()))?;
+        registry.register(Box::new(products.clone()))?;
+        registry.register(Box::new(parse_failures.clone()))?;
+        registry.register(Box::new(insert_duration.clone()))?;
+        registry.register(Box::new(last_successful_crawl.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests,
+            request_duration,
+            pages,
+            products,
+            parse_failures,
+            insert_duration,
+            last_successful_crawl,
+        })
+    }
+
+    pub fn observe_request(&self, store: Store, status: Option<u16>, duration: Duration) {
+        let status = status.map_or_else(|| "error".to_string(), |status| status.to_string());
+
+        self.requests
+            .with_label_values(&[&store.to_string(), &status])
+            .inc();
+        self.request_duration
+            .with_label_values(&[&store.to_string()])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_page(&self, store: Store, category: &str, products: usize) {
+        let labels = [store.to_string(), category.to_string()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+
+        self.pages.with_label_values(&labels).inc();
+        self.products
+            .with_label_values(&labels)
+            .inc_by(products as u64);
+    }
+
+    pub fn crawl_succeeded(&self, store: Store) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.last_successful_crawl
+            .with_label_values(&[&store.to_string()])
+            .set(now.as_secs_f64());
+    }
+
+    /// All metrics in the text format of Prometheus.
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// The metrics of the process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+    METRICS.get_or_init(|| Metrics::new().expect("metrics are registered once"))
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/metrics", get(serve_metrics))
+        .route("/health", get(health))
+}
+
+/// Serves `/metrics` and `/health` on `listener` until the process stops.
+pub async fn serve(listener: TcpListener) -> Result<()> {
+    axum::Server::from_tcp(listener)?
+        .serve(router().into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+async fn serve_metrics() -> impl IntoResponse {
+    match metrics().render() {
+        Ok(text) => (
+            [(CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+            text,
+        )
+            .into_response(),
+        Err(err) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            err.to_string(),
+        )
+            .into_response(),
+    }
+}
+
+async fn health() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+    use crate::test_support::{crawl_billa, mock_billa};
+
+    #[tokio::test]
+    async fn crawl_is_measured() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(dir.path()).await.unwrap();
+        let server = mock_billa().await;
+
+        crawl_billa(&storage, &server).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener));
+
+        let health = reqwest::get(format!("{}/health", base_url)).await.unwrap();
+        assert_eq!(health.status().as_u16(), 200);
+
+        let text = reqwest::get(format!("{}/metrics", base_url))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let value = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .unwrap_or_default()
+        };
+
+        // the tests share the metrics of the process, other crawls can only add to them
+        assert!(value(r#"crawler_requests_total{status="200",store="billa"}"#) >= 3.0);
+        assert!(value(r#"crawler_pages_total{category="Bread",store="billa"}"#) >= 1.0);
+        assert!(value(r#"crawler_products_total{category="Bread",store="billa"}"#) >= 2.0);
+        assert!(value(r#"crawler_db_insert_duration_seconds_count{store="billa"}"#) >= 1.0);
+        assert!(text.contains("crawler_request_duration_seconds_bucket"));
+    }
+}
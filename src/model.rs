@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter, EnumString};
+
+use crate::stores::{billa, spar};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    EnumIter,
+    EnumString,
+    Display,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Store {
+    Billa,
+    Spar,
+}
+
+/// Store independent view of a product with the price of a single crawl.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Product {
+    pub store: Store,
+    /// Id of the article in the online shop of the store.
+    pub article_id: String,
+    pub name: String,
+    pub description: String,
+    pub brand: String,
+    pub category: String,
+    pub url: String,
+    pub grammage: String,
+    pub price: Decimal,
+    /// Price per [`Product::unit`], if the store publishes it.
+    pub unit_price: Option<Decimal>,
+    pub unit: String,
+    pub details: Details,
+}
+
+/// Fields only one of the stores knows about, kept to store them unchanged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Details {
+    Billa {
+        badge: String,
+        price_factor: Decimal,
+        price_unit: String,
+        /// If the product is on promotion.
+        #[serde(default)]
+        discounted: bool,
+    },
+    Spar {
+        price_per_unit: String,
+    },
+}
+
+/// Article as named in crawl reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Listing {
+    pub article_id: String,
+    pub name: String,
+}
+
+/// How far the crawl of a category got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CategoryProgress {
+    /// Every page was downloaded and stored.
+    Done,
+    /// The crawl was interrupted before this page, a resumed crawl continues with it.
+    NextPage(usize),
+}
+
+/// How a crawl session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum CrawlStatus {
+    /// Every category of every store was crawled.
+    Complete,
+    /// Some categories failed.
+    Incomplete,
+    /// Stopped by a shutdown, the crawl session can be resumed.
+    Interrupted,
+}
+
+/// Outcome of a crawl session, or of the resumed part of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrawlSummary {
+    pub status: CrawlStatus,
+    /// Pages downloaded and stored.
+    pub pages: usize,
+    /// Products stored with their prices.
+    pub products: usize,
+}
+
+/// Products whose listing in a store changed with a complete crawl.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListingChanges {
+    /// Products seen for the first time.
+    pub added: Vec<Listing>,
+    /// Products which weren't part of the crawl and are marked as delisted.
+    pub removed: Vec<Listing>,
+    /// Delisted products which are back.
+    pub relisted: Vec<Listing>,
+}
+
+/// A request of a crawl session, kept to see when the shops block, throttle or slow down the
+/// crawler.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub store: Store,
+    pub url: String,
+    /// `None` if no response arrived, see `error`.
+    pub status: Option<u16>,
+    /// The headers of the response in [`crate::http::JOURNAL_HEADERS`].
+    pub headers: BTreeMap<String, String>,
+    pub duration_ms: u64,
+    /// Size of the body.
+    pub bytes: usize,
+    /// How often the page was requested in the crawl session, including this request.
+    pub attempt: u32,
+    pub user_agent: String,
+    pub error: Option<String>,
+}
+
+impl billa::Product {
+    pub fn to_model(&self, category: billa::Category) -> Product {
+        Product {
+            store: Store::Billa,
+            article_id: self.billa_id.clone(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            brand: self.brand.clone(),
+            category: format!("{:?}", category),
+            url: self.online_shop_url.clone(),
+            grammage: self.grammage.clone(),
+            price: self.price.normal,
+            unit_price: Some(self.price.normal * self.grammage_price_factor),
+            unit: self.grammage_unit.clone(),
+            details: Details::Billa {
+                badge: self.grammage_badge.clone(),
+                price_factor: self.grammage_price_factor,
+                price_unit: self.price.unit.clone(),
+                discounted: self.price.discounted,
+            },
+        }
+    }
+}
+
+impl spar::Product {
+    pub fn to_model(&self, category: spar::Category) -> Product {
+        let unit_price = parse_unit_price(&self.price_per_unit);
+
+        Product {
+            store: Store::Spar,
+            article_id: self.id_internal.clone(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            brand: self.brand.join(";"),
+            category: format!("{:?}", category),
+            url: self.url.clone(),
+            grammage: self.sales_unit.clone(),
+            price: self.price,
+            unit_price: unit_price.as_ref().map(|(price, _)| *price),
+            unit: unit_price.map(|(_, unit)| unit).unwrap_or_default(),
+            details: Details::Spar {
+                price_per_unit: self.price_per_unit.clone(),
+            },
+        }
+    }
+}
+
+/// Parses unit prices as published by Spar, e.g. `"1.49 €/kg"`.
+pub fn parse_unit_price(value: &str) -> Option<(Decimal, String)> {
+    let (price, unit) = value.split_once("€/")?;
+    let price = price.trim().replace(',', ".").parse().ok()?;
+
+    Some((price, unit.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn unit_price_of_spar() {
+        assert_eq!(
+            parse_unit_price("1.49 €/kg"),
+            Some((dec!(1.49), "kg".to_string()))
+        );
+        assert_eq!(
+            parse_unit_price("0,99 €/l"),
+            Some((dec!(0.99), "l".to_string()))
+        );
+        assert_eq!(parse_unit_price(""), None);
+    }
+
+    #[test]
+    fn unit_price_of_billa_is_exact() {
+        let page: serde_json::Value =
+            serde_json::from_str(&crate::test_support::fixture("billa", "B2-1_1.json")).unwrap();
+        let product: billa::Product =
+            serde_json::from_value(page["tiles"][2]["data"].clone()).unwrap();
+
+        let product = product.to_model(billa::Category::Vegetables);
+        assert_eq!(product.price, dec!(2.49));
+        assert_eq!(product.unit_price, Some(dec!(4.98)));
+    }
+}
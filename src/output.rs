@@ -0,0 +1,88 @@
+/// Output formats of the reporting commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Table,
+    Json,
+    Markdown,
+}
+
+/// Rows of text rendered as a table for the terminal or in Markdown.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new<T: Into<String>>(header: impl IntoIterator<Item = T>) -> Self {
+        Table {
+            header: header.into_iter().map(Into::into).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push<T: Into<String>>(&mut self, row: impl IntoIterator<Item = T>) {
+        self.rows.push(row.into_iter().map(Into::into).collect());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Columns padded to the widest cell, separated by two spaces.
+    pub fn to_text(&self) -> String {
+        let mut widths = self
+            .header
+            .iter()
+            .map(|cell| cell.chars().count())
+            .collect::<Vec<_>>();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let line = |row: &[String]| {
+            let cells = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>();
+
+            format!("{}\n", cells.join("  ").trim_end())
+        };
+
+        let mut text = line(&self.header);
+        text.push_str(&line(
+            &widths
+                .iter()
+                .map(|width| "-".repeat(*width))
+                .collect::<Vec<_>>(),
+        ));
+        for row in &self.rows {
+            text.push_str(&line(row));
+        }
+
+        text
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let line = |row: &[String]| {
+            let cells = row
+                .iter()
+                .map(|cell| cell.replace('|', "\\|"))
+                .collect::<Vec<_>>();
+
+            format!("| {} |\n", cells.join(" | "))
+        };
+
+        let mut markdown = line(&self.header);
+        markdown.push_str(&line(&vec!["---".to_string(); self.header.len()]));
+        for row in &self.rows {
+            markdown.push_str(&line(row));
+        }
+
+        markdown
+    }
+}
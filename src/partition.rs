@@ -0,0 +1,285 @@
+//! Monthly partitions of the raw and price tables of postgres.
+//!
+//! `br_billa_raw`, `bp_billa_price`, `sr_spar_raw` and `spr_spar_price` are range partitioned by
+//! their created timestamp. A price has the created timestamp of its raw document, so both are in
+//! the partitions of the same month, e.g. `br_billa_raw_2023_06` and `bp_billa_price_2023_06`.
+//! The crawler creates the partitions of the current and the next month whenever it starts or
+//! resumes a crawl session, see [`maintain`].
+//!
+//! Given a number of months it also detaches the partitions of months older than that, once all of
+//! their price intervals ended before. Detached partitions are left as tables of their own, to
+//! be archived or dropped.
+
+use anyhow::Result;
+use chrono::{Datelike, Months, NaiveDate};
+use sqlx::{PgConnection, PgPool};
+use tracing::info;
+
+struct PartitionedTables {
+    raw: &'static str,
+    prices: &'static str,
+    valid_to: &'static str,
+}
+
+const TABLES: [PartitionedTables; 2] = [
+    PartitionedTables {
+        raw: "br_billa_raw",
+        prices: "bp_billa_price",
+        valid_to: "bp_valid_to",
+    },
+    PartitionedTables {
+        raw: "sr_spar_raw",
+        prices: "spr_spar_price",
+        valid_to: "spr_valid_to",
+    },
+];
+
+/// Serializes the changes of the partitions of concurrent crawlers.
+const LOCK_KEY: &str = "austria_online_grocery_store partitions";
+
+/// Creates the partitions of the current and the next month and detaches the ones older than
+/// `detach_after` months, if given.
+pub async fn maintain(pool: &PgPool, detach_after: Option<u32>) -> Result<()> {
+    let month = current_month(pool).await?;
+    create_partitions(pool, month, month + Months::new(1)).await?;
+
+    if let Some(months) = detach_after {
+        for partition in detach_partitions(pool, months).await? {
+            info!(partition, "detached partition");
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the partitions of every month from `from` up to `to` which don't exist yet.
+pub async fn create_partitions(pool: &PgPool, from: NaiveDate, to: NaiveDate) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("select pg_advisory_xact_lock(hashtextextended($1, 0))")
+        .bind(LOCK_KEY)
+        .execute(&mut tx)
+        .await?;
+
+    let mut month = from.with_day(1).unwrap();
+    while month <= to {
+        let next = month + Months::new(1);
+
+        for tables in &TABLES {
+            for table in [tables.raw, tables.prices] {
+                sqlx::query(&format!(
+                    "create table if not exists {} partition of {} for values from ('{}') to ('{}')",
+                    partition_name(table, month),
+                    table,
+                    month,
+                    next
+                ))
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+
+        month = next;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Detaches the partitions of months before the last `months` whose price intervals all ended
+/// before, returns their names.
+pub async fn detach_partitions(pool: &PgPool, months: u32) -> Result<Vec<String>> {
+    let cutoff = current_month(pool).await? - Months::new(months);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("select pg_advisory_xact_lock(hashtextextended($1, 0))")
+        .bind(LOCK_KEY)
+        .execute(&mut tx)
+        .await?;
+
+    let mut detached = Vec::new();
+    for tables in &TABLES {
+        for (partition, month) in partitions(&mut tx, tables.prices).await? {
+            if month >= cutoff {
+                continue;
+            }
+
+            let open: (bool,) = sqlx::query_as(&format!(
+                "select exists (select 1 from {} where {} is null or {} >= $1)",
+                partition, tables.valid_to, tables.valid_to
+            ))
+            .bind(cutoff.and_hms_opt(0, 0, 0).unwrap())
+            .fetch_one(&mut tx)
+            .await?;
+            if open.0 {
+                continue;
+            }
+
+            sqlx::query(&format!(
+                "alter table {} detach partition {}",
+                tables.prices, partition
+            ))
+            .execute(&mut tx)
+            .await?;
+            // the detached prices keep referring to their documents, which would keep these
+            // from being detached as well
+            let foreign_keys: Vec<(String,)> = sqlx::query_as(
+                "select conname from pg_constraint
+                where conrelid = $1::regclass and confrelid = $2::regclass and contype = 'f'",
+            )
+            .bind(&partition)
+            .bind(tables.raw)
+            .fetch_all(&mut tx)
+            .await?;
+            for (foreign_key,) in foreign_keys {
+                sqlx::query(&format!(
+                    "alter table {} drop constraint {}",
+                    partition, foreign_key
+                ))
+                .execute(&mut tx)
+                .await?;
+            }
+            detached.push(partition);
+
+            let raw = partition_name(tables.raw, month);
+            if partitions(&mut tx, tables.raw)
+                .await?
+                .iter()
+                .any(|(partition, _)| *partition == raw)
+            {
+                sqlx::query(&format!(
+                    "alter table {} detach partition {}",
+                    tables.raw, raw
+                ))
+                .execute(&mut tx)
+                .await?;
+                detached.push(raw);
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(detached)
+}
+
+/// The attached monthly partitions of `table` with their month, the oldest first.
+async fn partitions(conn: &mut PgConnection, table: &str) -> Result<Vec<(String, NaiveDate)>> {
+    let names: Vec<(String,)> = sqlx::query_as(
+        "select partition.relname::text
+        from pg_inherits
+        join pg_class partition on inhrelid = partition.oid
+        where inhparent = $1::regclass
+        order by partition.relname",
+    )
+    .bind(table)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(names
+        .into_iter()
+        .filter_map(|(name,)| {
+            let month = NaiveDate::parse_from_str(
+                &format!("{}_01", name.strip_prefix(table)?.strip_prefix('_')?),
+                "%Y_%m_%d",
+            )
+            .ok()?;
+
+            Some((name, month))
+        })
+        .collect())
+}
+
+/// The month of the database's clock, the created timestamps are in its time zone.
+async fn current_month(pool: &PgPool) -> Result<NaiveDate> {
+    let month: (NaiveDate,) = sqlx::query_as("select date_trunc('month', localtimestamp)::date")
+        .fetch_one(pool)
+        .await?;
+
+    Ok(month.0)
+}
+
+fn partition_name(table: &str, month: NaiveDate) -> String {
+    format!("{}_{}", table, month.format("%Y_%m"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestDatabase;
+
+    #[tokio::test]
+    async fn partitions_are_detached_once_their_prices_ended() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let month = current_month(&db.pool).await.unwrap();
+        let ended = month - Months::new(14);
+        let open = month - Months::new(13);
+        create_partitions(&db.pool, ended, open).await.unwrap();
+        // creating them again doesn't fail
+        create_partitions(&db.pool, ended, month).await.unwrap();
+
+        let crawl_id = db.crawl_id().await;
+        let (category_id,): (sqlx::types::Uuid,) = sqlx::query_as(
+            "insert into bc_billa_category (bc_text) values ('Bread') returning bc_id",
+        )
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        for (article_id, created, valid_to) in [("1", ended, Some(open)), ("2", open, None)] {
+            let created = created.and_hms_opt(12, 0, 0).unwrap();
+            sqlx::query(
+                "with product as (
+                    insert into bpo_billa_product (bpo_online_shop_url, bpo_billa_id, bpo_name, bpo_bc_category)
+                    values ( 'url', $1, 'name', $2 ) returning bpo_id
+                ), raw as (
+                    insert into br_billa_raw (br_url, br_bcw_crawl, br_created)
+                    values ( 'url', $3, $4 ) returning br_id
+                )
+                insert into bp_billa_price (bp_bpo_product, bp_br_raw, bp_created, bp_valid_to, bp_last_bcw_crawl)
+                select bpo_id, br_id, $4, $5, $3 from product, raw",
+            )
+            .bind(article_id)
+            .bind(category_id)
+            .bind(crawl_id)
+            .bind(created)
+            .bind(valid_to.map(|valid_to| valid_to.and_hms_opt(0, 0, 0).unwrap()))
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        }
+
+        // the empty partitions of spar are detached as well
+        let detached = detach_partitions(&db.pool, 12).await.unwrap();
+        assert_eq!(
+            detached
+                .iter()
+                .filter(|partition| partition.starts_with('b'))
+                .collect::<Vec<_>>(),
+            [
+                &partition_name("bp_billa_price", ended),
+                &partition_name("br_billa_raw", ended)
+            ]
+        );
+        assert!(detached.contains(&partition_name("spr_spar_price", open)));
+
+        let prices: (i64,) = sqlx::query_as("select count(*) from bp_billa_price")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(prices.0, 1);
+        let archived: (i64,) = sqlx::query_as(&format!(
+            "select count(*) from {}",
+            partition_name("bp_billa_price", ended)
+        ))
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(archived.0, 1);
+
+        assert!(detach_partitions(&db.pool, 12).await.unwrap().is_empty());
+
+        db.close().await;
+    }
+}
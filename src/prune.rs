@@ -0,0 +1,392 @@
+//! Retention of the raw documents in postgres.
+//!
+//! The bodies of the documents of crawl sessions older than the retention are archived into a
+//! zstd compressed JSON lines file per store and crawl session, then removed from postgres. The
+//! documents themselves stay, so the prices keep referring to them, and remember their archive in
+//! `br_archive` / `sr_archive`. [`crate::blob::document`] reads archived bodies from there.
+//!
+//! Blobs which no document refers to anymore are deleted together with their offloaded bodies.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tokio::io::AsyncWriteExt;
+
+use crate::blob::{self, StoredBody};
+use crate::model::Store;
+use crate::output::{Format, Table};
+
+const COMPRESSION_LEVEL: i32 = 19;
+
+/// The documents of a store in a crawl session whose bodies are past the retention.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PrunedCrawl {
+    pub store: Store,
+    pub crawl_id: Uuid,
+    pub created: Option<NaiveDateTime>,
+    pub documents: i64,
+    /// Size of the uncompressed bodies.
+    pub bytes: i64,
+    /// `None` for a dry run.
+    pub archive: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PruneReport {
+    pub crawls: Vec<PrunedCrawl>,
+    /// Blobs deleted as no document refers to them anymore.
+    pub blobs: u64,
+}
+
+/// A line of an archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedDocument {
+    pub document_id: Uuid,
+    pub url: String,
+    pub created: Option<NaiveDateTime>,
+    pub body: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DocumentRow {
+    id: Uuid,
+    url: String,
+    created: Option<NaiveDateTime>,
+    #[sqlx(flatten)]
+    body: StoredBody,
+}
+
+struct PruneQueries {
+    /// Crawl sessions older than `$1` with documents which have a body, the oldest first.
+    crawls: &'static str,
+    /// The documents with a body of the crawl session `$1`.
+    documents: &'static str,
+    /// Removes the bodies of the documents `$1`, they were archived to `$2`.
+    archive: &'static str,
+}
+
+const BILLA_PRUNE_QUERIES: PruneQueries = PruneQueries {
+    crawls: "select bcw_id, bcw_created, count(*), coalesce(sum(coalesce(octet_length(br_raw), rb_size)), 0)::bigint
+    from br_billa_raw
+    join bcw_billa_crawl on br_bcw_crawl = bcw_id
+    left join rb_raw_blob on br_rb_blob = rb_hash
+    where (br_raw is not null or br_rb_blob is not null) and bcw_created < $1
+    group by bcw_id, bcw_created
+    order by bcw_created",
+    documents: "select br_id as id, br_url as url, br_created as created,
+        br_raw as raw, rb_body as blob, rb_location as location
+    from br_billa_raw
+    left join rb_raw_blob on br_rb_blob = rb_hash
+    where br_bcw_crawl = $1 and (br_raw is not null or br_rb_blob is not null)
+    order by br_created
+    for update of br_billa_raw",
+    archive: "update br_billa_raw set br_raw = null, br_rb_blob = null, br_archive = $2 where br_id = any($1)",
+};
+
+const SPAR_PRUNE_QUERIES: PruneQueries = PruneQueries {
+    crawls: "select bcw_id, bcw_created, count(*), coalesce(sum(coalesce(octet_length(sr_raw), rb_size)), 0)::bigint
+    from sr_spar_raw
+    join bcw_billa_crawl on sr_cs_crawl_session = bcw_id
+    left join rb_raw_blob on sr_rb_blob = rb_hash
+    where (sr_raw is not null or sr_rb_blob is not null) and bcw_created < $1
+    group by bcw_id, bcw_created
+    order by bcw_created",
+    documents: "select sr_id as id, sr_url as url, sr_created as created,
+        sr_raw as raw, rb_body as blob, rb_location as location
+    from sr_spar_raw
+    left join rb_raw_blob on sr_rb_blob = rb_hash
+    where sr_cs_crawl_session = $1 and (sr_raw is not null or sr_rb_blob is not null)
+    order by sr_created
+    for update of sr_spar_raw",
+    archive: "update sr_spar_raw set sr_raw = null, sr_rb_blob = null, sr_archive = $2 where sr_id = any($1)",
+};
+
+/// Archives the bodies of the documents of crawl sessions older than `keep_days` into `dir` and
+/// removes them from postgres, unless `dry_run` is set.
+pub async fn prune(
+    pool: &PgPool,
+    keep_days: u32,
+    dir: &Path,
+    dry_run: bool,
+) -> Result<PruneReport> {
+    // the created timestamps are in the time zone of the database
+    let (cutoff,): (NaiveDateTime,) =
+        sqlx::query_as("select localtimestamp - make_interval(days => $1)")
+            .bind(i32::try_from(keep_days)?)
+            .fetch_one(pool)
+            .await?;
+
+    let mut report = PruneReport::default();
+    for (store, queries) in [
+        (Store::Billa, &BILLA_PRUNE_QUERIES),
+        (Store::Spar, &SPAR_PRUNE_QUERIES),
+    ] {
+        let crawls: Vec<(Uuid, Option<NaiveDateTime>, i64, i64)> = sqlx::query_as(queries.crawls)
+            .bind(cutoff)
+            .fetch_all(pool)
+            .await?;
+
+        for (crawl_id, created, documents, bytes) in crawls {
+            let archive = if dry_run {
+                None
+            } else {
+                Some(archive_crawl(pool, queries, store, crawl_id, dir).await?)
+            };
+
+            report.crawls.push(PrunedCrawl {
+                store,
+                crawl_id,
+                created,
+                documents,
+                bytes,
+                archive,
+            });
+        }
+    }
+
+    if !dry_run {
+        report.blobs = delete_unreferenced_blobs(pool, cutoff).await?;
+    }
+
+    Ok(report)
+}
+
+/// Writes the bodies of `crawl_id` into a new archive and removes them, returns the path of the
+/// archive.
+async fn archive_crawl(
+    pool: &PgPool,
+    queries: &PruneQueries,
+    store: Store,
+    crawl_id: Uuid,
+    dir: &Path,
+) -> Result<String> {
+    let mut tx = pool.begin().await?;
+
+    let rows: Vec<DocumentRow> = sqlx::query_as(queries.documents)
+        .bind(crawl_id)
+        .fetch_all(&mut tx)
+        .await?;
+
+    let mut lines = String::new();
+    let mut ids = Vec::with_capacity(rows.len());
+    for row in rows {
+        let body = row
+            .body
+            .text()
+            .await?
+            .ok_or_else(|| anyhow!("no body of document {}", row.id))?;
+
+        let document = ArchivedDocument {
+            document_id: row.id,
+            url: row.url,
+            created: row.created,
+            body,
+        };
+        lines.push_str(&serde_json::to_string(&document)?);
+        lines.push('\n');
+        ids.push(row.id);
+    }
+
+    // a crawl session resumed after it was pruned gets another archive
+    let path = std::path::absolute(dir.join(store.to_string()).join(format!(
+        "{}-{}.jsonl.zst",
+        crawl_id,
+        Utc::now().timestamp()
+    )))?;
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+
+    let compressed = zstd::encode_all(lines.as_bytes(), COMPRESSION_LEVEL)?;
+    let mut file = tokio::fs::File::create(&path)
+        .await
+        .with_context(|| format!("creating archive {}", path.display()))?;
+    file.write_all(&compressed).await?;
+    // the bodies are only removed once the archive is on disk
+    file.sync_all().await?;
+
+    let archive = path.display().to_string();
+    sqlx::query(queries.archive)
+        .bind(&ids)
+        .bind(&archive)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(archive)
+}
+
+/// Deletes the blobs created before `cutoff` which no document refers to, returns how many.
+///
+/// Blobs locked by a running [`blob::save`] are skipped.
+async fn delete_unreferenced_blobs(pool: &PgPool, cutoff: NaiveDateTime) -> Result<u64> {
+    let deleted: Vec<(Option<String>,)> = sqlx::query_as(
+        "delete from rb_raw_blob
+        where rb_hash in (
+            select rb_hash from rb_raw_blob
+            where rb_created < $1
+                and not exists (select 1 from br_billa_raw where br_rb_blob = rb_hash)
+                and not exists (select 1 from sr_spar_raw where sr_rb_blob = rb_hash)
+            for update skip locked
+        )
+        returning rb_location",
+    )
+    .bind(cutoff)
+    .fetch_all(pool)
+    .await?;
+
+    for location in deleted.iter().filter_map(|(location,)| location.as_deref()) {
+        blob::remove(location).await?;
+    }
+
+    Ok(deleted.len() as u64)
+}
+
+/// The body of `document_id` from the archive at `path`.
+pub async fn archived_document(path: &str, document_id: Uuid) -> Result<String> {
+    let compressed = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("reading archive {}", path))?;
+    let lines = String::from_utf8(zstd::decode_all(compressed.as_slice())?)?;
+
+    for line in lines.lines() {
+        let document: ArchivedDocument = serde_json::from_str(line)?;
+        if document.document_id == document_id {
+            return Ok(document.body);
+        }
+    }
+
+    Err(anyhow!(
+        "document {} isn't in archive {}",
+        document_id,
+        path
+    ))
+}
+
+pub fn render(report: &PruneReport, format: Format) -> Result<String> {
+    let mut table = Table::new(["store", "crawl", "created", "documents", "bytes", "archive"]);
+    for crawl in &report.crawls {
+        table.push([
+            crawl.store.to_string(),
+            crawl.crawl_id.to_string(),
+            crawl
+                .created
+                .map(|created| created.format("%F %T").to_string())
+                .unwrap_or_default(),
+            crawl.documents.to_string(),
+            crawl.bytes.to_string(),
+            crawl.archive.clone().unwrap_or_default(),
+        ]);
+    }
+
+    let output = match format {
+        Format::Table => format!("{}deleted {} blobs\n", table.to_text(), report.blobs),
+        Format::Json => serde_json::to_string_pretty(report)?,
+        Format::Markdown => format!("{}\ndeleted {} blobs\n", table.to_markdown(), report.blobs),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use crate::stores::billa::{BillaCrawl, Category};
+    use crate::stores::ExecuteCrawler;
+    use crate::test_support::{fixture, TestDatabase};
+
+    #[tokio::test]
+    async fn old_documents_are_archived_and_keep_their_prices() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let bread = fixture("billa", "B2-2_1.json");
+        let vegetables = fixture("billa", "B2-1_1.json");
+
+        let old = db.crawl_id().await;
+        sqlx::query(
+            "update bcw_billa_crawl set bcw_created = now() - interval '100 days' where bcw_id = $1",
+        )
+        .bind(old)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        let category_id = db
+            .storage
+            .get_or_add_category(Store::Billa, "Bread")
+            .await
+            .unwrap();
+        let (products, _) = BillaCrawl::parse_page(&bread).unwrap();
+        let products = products
+            .iter()
+            .map(|product| BillaCrawl::to_model(product, Category::Bread))
+            .collect::<Vec<_>>();
+        let archived = db
+            .storage
+            .save_page(Store::Billa, old, "bread", &bread, category_id, &products)
+            .await
+            .unwrap();
+        db.storage
+            .save_document(Store::Billa, old, "vegetables", &vegetables)
+            .await
+            .unwrap();
+        sqlx::query("update rb_raw_blob set rb_created = now() - interval '100 days'")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        // the body of bread is still used by a recent crawl session
+        let recent = db.crawl_id().await;
+        db.storage
+            .save_document(Store::Billa, recent, "bread", &bread)
+            .await
+            .unwrap();
+
+        let dry_run = prune(&db.pool, 30, dir.path(), true).await.unwrap();
+        assert_eq!(dry_run.crawls.len(), 1);
+        assert_eq!(
+            (dry_run.crawls[0].crawl_id, dry_run.crawls[0].documents),
+            (old, 2)
+        );
+        assert_eq!(
+            dry_run.crawls[0].bytes,
+            (bread.len() + vegetables.len()) as i64
+        );
+        assert_eq!(dry_run.crawls[0].archive, None);
+
+        let report = prune(&db.pool, 30, dir.path(), false).await.unwrap();
+        assert_eq!(report.crawls.len(), 1);
+        assert_eq!(report.blobs, 1);
+        assert!(Path::new(report.crawls[0].archive.as_ref().unwrap()).exists());
+
+        let blobs: (i64,) = sqlx::query_as("select count(*) from rb_raw_blob")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(blobs.0, 1);
+        let prices: (i64,) =
+            sqlx::query_as("select count(*) from bp_billa_price where bp_br_raw = $1")
+                .bind(archived)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(prices.0, 2);
+
+        let body = blob::document(&db.pool, Store::Billa, archived)
+            .await
+            .unwrap();
+        assert_eq!(body.as_deref(), Some(bread.as_str()));
+
+        assert!(prune(&db.pool, 30, dir.path(), false)
+            .await
+            .unwrap()
+            .crawls
+            .is_empty());
+
+        db.close().await;
+    }
+}
@@ -0,0 +1,888 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{Duration, NaiveDateTime};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use crate::model::{parse_unit_price, CrawlStatus, Details, Product, Store};
+use crate::output::{Format, Table};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct Crawl {
+    #[sqlx(rename = "bcw_id")]
+    pub id: Uuid,
+    #[sqlx(rename = "bcw_created")]
+    pub created: NaiveDateTime,
+}
+
+/// All crawl sessions, the newest first.
+pub async fn crawls(pool: &PgPool) -> Result<Vec<Crawl>> {
+    let crawls =
+        sqlx::query_as("select bcw_id, bcw_created from bcw_billa_crawl order by bcw_created desc")
+            .fetch_all(pool)
+            .await?;
+
+    Ok(crawls)
+}
+
+/// The complete crawl sessions which crawled `store`, the newest first.
+pub async fn complete_crawls(pool: &PgPool, store: Store) -> Result<Vec<Crawl>> {
+    let documents = match store {
+        Store::Billa => "select 1 from br_billa_raw where br_bcw_crawl = bcw_id",
+        Store::Spar => "select 1 from sr_spar_raw where sr_cs_crawl_session = bcw_id",
+    };
+
+    let crawls = sqlx::query_as(&format!(
+        "select bcw_id, bcw_created from bcw_billa_crawl
+        where bcw_status = $1 and exists ({documents})
+        order by bcw_created desc"
+    ))
+    .bind(CrawlStatus::Complete.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    Ok(crawls)
+}
+
+pub async fn latest_crawl(pool: &PgPool) -> Result<Option<Crawl>> {
+    let crawl = sqlx::query_as(
+        "select bcw_id, bcw_created from bcw_billa_crawl order by bcw_created desc limit 1",
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(crawl)
+}
+
+/// sqlx decodes `numeric` with a scale in multiples of four digits, e.g. 2.4900, the prices are
+/// normalized before they are used.
+#[derive(Debug, sqlx::FromRow)]
+struct ProductRow {
+    article_id: String,
+    name: String,
+    description: Option<String>,
+    brand: Option<String>,
+    category: String,
+    url: String,
+    grammage: Option<String>,
+    price: Decimal,
+    unit: Option<String>,
+    badge: Option<String>,
+    price_factor: Option<Decimal>,
+    price_unit: Option<String>,
+    discounted: bool,
+}
+
+/// Products of `store` with the prices stored in the crawl session `crawl_id`.
+pub async fn products(pool: &PgPool, store: Store, crawl_id: Uuid) -> Result<Vec<Product>> {
+    let query = match store {
+        Store::Billa => {
+            "select bpo_billa_id as article_id, bpo_name as name, bpo_description as description, bpo_brand as brand, bc_text as category, bpo_online_shop_url as url, bpo_grammage as grammage, bp_normal as price, bpo_unit as unit, bpo_badge as badge, bpo_price_factor as price_factor, bp_unit as price_unit, bp_discounted as discounted
+            from bp_billa_price_series
+            join bpo_billa_product on bp_bpo_product = bpo_id
+            join bc_billa_category on bpo_bc_category = bc_id
+            where bcw_id = $1
+            order by bpo_billa_id"
+        }
+        Store::Spar => {
+            "select sp_spar_id as article_id, sp_name as name, sp_description as description, sp_brand as brand, sc_text as category, sp_online_shop_url as url, spr_sales_unit as grammage, spr_price as price, null as unit, null as badge, null::numeric as price_factor, spr_price_unit as price_unit, false as discounted
+            from spr_spar_price_series
+            join sp_spar_product on spr_sp_product = sp_id
+            join sc_spar_category on sp_sc_category = sc_id
+            where bcw_id = $1
+            order by sp_spar_id"
+        }
+    };
+
+    let rows: Vec<ProductRow> = sqlx::query_as(query).bind(crawl_id).fetch_all(pool).await?;
+
+    let products = rows
+        .into_iter()
+        .map(|row| {
+            let price = row.price.normalize();
+            let price_unit = row.price_unit.unwrap_or_default();
+
+            let (unit_price, unit, details) = match store {
+                Store::Billa => {
+                    let price_factor = row.price_factor.unwrap_or_default().normalize();
+
+                    (
+                        Some(price * price_factor),
+                        row.unit.unwrap_or_default(),
+                        Details::Billa {
+                            badge: row.badge.unwrap_or_default(),
+                            price_factor,
+                            price_unit,
+                            discounted: row.discounted,
+                        },
+                    )
+                }
+                Store::Spar => {
+                    let (unit_price, unit) = match parse_unit_price(&price_unit) {
+                        Some((unit_price, unit)) => (Some(unit_price), unit),
+                        None => (None, String::new()),
+                    };
+
+                    (
+                        unit_price,
+                        unit,
+                        Details::Spar {
+                            price_per_unit: price_unit,
+                        },
+                    )
+                }
+            };
+
+            Product {
+                store,
+                article_id: row.article_id,
+                name: row.name,
+                description: row.description.unwrap_or_default(),
+                brand: row.brand.unwrap_or_default(),
+                category: row.category,
+                url: row.url,
+                grammage: row.grammage.unwrap_or_default(),
+                price,
+                unit_price,
+                unit,
+                details,
+            }
+        })
+        .collect();
+
+    Ok(products)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct Category {
+    pub id: Uuid,
+    pub name: String,
+    /// Number of products in the category.
+    pub products: i64,
+}
+
+pub async fn categories(pool: &PgPool, store: Store) -> Result<Vec<Category>> {
+    let query = match store {
+        Store::Billa => {
+            "select bc_id as id, bc_text as name, count(bpo_id) as products
+            from bc_billa_category
+            left join bpo_billa_product on bpo_bc_category = bc_id
+            group by bc_id
+            order by bc_text"
+        }
+        Store::Spar => {
+            "select sc_id as id, sc_text as name, count(sp_id) as products
+            from sc_spar_category
+            left join sp_spar_product on sp_sc_category = sc_id
+            group by sc_id
+            order by sc_text"
+        }
+    };
+
+    let categories = sqlx::query_as(query).fetch_all(pool).await?;
+
+    Ok(categories)
+}
+
+/// Products of both stores with their latest price.
+const PRODUCT_SUMMARIES: &str = "
+    select 'billa' as store, bpo_billa_id as article_id, bpo_name as name, coalesce(bpo_brand, '') as brand, bc_text as category, price.bp_normal as price, bpo_delisted as delisted, bpo_search as search
+    from bpo_billa_product
+    join bc_billa_category on bpo_bc_category = bc_id
+    left join lateral (
+        select bp_normal from bp_billa_price where bp_bpo_product = bpo_id order by bp_valid_from desc limit 1
+    ) price on true
+    union all
+    select 'spar' as store, sp_spar_id as article_id, sp_name as name, coalesce(sp_brand, '') as brand, sc_text as category, price.spr_price as price, sp_delisted as delisted, sp_search as search
+    from sp_spar_product
+    join sc_spar_category on sp_sc_category = sc_id
+    left join lateral (
+        select spr_price from spr_spar_price where spr_sp_product = sp_id order by spr_valid_from desc limit 1
+    ) price on true";
+
+/// Products matching all the filters of [`ProductFilter`], `$6` is the text as `tsquery`.
+const FILTERED_PRODUCTS: &str = "
+    where ($1::text is null or store = $1)
+        and ($2::text is null or category = $2)
+        and ($3::text is null or brand ilike $3)
+        and ($4::numeric is null or price >= $4)
+        and ($5::numeric is null or price <= $5)
+        and ($6::text is null or search @@ to_tsquery('german', $6))";
+
+/// Filters of [`search_products`], the products have to match all given ones.
+#[derive(Debug, Clone, Default)]
+pub struct ProductFilter {
+    pub store: Option<Store>,
+    pub category: Option<String>,
+    /// Case insensitive.
+    pub brand: Option<String>,
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    /// Words searched for in the name, brand and description with german stemming, every word
+    /// has to match the start of a word of the product.
+    pub text: Option<String>,
+}
+
+impl ProductFilter {
+    fn ts_query(&self) -> Option<String> {
+        ts_query(self.text.as_deref()?)
+    }
+}
+
+/// Words of `text` as prefixes in a `tsquery`, e.g. `bio:* & karotten:*`.
+pub(crate) fn ts_query(text: &str) -> Option<String> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word))
+        .collect::<Vec<_>>();
+
+    (!words.is_empty()).then(|| words.join(" & "))
+}
+
+/// A product with its latest price.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProductSummary {
+    pub store: Store,
+    pub article_id: String,
+    pub name: String,
+    pub brand: String,
+    pub category: String,
+    pub price: Option<Decimal>,
+    /// When the product wasn't seen anymore, `None` if it's listed.
+    pub delisted: Option<NaiveDateTime>,
+    /// How well the product matches the text of the search, higher is better.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<f32>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ProductSummaryRow {
+    store: String,
+    article_id: String,
+    name: String,
+    brand: String,
+    category: String,
+    price: Option<Decimal>,
+    delisted: Option<NaiveDateTime>,
+    rank: Option<f32>,
+}
+
+/// Products matching `filter`, the best matches of the text first and otherwise ordered by store
+/// and article id. Skips `offset` and returns at most `limit` of them together with the number of
+/// all matching products.
+pub async fn search_products(
+    pool: &PgPool,
+    filter: &ProductFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<ProductSummary>, i64)> {
+    let ts_query = filter.ts_query();
+
+    let total: (i64,) = sqlx::query_as(&format!(
+        "select count(*) from ({PRODUCT_SUMMARIES}) products {FILTERED_PRODUCTS}"
+    ))
+    .bind(filter.store.map(|store| store.to_string()))
+    .bind(&filter.category)
+    .bind(&filter.brand)
+    .bind(filter.min_price)
+    .bind(filter.max_price)
+    .bind(&ts_query)
+    .fetch_one(pool)
+    .await?;
+
+    let rows: Vec<ProductSummaryRow> = sqlx::query_as(&format!(
+        "select *, ts_rank(search, to_tsquery('german', $6)) as rank
+        from ({PRODUCT_SUMMARIES}) products
+        {FILTERED_PRODUCTS}
+        order by rank desc nulls last, store, article_id
+        limit $7 offset $8"
+    ))
+    .bind(filter.store.map(|store| store.to_string()))
+    .bind(&filter.category)
+    .bind(&filter.brand)
+    .bind(filter.min_price)
+    .bind(filter.max_price)
+    .bind(&ts_query)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    let products = rows
+        .into_iter()
+        .map(|row| {
+            Ok(ProductSummary {
+                store: row.store.parse()?,
+                article_id: row.article_id,
+                name: row.name,
+                brand: row.brand,
+                category: row.category,
+                price: row.price.map(|price| price.normalize()),
+                delisted: row.delisted,
+                rank: row.rank,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((products, total.0))
+}
+
+/// Renders the products of a search, JSON is a list of the products.
+pub fn render_products(products: &[ProductSummary], format: Format) -> Result<String> {
+    if format == Format::Json {
+        return Ok(serde_json::to_string_pretty(products)?);
+    }
+
+    let mut table = Table::new(["store", "article", "name", "brand", "category", "price"]);
+    for product in products {
+        table.push([
+            product.store.to_string(),
+            product.article_id.clone(),
+            product.name.clone(),
+            product.brand.clone(),
+            product.category.clone(),
+            product
+                .price
+                .map(|price| price.to_string())
+                .unwrap_or_default(),
+        ]);
+    }
+
+    Ok(match format {
+        Format::Markdown => table.to_markdown(),
+        _ => table.to_text(),
+    })
+}
+
+/// Length of a window of the price history, written as a number with the unit `h`, `d` or `w`,
+/// e.g. `30d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window(pub Duration);
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let unit_start = value
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| anyhow!("window {value:?} has no unit, use h, d or w"))?;
+        let (amount, unit) = value.split_at(unit_start);
+        let amount = amount.parse()?;
+
+        let duration = match unit {
+            "h" => Duration::try_hours(amount),
+            "d" => Duration::try_days(amount),
+            "w" => Duration::try_weeks(amount),
+            _ => bail!("unknown unit {unit:?} of window {value:?}, use h, d or w"),
+        };
+
+        duration
+            .map(Window)
+            .ok_or_else(|| anyhow!("window {value:?} is too long"))
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hours = self.0.num_hours();
+
+        if hours % (24 * 7) == 0 {
+            write!(f, "{}w", hours / (24 * 7))
+        } else if hours % 24 == 0 {
+            write!(f, "{}d", hours / 24)
+        } else {
+            write!(f, "{}h", hours)
+        }
+    }
+}
+
+impl Serialize for Window {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Price of a product in a crawl session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PricePoint {
+    pub crawl_id: Uuid,
+    pub created: NaiveDateTime,
+    pub price: Decimal,
+    pub unit_price: Option<Decimal>,
+}
+
+/// Prices of a product in every crawl session it was seen in, the oldest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct History {
+    pub store: Store,
+    pub article_id: String,
+    pub name: String,
+    pub prices: Vec<PricePoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WindowStats {
+    /// The whole history if `None`.
+    pub window: Option<Window>,
+    pub min: Decimal,
+    pub max: Decimal,
+    /// Change from the price at the start of the window to the current price in percent, `None`
+    /// if the history doesn't reach back far enough.
+    pub change: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryReport<'a> {
+    #[serde(flatten)]
+    pub history: &'a History,
+    pub current: Option<&'a PricePoint>,
+    pub stats: Vec<WindowStats>,
+}
+
+impl History {
+    /// The price of the latest crawl the product was seen in.
+    pub fn current(&self) -> Option<&PricePoint> {
+        self.prices.last()
+    }
+
+    /// Statistics over the prices of the last `window` before the current price.
+    pub fn stats(&self, window: Option<Window>) -> Option<WindowStats> {
+        let current = self.current()?;
+
+        let (start, prices) = match window {
+            // a window reaching back before any representable date covers the whole history
+            Some(Window(duration)) => match current.created.checked_sub_signed(duration) {
+                Some(since) => {
+                    // the price at the start of the window was set by the last crawl before it
+                    let start = self
+                        .prices
+                        .iter()
+                        .rev()
+                        .find(|point| point.created <= since);
+                    let prices = self
+                        .prices
+                        .iter()
+                        .filter(|point| point.created >= since)
+                        .chain(start)
+                        .collect::<Vec<_>>();
+
+                    (start, prices)
+                }
+                None => (None, self.prices.iter().collect()),
+            },
+            None => (self.prices.first(), self.prices.iter().collect()),
+        };
+
+        let change = start.filter(|start| !start.price.is_zero()).map(|start| {
+            ((current.price - start.price) / start.price * Decimal::ONE_HUNDRED).round_dp(2)
+        });
+
+        Some(WindowStats {
+            window,
+            min: prices.iter().map(|point| point.price).min()?,
+            max: prices.iter().map(|point| point.price).max()?,
+            change,
+        })
+    }
+
+    /// The history with the statistics of the whole history and of every window.
+    pub fn report(&self, windows: &[Window]) -> HistoryReport<'_> {
+        let stats = std::iter::once(None)
+            .chain(windows.iter().copied().map(Some))
+            .filter_map(|window| self.stats(window))
+            .collect();
+
+        HistoryReport {
+            history: self,
+            current: self.current(),
+            stats,
+        }
+    }
+
+    /// Renders the prices and the statistics of the whole history and of every window.
+    pub fn render(&self, windows: &[Window], format: Format) -> Result<String> {
+        let report = self.report(windows);
+        if format == Format::Json {
+            return Ok(serde_json::to_string_pretty(&report)?);
+        }
+        let stats = report.stats;
+
+        let mut prices = Table::new(["crawl", "created", "price", "unit price"]);
+        for point in &self.prices {
+            prices.push([
+                point.crawl_id.to_string(),
+                point.created.format("%Y-%m-%d %H:%M").to_string(),
+                point.price.to_string(),
+                point
+                    .unit_price
+                    .map(|price| price.to_string())
+                    .unwrap_or_default(),
+            ]);
+        }
+
+        let mut summary = Table::new(["window", "min", "max", "change %"]);
+        for stats in &stats {
+            summary.push([
+                stats
+                    .window
+                    .map(|window| window.to_string())
+                    .unwrap_or_else(|| "all".to_string()),
+                stats.min.to_string(),
+                stats.max.to_string(),
+                stats
+                    .change
+                    .map(|change| change.to_string())
+                    .unwrap_or_default(),
+            ]);
+        }
+
+        let current = self
+            .current()
+            .map(|point| point.price.to_string())
+            .unwrap_or_default();
+
+        let output = match format {
+            Format::Markdown => format!(
+                "## {} {} {}\n\ncurrent price: {}\n\n{}\n{}",
+                self.store,
+                self.article_id,
+                self.name,
+                current,
+                summary.to_markdown(),
+                prices.to_markdown()
+            ),
+            _ => format!(
+                "{} {} {}\ncurrent price: {}\n\n{}\n{}",
+                self.store,
+                self.article_id,
+                self.name,
+                current,
+                summary.to_text(),
+                prices.to_text()
+            ),
+        };
+
+        Ok(output)
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PricePointRow {
+    bcw_id: Uuid,
+    bcw_created: NaiveDateTime,
+    name: String,
+    price: Decimal,
+    price_factor: Option<Decimal>,
+    price_unit: Option<String>,
+}
+
+/// Price history of the product `article_id` of `store`, `None` if there is no such product.
+pub async fn history(pool: &PgPool, store: Store, article_id: &str) -> Result<Option<History>> {
+    let query = match store {
+        Store::Billa => {
+            "select bcw_id, bcw_created, bpo_name as name, bp_normal as price, bpo_price_factor as price_factor, null as price_unit
+            from bp_billa_price_series
+            join bpo_billa_product on bp_bpo_product = bpo_id
+            where bpo_billa_id = $1
+            order by bcw_created"
+        }
+        Store::Spar => {
+            "select bcw_id, bcw_created, sp_name as name, spr_price as price, null::numeric as price_factor, spr_price_unit as price_unit
+            from spr_spar_price_series
+            join sp_spar_product on spr_sp_product = sp_id
+            where sp_spar_id = $1
+            order by bcw_created"
+        }
+    };
+
+    let rows: Vec<PricePointRow> = sqlx::query_as(query)
+        .bind(article_id)
+        .fetch_all(pool)
+        .await?;
+
+    let Some(name) = rows.first().map(|row| row.name.clone()) else {
+        return Ok(None);
+    };
+
+    let prices = rows
+        .into_iter()
+        .map(|row| {
+            let price = row.price.normalize();
+            let unit_price = match store {
+                Store::Billa => row.price_factor.map(|factor| price * factor.normalize()),
+                Store::Spar => row
+                    .price_unit
+                    .as_deref()
+                    .and_then(parse_unit_price)
+                    .map(|(unit_price, _)| unit_price),
+            };
+
+            PricePoint {
+                crawl_id: row.bcw_id,
+                created: row.bcw_created,
+                price,
+                unit_price,
+            }
+        })
+        .collect();
+
+    Ok(Some(History {
+        store,
+        article_id: article_id.to_string(),
+        name,
+        prices,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::storage::Storage;
+    use crate::stores::spar::SparCrawl;
+    use crate::stores::ExecuteCrawler;
+    use crate::test_support::{crawl_billa, mock_billa, mock_store, TestDatabase};
+
+    #[tokio::test]
+    async fn products_of_crawl_are_normalized() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_store(
+            "spar",
+            "/fact-finder/rest/v4/search/products_lmos_at",
+            "filter",
+            "category-path:",
+        )
+        .await;
+
+        let crawl_id = db.storage.create_crawl().await.unwrap();
+        SparCrawl::execute(
+            &db.storage,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &server.uri(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(latest_crawl(&db.pool).await.unwrap().unwrap().id, crawl_id);
+
+        let spar = products(&db.pool, Store::Spar, crawl_id).await.unwrap();
+        assert_eq!(spar.len(), 3);
+        assert_eq!(spar[1].article_id, "2020003040115");
+        assert_eq!(spar[1].category, "Vegetables");
+        assert_eq!(spar[1].unit_price, Some(dec!(5.98)));
+        assert_eq!(spar[1].unit, "kg");
+
+        assert!(products(&db.pool, Store::Billa, crawl_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.close().await;
+    }
+
+    #[test]
+    fn window_parses_units() {
+        assert_eq!(
+            "12h".parse::<Window>().unwrap(),
+            Window(Duration::hours(12))
+        );
+        assert_eq!("30d".parse::<Window>().unwrap(), Window(Duration::days(30)));
+        assert_eq!("4w".parse::<Window>().unwrap().to_string(), "4w");
+        assert!("30".parse::<Window>().is_err());
+        assert!("1y".parse::<Window>().is_err());
+        assert!("9999999999999999h".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn stats_over_windows() {
+        let start = NaiveDate::from_ymd_opt(2023, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let history = History {
+            store: Store::Billa,
+            article_id: "00-384201".to_string(),
+            name: "Ölz Toastbrot".to_string(),
+            prices: [
+                (0, dec!(2.00)),
+                (10, dec!(3.00)),
+                (20, dec!(2.50)),
+                (30, dec!(2.40)),
+            ]
+            .into_iter()
+            .map(|(day, price)| PricePoint {
+                crawl_id: Uuid::nil(),
+                created: start + Duration::days(day),
+                price,
+                unit_price: None,
+            })
+            .collect(),
+        };
+
+        assert_eq!(history.current().unwrap().price, dec!(2.40));
+        assert_eq!(
+            history.stats(None).unwrap(),
+            WindowStats {
+                window: None,
+                min: dec!(2.00),
+                max: dec!(3.00),
+                change: Some(dec!(20.00)),
+            }
+        );
+        assert_eq!(
+            history.stats(Some("7d".parse().unwrap())).unwrap(),
+            WindowStats {
+                window: Some(Window(Duration::days(7))),
+                min: dec!(2.40),
+                max: dec!(2.50),
+                change: Some(dec!(-4.00)),
+            }
+        );
+        assert_eq!(
+            history.stats(Some("365d".parse().unwrap())).unwrap().change,
+            None
+        );
+        // reaches back before the dates chrono can represent
+        let stats = history.stats(Some("20000000w".parse().unwrap())).unwrap();
+        assert_eq!((stats.min, stats.change), (dec!(2.00), None));
+    }
+
+    #[tokio::test]
+    async fn history_of_product() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+
+        for _ in 0..2 {
+            crawl_billa(&db.storage, &server).await;
+        }
+
+        let history = history(&db.pool, Store::Billa, "00-426071")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(history.name, "Rispenparadeiser");
+        assert_eq!(history.prices.len(), 2);
+        assert_eq!(history.prices[1].price, dec!(2.49));
+        assert_eq!(history.prices[1].unit_price, Some(dec!(4.98)));
+        assert_eq!(history.stats(None).unwrap().change, Some(dec!(0)));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&history.render(&[], Format::Json).unwrap()).unwrap();
+        assert_eq!(json["current"]["price"], "2.49");
+
+        assert!(super::history(&db.pool, Store::Spar, "00-426071")
+            .await
+            .unwrap()
+            .is_none());
+
+        db.close().await;
+    }
+
+    #[test]
+    fn text_of_filter_as_ts_query() {
+        let filter = ProductFilter {
+            text: Some("Bio-Karotten, 1kg".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter.ts_query().unwrap(), "Bio:* & Karotten:* & 1kg:*");
+
+        let filter = ProductFilter {
+            text: Some(" & ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter.ts_query(), None);
+    }
+
+    #[tokio::test]
+    async fn search_products_of_both_stores() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let billa = mock_billa().await;
+        let crawl_id = crawl_billa(&db.storage, &billa).await;
+        let spar = mock_store(
+            "spar",
+            "/fact-finder/rest/v4/search/products_lmos_at",
+            "filter",
+            "category-path:",
+        )
+        .await;
+        SparCrawl::execute(
+            &db.storage,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &spar.uri(),
+        )
+        .await
+        .unwrap();
+
+        let search = |filter: ProductFilter| {
+            let pool = db.pool.clone();
+            async move { search_products(&pool, &filter, 10, 0).await.unwrap() }
+        };
+
+        // the stemmed "karotte" matches "Karotten"
+        let (carrots, total) = search(ProductFilter {
+            text: Some("karotte".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(total, 2);
+        let mut prices = carrots
+            .iter()
+            .map(|product| (product.store.to_string(), product.price))
+            .collect::<Vec<_>>();
+        prices.sort();
+        assert_eq!(
+            prices,
+            [
+                ("billa".to_string(), Some(dec!(2.29))),
+                ("spar".to_string(), Some(dec!(1.99)))
+            ]
+        );
+        assert!(carrots.iter().all(|product| product.rank.unwrap() > 0.0));
+
+        let (carrots, _) = search(ProductFilter {
+            text: Some("bio karotte".to_string()),
+            max_price: Some(dec!(2)),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(carrots.len(), 1);
+        assert_eq!(carrots[0].article_id, "2020001890507");
+
+        let (clever, total) = search(ProductFilter {
+            brand: Some("clever".to_string()),
+            store: Some(Store::Billa),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(total, 3);
+        assert!(clever
+            .iter()
+            .all(|product| product.brand == "Clever" && product.rank.is_none()));
+
+        let (_, total) = search(ProductFilter {
+            text: Some("karotte".to_string()),
+            category: Some("Bread".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(total, 0);
+
+        db.close().await;
+    }
+}
@@ -0,0 +1,856 @@
+//! A job queue in postgres which lets several workers, e.g. on different machines, crawl the
+//! pages of a crawl session.
+//!
+//! [`enqueue`] adds a job for the first page of every category, the worker which downloaded a
+//! page adds the job of the next one. Workers claim jobs with `for update skip locked`, so every
+//! job is run by a single worker. Failed jobs are retried with a growing delay up to
+//! [`MAX_ATTEMPTS`] times and a job whose worker stopped is claimed again once its lease ran out.
+//! The worker finishing the last job of a store completes the store like a single crawl does, the
+//! one finishing the last store of a crawl session finishes the session with the summed up jobs
+//! and sends the alerts of the watchlist.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::types::Uuid;
+use sqlx::{PgConnection, PgPool};
+use strum::IntoEnumIterator;
+use tokio::task::JoinSet;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::http::Fetcher;
+use crate::metrics::metrics;
+use crate::model::{CrawlStatus, CrawlSummary, Store};
+use crate::output::{Format, Table};
+use crate::shutdown::Shutdown;
+use crate::storage::postgres::PgStorage;
+use crate::storage::Storage;
+use crate::stores::billa::BillaCrawl;
+use crate::stores::pagination::{PaginationError, PaginationGuard, CATEGORY_TIMEOUT};
+use crate::stores::spar::SparCrawl;
+use crate::stores::{ExecuteCrawler, PageDownload};
+use crate::watch::{self, sink::Sink};
+
+/// Runs of a job before it's given up.
+pub const MAX_ATTEMPTS: i32 = 3;
+
+/// How long a claimed job belongs to its worker, afterwards other workers may claim it again.
+const LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// Delay of the first retry of a job, doubled for every further one.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How often an idle worker looks for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The download of a page of a category.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: Uuid,
+    pub crawl_id: Uuid,
+    pub store: Store,
+    pub category: String,
+    pub page: usize,
+    /// Including the current run.
+    pub attempts: i32,
+    /// Digest of the page before, see [`PaginationGuard::resume`].
+    pub previous_page: Option<u64>,
+    /// Since the first page of the category was claimed.
+    pub category_elapsed: Duration,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct JobRow {
+    qj_id: Uuid,
+    qj_bcw_crawl: Uuid,
+    qj_store: String,
+    qj_category: String,
+    qj_page: i32,
+    qj_attempts: i32,
+    qj_previous_page: Option<i64>,
+    qj_category_elapsed: f64,
+}
+
+impl TryFrom<JobRow> for Job {
+    type Error = anyhow::Error;
+
+    fn try_from(row: JobRow) -> Result<Self> {
+        Ok(Job {
+            id: row.qj_id,
+            crawl_id: row.qj_bcw_crawl,
+            store: row
+                .qj_store
+                .parse()
+                .map_err(|_| anyhow!("unknown store {}", row.qj_store))?,
+            category: row.qj_category,
+            page: usize::try_from(row.qj_page)
+                .ok()
+                .filter(|page| *page >= 1)
+                .ok_or_else(|| anyhow!("invalid page {} of job {}", row.qj_page, row.qj_id))?,
+            attempts: row.qj_attempts,
+            previous_page: row.qj_previous_page.map(|digest| digest as u64),
+            category_elapsed: Duration::from_secs_f64(row.qj_category_elapsed.max(0.0)),
+        })
+    }
+}
+
+/// Starts a crawl session of `stores` whose pages are downloaded by the workers.
+///
+/// Fails if one of the stores is crawled by another process or still in a crawl session of the
+/// queue.
+pub async fn enqueue(storage: &PgStorage, stores: &[Store]) -> Result<Uuid> {
+    let mut locks = Vec::new();
+    for store in Store::iter().filter(|store| stores.contains(store)) {
+        // also refused while the store is in an unfinished crawl session of the queue
+        let Some(lock) = storage.lock_store(store, false).await? else {
+            bail!(
+                "{} is crawled by another process or in an unfinished crawl session of the queue",
+                store
+            );
+        };
+        locks.push(lock);
+    }
+
+    let crawl_id = storage.create_crawl().await?;
+
+    let mut tx = storage.pool.begin().await?;
+
+    for store in Store::iter().filter(|store| stores.contains(store)) {
+        // added up front, so the workers don't race each other adding them
+        let categories = match store {
+            Store::Billa => categories::<BillaCrawl>(storage).await?,
+            Store::Spar => categories::<SparCrawl>(storage).await?,
+        };
+
+        sqlx::query("insert into qc_queue_crawl (qc_bcw_crawl, qc_store) values ($1, $2)")
+            .bind(crawl_id)
+            .bind(store.to_string())
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "insert into qj_queue_job (qj_bcw_crawl, qj_store, qj_category, qj_page)
+            select $1, $2, category, 1 from unnest($3::text[]) as category",
+        )
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .bind(&categories)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    for lock in locks {
+        lock.release().await?;
+    }
+
+    info!(%crawl_id, ?stores, "crawl enqueued");
+
+    Ok(crawl_id)
+}
+
+async fn categories<C: ExecuteCrawler>(storage: &PgStorage) -> Result<Vec<String>> {
+    let categories = C::get_or_add_categories(storage).await?;
+
+    Ok(categories
+        .keys()
+        .map(|category| format!("{:?}", category))
+        .collect())
+}
+
+/// Claims the job which waits the longest, `None` if no job is due.
+pub async fn claim(pool: &PgPool, worker: &str) -> Result<Option<Job>> {
+    let row: Option<JobRow> = sqlx::query_as(
+        "update qj_queue_job
+        set qj_status = 'running', qj_attempts = qj_attempts + 1, qj_worker = $1,
+            qj_locked_until = current_timestamp + make_interval(secs => $2),
+            qj_category_started = case
+                when qj_page = 1 and qj_attempts = 0 then current_timestamp
+                else qj_category_started
+            end
+        where qj_id = (
+            select qj_id from qj_queue_job
+            where (qj_status = 'queued' and qj_run_after <= current_timestamp)
+                or (qj_status = 'running' and qj_locked_until < current_timestamp)
+            order by qj_run_after
+            limit 1
+            for update skip locked
+        )
+        returning qj_id, qj_bcw_crawl, qj_store, qj_category, qj_page, qj_attempts,
+            qj_previous_page,
+            extract(epoch from current_timestamp - qj_category_started)::float8 as qj_category_elapsed",
+    )
+    .bind(worker)
+    .bind(LEASE.as_secs_f64())
+    .fetch_optional(pool)
+    .await?;
+
+    row.map(Job::try_from).transpose()
+}
+
+/// Runs the jobs of the queue.
+#[derive(Debug, Clone)]
+pub struct Worker {
+    storage: PgStorage,
+    fetcher: Fetcher,
+    /// Shown in the jobs it claimed.
+    name: String,
+    base_urls: HashMap<Store, String>,
+    retry_delay: Duration,
+    /// Once requested no further jobs are claimed, the running ones are finished.
+    shutdown: Shutdown,
+    /// Get the alerts of the watchlist once a crawl session is finished.
+    alerts: Vec<Sink>,
+}
+
+impl Worker {
+    pub fn new(storage: PgStorage, fetcher: Fetcher) -> Self {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+
+        Worker {
+            storage,
+            fetcher,
+            name: format!("{}:{}", host, std::process::id()),
+            base_urls: HashMap::from([
+                (Store::Billa, BillaCrawl::BASE_URL.to_string()),
+                (Store::Spar, SparCrawl::BASE_URL.to_string()),
+            ]),
+            retry_delay: RETRY_DELAY,
+            shutdown: Shutdown::new(),
+            alerts: Vec::new(),
+        }
+    }
+
+    /// Sends the requests for `store` to `base_url` instead of the shop.
+    pub fn with_base_url(mut self, store: Store, base_url: &str) -> Self {
+        self.base_urls.insert(store, base_url.to_string());
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_alerts(mut self, alerts: Vec<Sink>) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    /// Runs `concurrency` jobs at once, waits for new jobs or returns once no job is left if
+    /// `until_empty` is set.
+    pub async fn run(&self, concurrency: usize, until_empty: bool) -> Result<()> {
+        // the jobs of crawl sessions enqueued before may be run in a new month
+        self.storage.maintain_partitions().await?;
+
+        let mut set = JoinSet::new();
+        for _ in 0..concurrency {
+            set.spawn(self.clone().work(until_empty));
+        }
+
+        while let Some(res) = set.join_next().await {
+            res??;
+        }
+
+        Ok(())
+    }
+
+    async fn work(self, until_empty: bool) -> Result<()> {
+        while !self.shutdown.is_requested() {
+            match claim(&self.storage.pool, &self.name).await? {
+                Some(job) => self.run_job(job).await?,
+                None if until_empty && !self.has_jobs().await? => return Ok(()),
+                None => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                        _ = self.shutdown.requested() => {}
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// If any job is waiting or running.
+    async fn has_jobs(&self) -> Result<bool> {
+        let pending: (bool,) = sqlx::query_as(
+            "select exists (select 1 from qj_queue_job where qj_status in ('queued', 'running'))",
+        )
+        .fetch_one(&self.storage.pool)
+        .await?;
+
+        Ok(pending.0)
+    }
+
+    async fn run_job(&self, job: Job) -> Result<()> {
+        let span = info_span!(
+            "job",
+            crawl_id = %job.crawl_id,
+            store = %job.store,
+            category = %job.category,
+            page = job.page,
+            attempt = job.attempts,
+        );
+
+        async {
+            let downloaded = match job.store {
+                Store::Billa => self.download::<BillaCrawl>(&job).await,
+                Store::Spar => self.download::<SparCrawl>(&job).await,
+            };
+
+            match downloaded {
+                Ok((products, last, digest)) => {
+                    self.job_done(&job, products, last, digest).await?;
+                    info!(products, last, "job done");
+                }
+                Err(err) => {
+                    let failed = self.job_failed(&job, &err).await?;
+                    error!(?err, failed, "job failed");
+                }
+            }
+
+            self.finish_store(job.crawl_id, job.store).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Downloads the page of the job and stores its products, returns their number, if it's the
+    /// last page and the digest of the page.
+    async fn download<C: ExecuteCrawler>(&self, job: &Job) -> Result<(usize, bool, Option<u64>)> {
+        let category = C::Category::iter()
+            .find(|category| format!("{:?}", category) == job.category)
+            .ok_or_else(|| anyhow!("unknown category {} of {}", job.category, job.store))?;
+        let base_url = &self.base_urls[&C::STORE];
+
+        let mut guard = PaginationGuard::resume(job.page, job.previous_page);
+        let downloaded = async {
+            if job.category_elapsed > CATEGORY_TIMEOUT {
+                bail!(PaginationError::Timeout(CATEGORY_TIMEOUT));
+            }
+            guard.next_request()?;
+
+            let category_id = self
+                .storage
+                .get_or_add_category(C::STORE, &job.category)
+                .await?;
+
+            C::download_page(
+                job.crawl_id,
+                &self.fetcher,
+                &self.storage,
+                base_url,
+                category,
+                category_id,
+                job.page,
+                job.attempts as u32,
+                &mut guard,
+            )
+            .await
+        }
+        .await;
+
+        let page = match downloaded {
+            Ok(PageDownload::Downloaded(page)) => page,
+            Ok(PageDownload::Retry(_)) => bail!("unexpected status"),
+            Err(err) => {
+                if let Some(err) = err.downcast_ref::<PaginationError>() {
+                    let url = C::page_url(base_url, category, job.page);
+                    self.storage
+                        .save_error(C::STORE, job.crawl_id, &url, &err.to_string())
+                        .await?;
+                }
+
+                return Err(err);
+            }
+        };
+
+        Ok((page.products.len(), page.last, guard.last_page()))
+    }
+
+    async fn job_done(
+        &self,
+        job: &Job,
+        products: usize,
+        last: bool,
+        digest: Option<u64>,
+    ) -> Result<()> {
+        let mut tx = self.storage.pool.begin().await?;
+
+        // added before the job is done, so the store never looks finished in between
+        if !last {
+            sqlx::query(
+                "insert into qj_queue_job (
+                    qj_bcw_crawl, qj_store, qj_category, qj_page, qj_previous_page, qj_category_started
+                )
+                select qj_bcw_crawl, qj_store, qj_category, qj_page + 1, $2, qj_category_started
+                from qj_queue_job
+                where qj_id = $1
+                on conflict do nothing",
+            )
+            .bind(job.id)
+            .bind(digest.map(|digest| digest as i64))
+            .execute(&mut tx)
+            .await?;
+        }
+
+        sqlx::query(
+            "update qj_queue_job
+            set qj_status = 'done', qj_product_count = $2, qj_locked_until = null,
+                qj_finished = current_timestamp
+            where qj_id = $1",
+        )
+        .bind(job.id)
+        .bind(products as i32)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Queues the job again or gives it up after [`MAX_ATTEMPTS`], returns if it was given up.
+    async fn job_failed(&self, job: &Job, err: &anyhow::Error) -> Result<bool> {
+        let status: (String,) = sqlx::query_as(
+            "update qj_queue_job
+            set qj_status = case when qj_attempts >= $2 then 'failed' else 'queued' end,
+                qj_run_after = current_timestamp + make_interval(secs => $3 * power(2, qj_attempts - 1)),
+                qj_finished = case when qj_attempts >= $2 then current_timestamp end,
+                qj_locked_until = null,
+                qj_error = $4
+            where qj_id = $1
+            returning qj_status",
+        )
+        .bind(job.id)
+        .bind(MAX_ATTEMPTS)
+        .bind(self.retry_delay.as_secs_f64())
+        .bind(format!("{:#}", err))
+        .fetch_one(&self.storage.pool)
+        .await?;
+
+        Ok(status.0 == "failed")
+    }
+
+    /// Completes the store of the crawl session once none of its jobs is left and the crawl
+    /// session once none of its stores is left, only a single worker gets to do so.
+    async fn finish_store(&self, crawl_id: Uuid, store: Store) -> Result<()> {
+        let mut tx = self.storage.pool.begin().await?;
+
+        // serializes the workers finishing stores of the crawl session, so exactly one of them
+        // sees the last store finished
+        sqlx::query("select 1 from bcw_billa_crawl where bcw_id = $1 for update")
+            .bind(crawl_id)
+            .execute(&mut tx)
+            .await?;
+
+        let complete: Option<(bool,)> = sqlx::query_as(
+            "update qc_queue_crawl
+            set qc_finished = current_timestamp,
+                qc_complete = not exists (
+                    select 1 from qj_queue_job
+                    where qj_bcw_crawl = $1 and qj_store = $2 and qj_status = 'failed'
+                )
+            where qc_bcw_crawl = $1 and qc_store = $2 and qc_finished is null
+                and not exists (
+                    select 1 from qj_queue_job
+                    where qj_bcw_crawl = $1 and qj_store = $2 and qj_status in ('queued', 'running')
+                )
+            returning qc_complete",
+        )
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .fetch_optional(&mut tx)
+        .await?;
+        let summary = match complete {
+            Some(_) => session_summary(&mut tx, crawl_id).await?,
+            None => None,
+        };
+
+        tx.commit().await?;
+
+        match complete {
+            Some((true,)) => {
+                metrics().crawl_succeeded(store);
+
+                // keeps a crawl of another process from completing the store at the same time
+                let lock = self.storage.lock_store(store, true).await?;
+                let listings = self.storage.complete_crawl(store, crawl_id).await?;
+                if let Some(lock) = lock {
+                    lock.release().await?;
+                }
+                info!(%crawl_id, %store, ?listings, "store finished");
+            }
+            Some((false,)) => {
+                warn!(%crawl_id, %store, "store finished with failed jobs, listings unchanged");
+            }
+            None => {}
+        }
+
+        if let Some(summary) = summary {
+            self.storage.finish_crawl(crawl_id, summary).await?;
+            info!(%crawl_id, ?summary, "crawl finished");
+
+            watch::send_alerts(&self.storage.pool, crawl_id, &self.alerts).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The outcome of the crawl session summed up over its jobs, `None` while any of its stores isn't
+/// finished.
+async fn session_summary(conn: &mut PgConnection, crawl_id: Uuid) -> Result<Option<CrawlSummary>> {
+    let (unfinished, complete): (bool, bool) = sqlx::query_as(
+        "select bool_or(qc_finished is null), bool_and(coalesce(qc_complete, false))
+        from qc_queue_crawl
+        where qc_bcw_crawl = $1",
+    )
+    .bind(crawl_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    if unfinished {
+        return Ok(None);
+    }
+
+    let (pages, products): (i64, i64) = sqlx::query_as(
+        "select count(*), coalesce(sum(qj_product_count), 0)
+        from qj_queue_job
+        where qj_bcw_crawl = $1 and qj_status = 'done'",
+    )
+    .bind(crawl_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(Some(CrawlSummary {
+        status: if complete {
+            CrawlStatus::Complete
+        } else {
+            CrawlStatus::Incomplete
+        },
+        pages: pages as usize,
+        products: products as usize,
+    }))
+}
+
+/// Progress of a store of a crawl session in the queue, from the view `qs_queue_status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct QueueStatus {
+    #[sqlx(rename = "qc_bcw_crawl")]
+    pub crawl_id: Uuid,
+    #[sqlx(rename = "qc_store")]
+    pub store: String,
+    #[sqlx(rename = "qs_queued")]
+    pub queued: i64,
+    #[sqlx(rename = "qs_running")]
+    pub running: i64,
+    #[sqlx(rename = "qs_done")]
+    pub done: i64,
+    #[sqlx(rename = "qs_failed")]
+    pub failed: i64,
+    /// Jobs which needed more than one attempt.
+    #[sqlx(rename = "qs_retried")]
+    pub retried: i64,
+    #[sqlx(rename = "qs_products")]
+    pub products: i64,
+    #[sqlx(rename = "qc_finished")]
+    pub finished: Option<NaiveDateTime>,
+    /// If every job of the store was done, `None` while it isn't finished.
+    #[sqlx(rename = "qc_complete")]
+    pub complete: Option<bool>,
+}
+
+/// A job which failed at least once, either waiting for its retry or given up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct FailedJob {
+    #[sqlx(rename = "qj_id")]
+    pub id: Uuid,
+    #[sqlx(rename = "qj_bcw_crawl")]
+    pub crawl_id: Uuid,
+    #[sqlx(rename = "qj_store")]
+    pub store: String,
+    #[sqlx(rename = "qj_category")]
+    pub category: String,
+    #[sqlx(rename = "qj_page")]
+    pub page: i32,
+    #[sqlx(rename = "qj_status")]
+    pub status: String,
+    #[sqlx(rename = "qj_attempts")]
+    pub attempts: i32,
+    #[sqlx(rename = "qj_error")]
+    pub error: String,
+}
+
+/// The stores of `crawl_id` or of every crawl session in the queue, the newest first.
+pub async fn status(pool: &PgPool, crawl_id: Option<Uuid>) -> Result<Vec<QueueStatus>> {
+    let statuses = sqlx::query_as(
+        "select qs_queue_status.*
+        from qs_queue_status
+            join bcw_billa_crawl on qc_bcw_crawl = bcw_id
+        where $1::uuid is null or qc_bcw_crawl = $1
+        order by bcw_created desc, qc_store",
+    )
+    .bind(crawl_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(statuses)
+}
+
+pub async fn failed_jobs(pool: &PgPool, crawl_id: Option<Uuid>) -> Result<Vec<FailedJob>> {
+    let jobs = sqlx::query_as(
+        "select qj_id, qj_bcw_crawl, qj_store, qj_category, qj_page, qj_status, qj_attempts, qj_error
+        from qj_queue_job
+        where qj_error is not null and qj_status <> 'done'
+            and ($1::uuid is null or qj_bcw_crawl = $1)
+        order by qj_created, qj_store, qj_category, qj_page",
+    )
+    .bind(crawl_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(jobs)
+}
+
+pub fn render_status(
+    statuses: &[QueueStatus],
+    failed: &[FailedJob],
+    format: Format,
+) -> Result<String> {
+    let mut table = Table::new([
+        "crawl", "store", "queued", "running", "done", "failed", "retried", "products", "finished",
+    ]);
+    for status in statuses {
+        let finished = match (status.finished, status.complete) {
+            (Some(finished), Some(false)) => format!("{} incomplete", finished.format("%F %T")),
+            (Some(finished), _) => finished.format("%F %T").to_string(),
+            (None, _) => String::new(),
+        };
+
+        table.push([
+            status.crawl_id.to_string(),
+            status.store.clone(),
+            status.queued.to_string(),
+            status.running.to_string(),
+            status.done.to_string(),
+            status.failed.to_string(),
+            status.retried.to_string(),
+            status.products.to_string(),
+            finished,
+        ]);
+    }
+
+    let mut failed_table = Table::new([
+        "job", "crawl", "store", "category", "page", "status", "attempts", "error",
+    ]);
+    for job in failed {
+        failed_table.push([
+            job.id.to_string(),
+            job.crawl_id.to_string(),
+            job.store.clone(),
+            job.category.clone(),
+            job.page.to_string(),
+            job.status.clone(),
+            job.attempts.to_string(),
+            job.error.clone(),
+        ]);
+    }
+
+    let output = match format {
+        Format::Json => serde_json::to_string_pretty(&json!({
+            "stores": statuses,
+            "failed_jobs": failed,
+        }))?,
+        Format::Table if failed.is_empty() => table.to_text(),
+        Format::Table => format!("{}\n{}", table.to_text(), failed_table.to_text()),
+        Format::Markdown if failed.is_empty() => table.to_markdown(),
+        Format::Markdown => format!("{}\n{}", table.to_markdown(), failed_table.to_markdown()),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{method, query_param};
+    use wiremock::{Mock, ResponseTemplate};
+
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::http::Mode;
+    use crate::test_support::{fixture, mock_billa, TestDatabase};
+    use crate::watch::Rule;
+
+    #[tokio::test]
+    async fn workers_crawl_enqueued_pages() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+
+        // Bread works on its retry, Household never
+        Mock::given(method("GET"))
+            .and(query_param("category", "B2-2"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(query_param("category", "B2-9"))
+            .respond_with(ResponseTemplate::new(500))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let crawl_id = enqueue(&db.storage, &[Store::Billa]).await.unwrap();
+        let enqueued = status(&db.pool, Some(crawl_id)).await.unwrap();
+        assert_eq!(enqueued.len(), 1);
+        assert_eq!(enqueued[0].queued, 9);
+        // billa is in the unfinished session, neither enqueued again nor crawled by itself
+        assert!(enqueue(&db.storage, &[Store::Billa]).await.is_err());
+        assert!(db
+            .storage
+            .lock_store(Store::Billa, false)
+            .await
+            .unwrap()
+            .is_none());
+
+        watch::add_watch(
+            &db.pool,
+            Some(Store::Billa),
+            None,
+            Some("toast"),
+            Rule::Below(dec!(100)),
+        )
+        .await
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let alerts = dir.path().join("alerts.jsonl");
+
+        Worker::new(db.storage.clone(), Fetcher::new(Mode::Live).unwrap())
+            .with_base_url(Store::Billa, &server.uri())
+            .with_retry_delay(Duration::ZERO)
+            .with_alerts(vec![Sink::File(alerts.clone())])
+            .run(3, true)
+            .await
+            .unwrap();
+
+        let status = status(&db.pool, Some(crawl_id)).await.unwrap();
+        assert_eq!(
+            (
+                status[0].queued,
+                status[0].running,
+                status[0].done,
+                status[0].failed,
+                status[0].retried,
+                status[0].products
+            ),
+            // the second page of Vegetables makes 10 jobs
+            (0, 0, 9, 1, 2, 7)
+        );
+        assert!(status[0].finished.is_some());
+        assert_eq!(status[0].complete, Some(false));
+
+        let failed = failed_jobs(&db.pool, Some(crawl_id)).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            (failed[0].category.as_str(), failed[0].status.as_str()),
+            ("Household", "failed")
+        );
+        assert_eq!(failed[0].attempts, MAX_ATTEMPTS);
+        assert!(failed[0].error.contains("unexpected status"));
+
+        let prices: (i64,) = sqlx::query_as("select count(*) from bp_billa_price")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(prices.0, 7);
+
+        let crawl: (Option<String>, i32, i32) = sqlx::query_as(
+            "select bcw_status, bcw_pages, bcw_products from bcw_billa_crawl where bcw_id = $1",
+        )
+        .bind(crawl_id)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(crawl, (Some("incomplete".to_string()), 9, 7));
+        // the alerts are sent once the session is finished
+        assert!(std::fs::read_to_string(&alerts)
+            .unwrap()
+            .contains("00-384201"));
+
+        let output = render_status(&status, &failed, Format::Table).unwrap();
+        assert!(output.contains("incomplete"));
+        assert!(output.contains("Household"));
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn a_repeated_page_fails_the_job_of_another_worker() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        Mock::given(method("GET"))
+            .and(query_param("category", "B2-1"))
+            .and(query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(fixture("billa", "B2-1_1.json")),
+            )
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let crawl_id = enqueue(&db.storage, &[Store::Billa]).await.unwrap();
+        // every worker runs a single job at a time, so the pages of a category are downloaded by
+        // different ones
+        let worker = Worker::new(db.storage.clone(), Fetcher::new(Mode::Live).unwrap())
+            .with_base_url(Store::Billa, &server.uri())
+            .with_retry_delay(Duration::ZERO);
+        worker.run(1, true).await.unwrap();
+
+        let failed = failed_jobs(&db.pool, Some(crawl_id)).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            (failed[0].category.as_str(), failed[0].page),
+            ("Vegetables", 2)
+        );
+        assert!(failed[0].error.contains("identical to the previous page"));
+
+        // the session is finished, so billa can be enqueued again
+        enqueue(&db.storage, &[Store::Billa]).await.unwrap();
+
+        db.close().await;
+    }
+
+    #[test]
+    fn jobs_of_pages_before_the_first_are_rejected() {
+        let row = |page| JobRow {
+            qj_id: Uuid::nil(),
+            qj_bcw_crawl: Uuid::nil(),
+            qj_store: "billa".to_string(),
+            qj_category: "Vegetables".to_string(),
+            qj_page: page,
+            qj_attempts: 1,
+            qj_previous_page: None,
+            qj_category_elapsed: 0.0,
+        };
+
+        assert_eq!(Job::try_from(row(1)).unwrap().page, 1);
+        assert!(Job::try_from(row(0)).is_err());
+        assert!(Job::try_from(row(-1)).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Stores the products and prices of orphaned raw documents.
+//!
+//! A page is saved together with its products and prices in a single transaction. Documents
+//! saved by older versions, which stored the prices of a category only after all of its pages
+//! were downloaded, can be left without them, e.g. if the crawler was killed in between. These
+//! documents aren't marked as stored, see the migration `0008_stored_documents.sql`.
+//!
+//! A document whose crawl session is covered by a price interval of one of its products was
+//! stored, only the intervals don't refer to every document. The prices of an orphan are only
+//! stored if none of its products has prices of a newer crawl session, as the intervals can't be
+//! split afterwards. Orphans are repaired in the order of their crawl sessions.
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use crate::blob::StoredBody;
+use crate::model::Store;
+use crate::output::{Format, Table};
+use crate::storage::postgres::{store_products, PgStorage};
+use crate::storage::Storage;
+use crate::stores::billa::BillaCrawl;
+use crate::stores::spar::SparCrawl;
+use crate::stores::ExecuteCrawler;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum RepairOutcome {
+    /// Would be repaired, only returned by a dry run.
+    Orphaned,
+    Repaired,
+    /// Skipped as some of its products have prices of a newer crawl session.
+    Outdated,
+    /// The document couldn't be parsed or stored.
+    Failed,
+}
+
+/// A raw document without prices which has products.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OrphanedDocument {
+    pub store: Store,
+    pub document_id: Uuid,
+    pub crawl_id: Uuid,
+    pub url: String,
+    pub products: usize,
+    pub outcome: RepairOutcome,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DocumentRow {
+    id: Uuid,
+    crawl_id: Uuid,
+    url: String,
+    #[sqlx(flatten)]
+    body: StoredBody,
+}
+
+struct RepairQueries {
+    /// Documents without an error which aren't marked as stored, in the order they were crawled.
+    unstored: &'static str,
+    /// If one of the products `$2` has a price interval covering the crawl session `$1` and if
+    /// one has a price of a newer crawl session.
+    prices: &'static str,
+    mark_stored: &'static str,
+}
+
+const BILLA_REPAIR_QUERIES: RepairQueries = RepairQueries {
+    unstored: "select br_id as id, br_bcw_crawl as crawl_id, br_url as url,
+        br_raw as raw, rb_body as blob, rb_location as location
+    from br_billa_raw
+    left join rb_raw_blob on br_rb_blob = rb_hash
+    join bcw_billa_crawl on br_bcw_crawl = bcw_id
+    where (br_raw is not null or br_rb_blob is not null) and br_err is null and not br_stored
+    order by bcw_created, br_created",
+    prices: "with prices as (
+        select first_crawl.bcw_created as first_created, last_crawl.bcw_created as last_created
+        from bp_billa_price
+        join bpo_billa_product on bp_bpo_product = bpo_id
+        join br_billa_raw on bp_br_raw = br_id
+        join bcw_billa_crawl first_crawl on br_bcw_crawl = first_crawl.bcw_id
+        join bcw_billa_crawl last_crawl on bp_last_bcw_crawl = last_crawl.bcw_id
+        where bpo_billa_id = any($2)
+    ), crawl as (
+        select bcw_created as created from bcw_billa_crawl where bcw_id = $1
+    )
+    select
+        exists (select 1 from prices, crawl where created between first_created and last_created),
+        exists (select 1 from prices, crawl where last_created > created)",
+    mark_stored: "update br_billa_raw set br_stored = true where br_id = $1",
+};
+
+const SPAR_REPAIR_QUERIES: RepairQueries = RepairQueries {
+    unstored: "select sr_id as id, sr_cs_crawl_session as crawl_id, sr_url as url,
+        sr_raw as raw, rb_body as blob, rb_location as location
+    from sr_spar_raw
+    left join rb_raw_blob on sr_rb_blob = rb_hash
+    join bcw_billa_crawl on sr_cs_crawl_session = bcw_id
+    where (sr_raw is not null or sr_rb_blob is not null) and sr_err is null and not sr_stored
+    order by bcw_created, sr_created",
+    prices: "with prices as (
+        select first_crawl.bcw_created as first_created, last_crawl.bcw_created as last_created
+        from spr_spar_price
+        join sp_spar_product on spr_sp_product = sp_id
+        join sr_spar_raw on spr_sr_raw = sr_id
+        join bcw_billa_crawl first_crawl on sr_cs_crawl_session = first_crawl.bcw_id
+        join bcw_billa_crawl last_crawl on spr_last_cs_crawl_session = last_crawl.bcw_id
+        where sp_spar_id = any($2)
+    ), crawl as (
+        select bcw_created as created from bcw_billa_crawl where bcw_id = $1
+    )
+    select
+        exists (select 1 from prices, crawl where created between first_created and last_created),
+        exists (select 1 from prices, crawl where last_created > created)",
+    mark_stored: "update sr_spar_raw set sr_stored = true where sr_id = $1",
+};
+
+/// Finds the orphaned documents of every store and stores their products and prices, unless
+/// `dry_run` is set.
+pub async fn repair(pool: &PgPool, dry_run: bool) -> Result<Vec<OrphanedDocument>> {
+    let mut documents = repair_store::<BillaCrawl>(pool, &BILLA_REPAIR_QUERIES, dry_run).await?;
+    documents.extend(repair_store::<SparCrawl>(pool, &SPAR_REPAIR_QUERIES, dry_run).await?);
+
+    Ok(documents)
+}
+
+async fn repair_store<C: ExecuteCrawler>(
+    pool: &PgPool,
+    queries: &RepairQueries,
+    dry_run: bool,
+) -> Result<Vec<OrphanedDocument>> {
+    let rows: Vec<DocumentRow> = sqlx::query_as(queries.unstored).fetch_all(pool).await?;
+
+    let mut documents = Vec::new();
+    for row in rows {
+        let (products, outcome, error) =
+            match repair_document::<C>(pool, queries, &row, dry_run).await {
+                Ok(Some((products, outcome))) => (products, outcome, None),
+                Ok(None) => continue,
+                Err(err) => (0, RepairOutcome::Failed, Some(format!("{:#}", err))),
+            };
+
+        documents.push(OrphanedDocument {
+            store: C::STORE,
+            document_id: row.id,
+            crawl_id: row.crawl_id,
+            url: row.url,
+            products,
+            outcome,
+            error,
+        });
+    }
+
+    Ok(documents)
+}
+
+/// Returns `None` if the document isn't an orphan, it's marked as stored then.
+async fn repair_document<C: ExecuteCrawler>(
+    pool: &PgPool,
+    queries: &RepairQueries,
+    row: &DocumentRow,
+    dry_run: bool,
+) -> Result<Option<(usize, RepairOutcome)>> {
+    let body = row
+        .body
+        .clone()
+        .text()
+        .await?
+        .ok_or_else(|| anyhow!("no body of {}", row.url))?;
+    let (products, _) = C::parse_page(&body)?;
+    let category =
+        C::category_of_url(&row.url).ok_or_else(|| anyhow!("unknown category of {}", row.url))?;
+    let products = products
+        .iter()
+        .map(|product| C::to_model(product, category))
+        .collect::<Vec<_>>();
+    let article_ids = products
+        .iter()
+        .map(|product| product.article_id.clone())
+        .collect::<Vec<_>>();
+
+    let category_id = PgStorage::new(pool.clone())
+        .get_or_add_category(C::STORE, &format!("{:?}", category))
+        .await?;
+
+    let mut tx = pool.begin().await?;
+
+    let (covered, newer): (bool, bool) = sqlx::query_as(queries.prices)
+        .bind(row.crawl_id)
+        .bind(&article_ids)
+        .fetch_one(&mut tx)
+        .await?;
+
+    // pages without products don't need prices
+    let outcome = if products.is_empty() || covered {
+        None
+    } else if newer {
+        return Ok(Some((products.len(), RepairOutcome::Outdated)));
+    } else if dry_run {
+        return Ok(Some((products.len(), RepairOutcome::Orphaned)));
+    } else {
+        store_products(&mut tx, category_id, row.id, &products).await?;
+
+        Some((products.len(), RepairOutcome::Repaired))
+    };
+
+    if !dry_run {
+        sqlx::query(queries.mark_stored)
+            .bind(row.id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+    }
+
+    Ok(outcome)
+}
+
+pub fn render(documents: &[OrphanedDocument], format: Format) -> Result<String> {
+    let mut table = Table::new(["store", "document", "crawl", "url", "products", "outcome"]);
+    for document in documents {
+        let outcome = match &document.error {
+            Some(error) => format!("{}: {}", document.outcome, error),
+            None => document.outcome.to_string(),
+        };
+
+        table.push([
+            document.store.to_string(),
+            document.document_id.to_string(),
+            document.crawl_id.to_string(),
+            document.url.clone(),
+            document.products.to_string(),
+            outcome,
+        ]);
+    }
+
+    let output = match format {
+        Format::Table => table.to_text(),
+        Format::Json => serde_json::to_string_pretty(documents)?,
+        Format::Markdown => table.to_markdown(),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stores::billa::{BillaUrl, Category};
+    use crate::test_support::{fixture, TestDatabase};
+
+    #[tokio::test]
+    async fn orphaned_documents_are_repaired_in_order() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let url = BillaUrl::new("http://localhost", Category::Bread, 1).as_url();
+        let body = fixture("billa", "B2-2_1.json");
+
+        let earliest = db.crawl_id().await;
+        let first = db.crawl_id().await;
+        let second = db.crawl_id().await;
+
+        // the prices of the first two crawls were never stored
+        let mut orphans = Vec::new();
+        for crawl_id in [first, second] {
+            orphans.push(
+                db.storage
+                    .save_document(Store::Billa, crawl_id, &url, &body)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let found = repair(&db.pool, true).await.unwrap();
+        let outcomes = found
+            .iter()
+            .map(|document| (document.document_id, document.products, document.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                (orphans[0], 2, RepairOutcome::Orphaned),
+                (orphans[1], 2, RepairOutcome::Orphaned)
+            ]
+        );
+
+        let repaired = repair(&db.pool, false).await.unwrap();
+        assert_eq!(repaired.len(), 2);
+        assert!(repaired
+            .iter()
+            .all(|document| document.outcome == RepairOutcome::Repaired));
+
+        let prices: Vec<(Uuid, Uuid)> =
+            sqlx::query_as("select bp_br_raw, bp_last_bcw_crawl from bp_billa_price")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(prices, [(orphans[0], second), (orphans[0], second)]);
+        assert!(repair(&db.pool, false).await.unwrap().is_empty());
+
+        // covered by the interval of its products, it only wasn't marked
+        db.storage
+            .save_document(Store::Billa, first, &url, &body)
+            .await
+            .unwrap();
+        // older than the stored prices, it can't be merged into their intervals
+        let outdated = db
+            .storage
+            .save_document(Store::Billa, earliest, &url, &body)
+            .await
+            .unwrap();
+
+        let skipped = repair(&db.pool, false).await.unwrap();
+        let outcomes = skipped
+            .iter()
+            .map(|document| (document.document_id, document.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes, [(outdated, RepairOutcome::Outdated)]);
+
+        db.close().await;
+    }
+}
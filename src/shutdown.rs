@@ -0,0 +1,82 @@
+//! Stops crawls gracefully on SIGINT or SIGTERM: no further pages are requested, but the pages
+//! already downloaded are still stored and the crawl session is marked as interrupted, so it can
+//! be resumed later.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Shared by everything which has to stop on shutdown, clones observe the same request.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+
+        Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Requests the shutdown on the first SIGINT or SIGTERM and exits at once on the second one.
+    pub fn on_signals() -> Result<Self> {
+        let shutdown = Shutdown::new();
+
+        #[cfg(unix)]
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+        let requested = shutdown.clone();
+        tokio::spawn(async move {
+            for signals in 0.. {
+                #[cfg(unix)]
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                #[cfg(not(unix))]
+                let _ = tokio::signal::ctrl_c().await;
+
+                if signals > 0 {
+                    std::process::exit(130);
+                }
+
+                warn!(
+                    "shutting down, finishing the downloaded pages, signal again to exit at once"
+                );
+                requested.request();
+            }
+        });
+
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Waits until the shutdown is requested.
+    pub async fn requested(&self) {
+        let mut receiver = self.receiver.clone();
+
+        // the sender lives as long as `self`, so waiting can't fail
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
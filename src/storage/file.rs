@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::Storage;
+use crate::model::{JournalEntry, Product, Store};
+
+pub const CRAWLS: &str = "crawls.jsonl";
+pub const DOCUMENTS: &str = "documents.jsonl";
+pub const CATEGORIES: &str = "categories.jsonl";
+pub const PRODUCTS: &str = "products.jsonl";
+pub const PRICES: &str = "prices.jsonl";
+pub const REQUESTS: &str = "requests.jsonl";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrawlRecord {
+    pub id: Uuid,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentRecord {
+    pub id: Uuid,
+    pub store: Store,
+    pub crawl_id: Uuid,
+    pub url: String,
+    pub body: Option<String>,
+    pub error: Option<String>,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CategoryRecord {
+    pub id: Uuid,
+    pub store: Store,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProductRecord {
+    pub id: Uuid,
+    pub category_id: Uuid,
+    pub product: Product,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PriceRecord {
+    pub product_id: Uuid,
+    pub document_id: Uuid,
+    pub product: Product,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestRecord {
+    pub crawl_id: Uuid,
+    #[serde(flatten)]
+    pub entry: JournalEntry,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug, Default)]
+struct Ids {
+    categories: HashMap<(Store, String), Uuid>,
+    products: HashMap<(Store, String), Uuid>,
+}
+
+/// Appends everything to JSON lines files in a directory, one file per kind of record.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    dir: PathBuf,
+    ids: Arc<Mutex<Ids>>,
+}
+
+impl FileStorage {
+    /// Opens the storage in `dir`, the ids of categories and products already stored there are
+    /// reused.
+    pub async fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut ids = Ids::default();
+        for category in read_records::<CategoryRecord>(&dir.join(CATEGORIES)).await? {
+            ids.categories
+                .insert((category.store, category.name), category.id);
+        }
+        for product in read_records::<ProductRecord>(&dir.join(PRODUCTS)).await? {
+            ids.products.insert(
+                (product.product.store, product.product.article_id),
+                product.id,
+            );
+        }
+
+        Ok(FileStorage {
+            dir,
+            ids: Arc::new(Mutex::new(ids)),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Appends `records` to `file`, has to be called while holding the lock of `ids` so lines of
+    /// concurrent writes don't interleave.
+    async fn append<T: Serialize>(&self, file: &str, records: &[T]) -> Result<()> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(file))
+            .await?;
+        file.write_all(&lines).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+pub async fn read_records<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+impl Storage for FileStorage {
+    async fn create_crawl(&self) -> Result<Uuid> {
+        let _ids = self.ids.lock().await;
+
+        let record = CrawlRecord {
+            id: Uuid::new_v4(),
+            created: Utc::now().naive_utc(),
+        };
+        self.append(CRAWLS, &[&record]).await?;
+
+        Ok(record.id)
+    }
+
+    async fn save_document(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+    ) -> Result<Uuid> {
+        let _ids = self.ids.lock().await;
+
+        let record = DocumentRecord {
+            id: Uuid::new_v4(),
+            store,
+            crawl_id,
+            url: url.to_string(),
+            body: Some(body.to_string()),
+            error: None,
+            created: Utc::now().naive_utc(),
+        };
+        self.append(DOCUMENTS, &[&record]).await?;
+
+        Ok(record.id)
+    }
+
+    async fn save_error(&self, store: Store, crawl_id: Uuid, url: &str, error: &str) -> Result<()> {
+        let _ids = self.ids.lock().await;
+
+        let record = DocumentRecord {
+            id: Uuid::new_v4(),
+            store,
+            crawl_id,
+            url: url.to_string(),
+            body: None,
+            error: Some(error.to_string()),
+            created: Utc::now().naive_utc(),
+        };
+        self.append(DOCUMENTS, &[&record]).await?;
+
+        Ok(())
+    }
+
+    async fn save_request(&self, crawl_id: Uuid, entry: &JournalEntry) -> Result<()> {
+        let _ids = self.ids.lock().await;
+
+        let record = RequestRecord {
+            crawl_id,
+            entry: entry.clone(),
+            created: Utc::now().naive_utc(),
+        };
+        self.append(REQUESTS, &[&record]).await?;
+
+        Ok(())
+    }
+
+    async fn get_or_add_category(&self, store: Store, name: &str) -> Result<Uuid> {
+        let mut ids = self.ids.lock().await;
+
+        if let Some(id) = ids.categories.get(&(store, name.to_string())) {
+            return Ok(*id);
+        }
+
+        let record = CategoryRecord {
+            id: Uuid::new_v4(),
+            store,
+            name: name.to_string(),
+        };
+        self.append(CATEGORIES, &[&record]).await?;
+        ids.categories.insert((store, record.name), record.id);
+
+        Ok(record.id)
+    }
+
+    async fn get_or_add_product(&self, category_id: Uuid, product: &Product) -> Result<Uuid> {
+        let mut ids = self.ids.lock().await;
+
+        let key = (product.store, product.article_id.clone());
+        if let Some(id) = ids.products.get(&key) {
+            return Ok(*id);
+        }
+
+        let record = ProductRecord {
+            id: Uuid::new_v4(),
+            category_id,
+            product: product.clone(),
+            created: Utc::now().naive_utc(),
+        };
+        self.append(PRODUCTS, &[&record]).await?;
+        ids.products.insert(key, record.id);
+
+        Ok(record.id)
+    }
+
+    async fn save_prices(&self, prices: &[(Uuid, Uuid, Product)]) -> Result<()> {
+        let _ids = self.ids.lock().await;
+
+        let created = Utc::now().naive_utc();
+        let records = prices
+            .iter()
+            .map(|(product_id, document_id, product)| PriceRecord {
+                product_id: *product_id,
+                document_id: *document_id,
+                product: product.clone(),
+                created,
+            })
+            .collect::<Vec<_>>();
+        self.append(PRICES, &records).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::test_support::{crawl_billa, mock_billa};
+
+    #[tokio::test]
+    async fn execute_appends_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = mock_billa().await;
+
+        let storage = FileStorage::open(dir.path()).await.unwrap();
+        crawl_billa(&storage, &server).await;
+
+        let documents = read_records::<DocumentRecord>(&dir.path().join(DOCUMENTS))
+            .await
+            .unwrap();
+        assert_eq!(documents.len(), 10);
+        assert!(documents.iter().all(|document| document.body.is_some()));
+
+        let products = read_records::<ProductRecord>(&dir.path().join(PRODUCTS))
+            .await
+            .unwrap();
+        assert_eq!(products.len(), 7);
+
+        let prices = read_records::<PriceRecord>(&dir.path().join(PRICES))
+            .await
+            .unwrap();
+        assert_eq!(prices.len(), 7);
+        let toast = prices
+            .iter()
+            .find(|price| price.product.article_id == "00-384201")
+            .unwrap();
+        assert_eq!(toast.product.price, dec!(2.79));
+        assert_eq!(toast.product.category, "Bread");
+
+        // products of an earlier crawl keep their id
+        let storage = FileStorage::open(dir.path()).await.unwrap();
+        let category_id = products[0].category_id;
+        let product_id = storage
+            .get_or_add_product(category_id, &products[0].product)
+            .await
+            .unwrap();
+        assert_eq!(product_id, products[0].id);
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt::Debug;
+use std::future::Future;
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use sqlx::types::Uuid;
+
+use crate::model::{CategoryProgress, CrawlSummary, JournalEntry, ListingChanges, Product, Store};
+
+pub mod file;
+pub mod postgres;
+pub mod sqlite;
+
+pub use file::FileStorage;
+
+/// Keeps other processes from crawling a store while it's held, see [`Storage::lock_store`].
+#[derive(Debug, Default)]
+pub struct StoreLock {
+    /// The postgres session holding the advisory lock, the lock ends with the session.
+    connection: Option<sqlx::PgConnection>,
+}
+
+impl StoreLock {
+    /// Lets other processes crawl the store, dropping the lock does the same once the connection
+    /// is gone.
+    pub async fn release(self) -> Result<()> {
+        if let Some(connection) = self.connection {
+            sqlx::Connection::close(connection).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Destination of everything a crawl produces.
+///
+/// Implemented by [`postgres::PgStorage`], for [`sqlx::SqlitePool`] and by [`FileStorage`], which
+/// writes JSON lines files.
+pub trait Storage: Clone + Debug + Send + Sync + 'static {
+    /// Starts a new crawl session and returns its id.
+    fn create_crawl(&self) -> impl Future<Output = Result<Uuid>> + Send;
+
+    /// Saves the body of a successful response and returns the id of the document.
+    fn save_document(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+    ) -> impl Future<Output = Result<Uuid>> + Send;
+
+    /// Saves why the request for `url` didn't return a document.
+    fn save_error(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        error: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Journals a request of the crawl session `crawl_id`.
+    fn save_request(
+        &self,
+        crawl_id: Uuid,
+        entry: &JournalEntry,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_or_add_category(
+        &self,
+        store: Store,
+        name: &str,
+    ) -> impl Future<Output = Result<Uuid>> + Send;
+
+    /// Returns the id of the product, it's added with `category_id` the first time it's seen.
+    fn get_or_add_product(
+        &self,
+        category_id: Uuid,
+        product: &Product,
+    ) -> impl Future<Output = Result<Uuid>> + Send;
+
+    /// Saves the price of every `(product id, document id, product)`.
+    fn save_prices(
+        &self,
+        prices: &[(Uuid, Uuid, Product)],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Saves the body of a page together with its products of `category_id` and their prices,
+    /// returns the id of the document.
+    ///
+    /// Postgres and sqlite save all of it in a single transaction, the file storage one after
+    /// another.
+    fn save_page(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+        category_id: Uuid,
+        products: &[Product],
+    ) -> impl Future<Output = Result<Uuid>> + Send {
+        async move {
+            let document_id = self.save_document(store, crawl_id, url, body).await?;
+
+            let mut prices = Vec::with_capacity(products.len());
+            for product in products {
+                let product_id = self.get_or_add_product(category_id, product).await?;
+
+                prices.push((product_id, document_id, product.clone()));
+            }
+            self.save_prices(&prices).await?;
+
+            Ok(document_id)
+        }
+    }
+
+    /// Called once every category of `store` was crawled in `crawl_id`, marks the products
+    /// which weren't seen as delisted and the ones which came back as relisted.
+    ///
+    /// Returns `None` if the storage doesn't keep track of listings.
+    fn complete_crawl(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+    ) -> impl Future<Output = Result<Option<ListingChanges>>> + Send {
+        let _ = (store, crawl_id);
+
+        async { Ok(None) }
+    }
+
+    /// Remembers how far the crawl of `category` got, see [`Storage::progress`].
+    fn save_progress(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        category: &str,
+        progress: CategoryProgress,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = (store, crawl_id, category, progress);
+
+        async { Ok(()) }
+    }
+
+    /// How far the crawls of the categories of `store` got in `crawl_id`, by category.
+    ///
+    /// Empty if the storage doesn't keep track of it.
+    fn progress(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+    ) -> impl Future<Output = Result<HashMap<String, CategoryProgress>>> + Send {
+        let _ = (store, crawl_id);
+
+        async { Ok(HashMap::new()) }
+    }
+
+    /// Marks how the crawl session ended, the counts of a resumed session are added up.
+    fn finish_crawl(
+        &self,
+        crawl_id: Uuid,
+        summary: CrawlSummary,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = (crawl_id, summary);
+
+        async { Ok(()) }
+    }
+
+    /// Continues the interrupted crawl session `crawl_id`.
+    fn resume_crawl(&self, crawl_id: Uuid) -> impl Future<Output = Result<()>> + Send {
+        let _ = crawl_id;
+
+        async { bail!("resuming a crawl needs a postgres storage") }
+    }
+
+    /// Locks `store` for crawling it, waits for other processes crawling it if `wait` is set and
+    /// returns `None` otherwise.
+    ///
+    /// Only postgres is shared between processes, the other storages always return a lock.
+    fn lock_store(
+        &self,
+        store: Store,
+        wait: bool,
+    ) -> impl Future<Output = Result<Option<StoreLock>>> + Send {
+        let _ = (store, wait);
+
+        async { Ok(Some(StoreLock::default())) }
+    }
+}
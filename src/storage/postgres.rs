@@ -0,0 +1,956 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use rust_decimal::Decimal;
+use sqlx::types::Uuid;
+use sqlx::{PgConnection, PgPool};
+
+use super::{Storage, StoreLock};
+use crate::blob::{self, BlobStore};
+use crate::model::{
+    CategoryProgress, CrawlStatus, CrawlSummary, Details, JournalEntry, Listing, ListingChanges,
+    Product, Store,
+};
+use crate::partition;
+
+/// Prices are stored as intervals which are only split when the price changes.
+///
+/// The queries run in order for every batch: unchanged prices extend their open interval to the
+/// crawl of the batch, changed prices close it and every product without an open interval gets
+/// a new one starting at the crawl. A new price is created with its document, so it's in the
+/// partition of the same month.
+const BILLA_PRICE_QUERIES: [&str; 3] = [
+    "with input as (
+        select product, normal, unit, discounted, br_bcw_crawl as crawl
+        from unnest($1::uuid[], $2::uuid[], $3::numeric[], $4::text[], $5::bool[]) as input(product, raw, normal, unit, discounted)
+        join br_billa_raw on raw = br_id
+    )
+    update bp_billa_price set bp_last_bcw_crawl = crawl
+    from input
+    where bp_bpo_product = product and bp_valid_to is null
+        and bp_normal = normal and bp_unit is not distinct from unit and bp_discounted = discounted",
+    "with input as (
+        select product, normal, unit, discounted, bcw_created as created
+        from unnest($1::uuid[], $2::uuid[], $3::numeric[], $4::text[], $5::bool[]) as input(product, raw, normal, unit, discounted)
+        join br_billa_raw on raw = br_id
+        join bcw_billa_crawl on br_bcw_crawl = bcw_id
+    )
+    update bp_billa_price set bp_valid_to = created
+    from input
+    where bp_bpo_product = product and bp_valid_to is null
+        and (bp_normal <> normal or bp_unit is distinct from unit or bp_discounted <> discounted)",
+    "insert into bp_billa_price (bp_bpo_product, bp_br_raw, bp_created, bp_normal, bp_unit, bp_discounted, bp_valid_from, bp_last_bcw_crawl)
+    select product, raw, br_created, normal, unit, discounted, bcw_created, bcw_id
+    from unnest($1::uuid[], $2::uuid[], $3::numeric[], $4::text[], $5::bool[]) as input(product, raw, normal, unit, discounted)
+    join br_billa_raw on raw = br_id
+    join bcw_billa_crawl on br_bcw_crawl = bcw_id
+    where not exists (select 1 from bp_billa_price where bp_bpo_product = product and bp_valid_to is null)",
+];
+
+const SPAR_PRICE_QUERIES: [&str; 3] = [
+    "with input as (
+        select product, price, sales_unit, price_unit, sr_cs_crawl_session as crawl
+        from unnest($1::uuid[], $2::uuid[], $3::numeric[], $4::text[], $5::text[]) as input(product, raw, price, sales_unit, price_unit)
+        join sr_spar_raw on raw = sr_id
+    )
+    update spr_spar_price set spr_last_cs_crawl_session = crawl
+    from input
+    where spr_sp_product = product and spr_valid_to is null
+        and spr_price = price
+        and spr_sales_unit is not distinct from sales_unit
+        and spr_price_unit is not distinct from price_unit",
+    "with input as (
+        select product, price, sales_unit, price_unit, bcw_created as created
+        from unnest($1::uuid[], $2::uuid[], $3::numeric[], $4::text[], $5::text[]) as input(product, raw, price, sales_unit, price_unit)
+        join sr_spar_raw on raw = sr_id
+        join bcw_billa_crawl on sr_cs_crawl_session = bcw_id
+    )
+    update spr_spar_price set spr_valid_to = created
+    from input
+    where spr_sp_product = product and spr_valid_to is null
+        and (spr_price <> price
+            or spr_sales_unit is distinct from sales_unit
+            or spr_price_unit is distinct from price_unit)",
+    "insert into spr_spar_price (spr_price, spr_sales_unit, spr_price_unit, spr_sp_product, spr_sr_raw, spr_p_created, spr_valid_from, spr_last_cs_crawl_session)
+    select price, sales_unit, price_unit, product, raw, sr_created, bcw_created, bcw_id
+    from unnest($1::uuid[], $2::uuid[], $3::numeric[], $4::text[], $5::text[]) as input(product, raw, price, sales_unit, price_unit)
+    join sr_spar_raw on raw = sr_id
+    join bcw_billa_crawl on sr_cs_crawl_session = bcw_id
+    where not exists (select 1 from spr_spar_price where spr_sp_product = product and spr_valid_to is null)",
+];
+
+/// Queries to update the listings of a store after a complete crawl `$1`, a product was seen in
+/// the crawl if one of its prices has it as the last crawl.
+struct ListingQueries {
+    relisted: &'static str,
+    /// Closes the open prices of products not seen, so they get a new interval if they come back.
+    close_prices: &'static str,
+    delisted: &'static str,
+    added: &'static str,
+}
+
+const BILLA_LISTING_QUERIES: ListingQueries = ListingQueries {
+    relisted: "update bpo_billa_product set bpo_delisted = null, bpo_relisted = bcw_created
+    from bcw_billa_crawl
+    where bcw_id = $1 and bpo_delisted is not null
+        and exists (select 1 from bp_billa_price where bp_bpo_product = bpo_id and bp_last_bcw_crawl = $1)
+    returning bpo_billa_id as article_id, bpo_name as name",
+    close_prices: "update bp_billa_price set bp_valid_to = bcw_created
+    from bcw_billa_crawl
+    where bcw_id = $1 and bp_valid_to is null and bp_last_bcw_crawl <> $1",
+    delisted: "update bpo_billa_product set bpo_delisted = bcw_created
+    from bcw_billa_crawl
+    where bcw_id = $1 and bpo_delisted is null
+        and not exists (select 1 from bp_billa_price where bp_bpo_product = bpo_id and bp_last_bcw_crawl = $1)
+    returning bpo_billa_id as article_id, bpo_name as name",
+    added: "select bpo_billa_id as article_id, bpo_name as name
+    from bpo_billa_product
+    where exists (select 1 from bp_billa_price where bp_bpo_product = bpo_id and bp_last_bcw_crawl = $1)
+        and not exists (
+            select 1
+            from bp_billa_price
+            join br_billa_raw on bp_br_raw = br_id
+            where bp_bpo_product = bpo_id and br_bcw_crawl <> $1
+        )",
+};
+
+const SPAR_LISTING_QUERIES: ListingQueries = ListingQueries {
+    relisted: "update sp_spar_product set sp_delisted = null, sp_relisted = bcw_created
+    from bcw_billa_crawl
+    where bcw_id = $1 and sp_delisted is not null
+        and exists (select 1 from spr_spar_price where spr_sp_product = sp_id and spr_last_cs_crawl_session = $1)
+    returning sp_spar_id as article_id, sp_name as name",
+    close_prices: "update spr_spar_price set spr_valid_to = bcw_created
+    from bcw_billa_crawl
+    where bcw_id = $1 and spr_valid_to is null and spr_last_cs_crawl_session <> $1",
+    delisted: "update sp_spar_product set sp_delisted = bcw_created
+    from bcw_billa_crawl
+    where bcw_id = $1 and sp_delisted is null
+        and not exists (select 1 from spr_spar_price where spr_sp_product = sp_id and spr_last_cs_crawl_session = $1)
+    returning sp_spar_id as article_id, sp_name as name",
+    added: "select sp_spar_id as article_id, sp_name as name
+    from sp_spar_product
+    where exists (select 1 from spr_spar_price where spr_sp_product = sp_id and spr_last_cs_crawl_session = $1)
+        and not exists (
+            select 1
+            from spr_spar_price
+            join sr_spar_raw on spr_sr_raw = sr_id
+            where spr_sp_product = sp_id and sr_cs_crawl_session <> $1
+        )",
+};
+
+#[derive(Debug, Default)]
+struct BillaPrices {
+    products: Vec<Uuid>,
+    documents: Vec<Uuid>,
+    normal: Vec<Decimal>,
+    unit: Vec<String>,
+    discounted: Vec<bool>,
+}
+
+#[derive(Debug, Default)]
+struct SparPrices {
+    products: Vec<Uuid>,
+    documents: Vec<Uuid>,
+    price: Vec<Decimal>,
+    sales_unit: Vec<String>,
+    price_unit: Vec<String>,
+}
+
+/// The postgres storage, the pool together with where the bodies of new documents are written and
+/// when partitions are detached.
+#[derive(Debug, Clone)]
+pub struct PgStorage {
+    pub pool: PgPool,
+    blob_store: BlobStore,
+    /// Months after which partitions are detached, see [`partition::maintain`].
+    detach_after: Option<u32>,
+}
+
+impl PgStorage {
+    /// Writes the bodies into the database and never detaches partitions, see
+    /// [`Self::with_blob_store`] and [`Self::with_detach_after`].
+    pub fn new(pool: PgPool) -> Self {
+        PgStorage {
+            pool,
+            blob_store: BlobStore::default(),
+            detach_after: None,
+        }
+    }
+
+    pub fn with_blob_store(mut self, blob_store: BlobStore) -> Self {
+        self.blob_store = blob_store;
+        self
+    }
+
+    pub fn with_detach_after(mut self, months: u32) -> Self {
+        self.detach_after = Some(months);
+        self
+    }
+
+    /// Creates the partitions of the current and the next month and detaches the old ones.
+    pub async fn maintain_partitions(&self) -> Result<()> {
+        partition::maintain(&self.pool, self.detach_after).await
+    }
+
+    /// The advisory lock of `store`, see [`Storage::lock_store`].
+    async fn advisory_lock(&self, store: Store, wait: bool) -> Result<Option<StoreLock>> {
+        // the advisory lock belongs to the session, so the connection must not go back to the pool
+        let mut connection = self.pool.acquire().await?.detach();
+
+        let locked = if wait {
+            sqlx::query("select pg_advisory_lock(hashtextextended($1, 0))")
+                .bind(lock_key(store))
+                .execute(&mut connection)
+                .await?;
+
+            true
+        } else {
+            let locked: (bool,) =
+                sqlx::query_as("select pg_try_advisory_lock(hashtextextended($1, 0))")
+                    .bind(lock_key(store))
+                    .fetch_one(&mut connection)
+                    .await?;
+
+            locked.0
+        };
+
+        if !locked {
+            sqlx::Connection::close(connection).await?;
+
+            return Ok(None);
+        }
+
+        Ok(Some(StoreLock {
+            connection: Some(connection),
+        }))
+    }
+}
+
+impl Storage for PgStorage {
+    async fn create_crawl(&self) -> Result<Uuid> {
+        self.maintain_partitions().await?;
+
+        let crawl_id: (Uuid,) =
+            sqlx::query_as("INSERT INTO bcw_billa_crawl DEFAULT VALUES RETURNING bcw_id")
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(crawl_id.0)
+    }
+
+    async fn save_document(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+    ) -> Result<Uuid> {
+        insert_document(
+            &mut *self.pool.acquire().await?,
+            store,
+            crawl_id,
+            url,
+            body,
+            false,
+            &self.blob_store,
+        )
+        .await
+    }
+
+    async fn save_error(&self, store: Store, crawl_id: Uuid, url: &str, error: &str) -> Result<()> {
+        let query = match store {
+            Store::Billa => {
+                "insert into br_billa_raw (br_url, br_err, br_bcw_crawl) values ( $1, $2, $3 )"
+            }
+            Store::Spar => {
+                "insert into sr_spar_raw (sr_url, sr_err, sr_cs_crawl_session) values ( $1, $2, $3 )"
+            }
+        };
+
+        sqlx::query(query)
+            .bind(url)
+            .bind(error)
+            .bind(crawl_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn save_request(&self, crawl_id: Uuid, entry: &JournalEntry) -> Result<()> {
+        sqlx::query(
+            "insert into rj_request_journal (rj_bcw_crawl, rj_store, rj_url, rj_status, rj_headers, rj_duration_ms, rj_bytes, rj_attempt, rj_user_agent, rj_error)
+            values ( $1, $2, $3, $4, $5::jsonb, $6, $7, $8, $9, $10 )",
+        )
+        .bind(crawl_id)
+        .bind(entry.store.to_string())
+        .bind(&entry.url)
+        .bind(entry.status.map(i32::from))
+        .bind(serde_json::to_string(&entry.headers)?)
+        .bind(entry.duration_ms as i64)
+        .bind(entry.bytes as i64)
+        .bind(entry.attempt as i32)
+        .bind(&entry.user_agent)
+        .bind(&entry.error)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_or_add_category(&self, store: Store, name: &str) -> Result<Uuid> {
+        let (select, insert) = match store {
+            Store::Billa => (
+                "SELECT bc_id FROM bc_billa_category WHERE bc_text = $1",
+                "INSERT INTO bc_billa_category (bc_text) VALUES ( $1 ) ON CONFLICT (bc_text) DO NOTHING RETURNING bc_id",
+            ),
+            Store::Spar => (
+                "select sc_id from sc_spar_category where sc_text = $1",
+                "insert into sc_spar_category (sc_text) values ( $1 ) on conflict (sc_text) do nothing returning sc_id",
+            ),
+        };
+
+        let id: Option<(Uuid,)> = sqlx::query_as(select)
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        if let Some(id) = id {
+            return Ok(id.0);
+        }
+
+        let id: Option<(Uuid,)> = sqlx::query_as(insert)
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        let id = match id {
+            Some(id) => id,
+            // added by a concurrent crawl in the meantime
+            None => {
+                sqlx::query_as(select)
+                    .bind(name)
+                    .fetch_one(&self.pool)
+                    .await?
+            }
+        };
+
+        Ok(id.0)
+    }
+
+    async fn get_or_add_product(&self, category_id: Uuid, product: &Product) -> Result<Uuid> {
+        product_id(&mut *self.pool.acquire().await?, category_id, product).await
+    }
+
+    async fn save_prices(&self, prices: &[(Uuid, Uuid, Product)]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        insert_prices(&mut tx, prices).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_page(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+        category_id: Uuid,
+        products: &[Product],
+    ) -> Result<Uuid> {
+        let mut tx = self.pool.begin().await?;
+        let document_id =
+            insert_document(&mut tx, store, crawl_id, url, body, true, &self.blob_store).await?;
+        store_products(&mut tx, category_id, document_id, products).await?;
+        tx.commit().await?;
+
+        Ok(document_id)
+    }
+
+    async fn save_progress(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        category: &str,
+        progress: CategoryProgress,
+    ) -> Result<()> {
+        let next_page = match progress {
+            CategoryProgress::Done => None,
+            CategoryProgress::NextPage(page) => Some(page as i32),
+        };
+
+        sqlx::query(
+            "insert into cp_crawl_progress (cp_bcw_crawl, cp_store, cp_category, cp_next_page)
+            values ($1, $2, $3, $4)
+            on conflict (cp_bcw_crawl, cp_store, cp_category)
+            do update set cp_next_page = excluded.cp_next_page, cp_updated = current_timestamp",
+        )
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .bind(category)
+        .bind(next_page)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn progress(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+    ) -> Result<HashMap<String, CategoryProgress>> {
+        let rows: Vec<(String, Option<i32>)> = sqlx::query_as(
+            "select cp_category, cp_next_page from cp_crawl_progress where cp_bcw_crawl = $1 and cp_store = $2",
+        )
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(category, next_page)| {
+                let progress = match next_page {
+                    Some(page) => CategoryProgress::NextPage(page as usize),
+                    None => CategoryProgress::Done,
+                };
+
+                (category, progress)
+            })
+            .collect())
+    }
+
+    async fn finish_crawl(&self, crawl_id: Uuid, summary: CrawlSummary) -> Result<()> {
+        sqlx::query(
+            "update bcw_billa_crawl
+            set bcw_status = $2, bcw_finished = current_timestamp,
+                bcw_pages = bcw_pages + $3, bcw_products = bcw_products + $4
+            where bcw_id = $1",
+        )
+        .bind(crawl_id)
+        .bind(summary.status.to_string())
+        .bind(summary.pages as i32)
+        .bind(summary.products as i32)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn resume_crawl(&self, crawl_id: Uuid) -> Result<()> {
+        let resumed = sqlx::query(
+            "update bcw_billa_crawl set bcw_status = 'running', bcw_finished = null
+            where bcw_id = $1 and bcw_status = $2",
+        )
+        .bind(crawl_id)
+        .bind(CrawlStatus::Interrupted.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        if resumed.rows_affected() == 0 {
+            bail!("crawl {} doesn't exist or wasn't interrupted", crawl_id);
+        }
+
+        self.maintain_partitions().await?;
+
+        Ok(())
+    }
+
+    /// Also treats a store in an unfinished crawl session of the queue as locked, its workers
+    /// don't hold the lock.
+    async fn lock_store(&self, store: Store, wait: bool) -> Result<Option<StoreLock>> {
+        loop {
+            let Some(lock) = self.advisory_lock(store, wait).await? else {
+                return Ok(None);
+            };
+
+            let (queued,): (bool,) = sqlx::query_as(
+                "select exists (select 1 from qc_queue_crawl where qc_store = $1 and qc_finished is null)",
+            )
+            .bind(store.to_string())
+            .fetch_one(&self.pool)
+            .await?;
+            if !queued {
+                return Ok(Some(lock));
+            }
+
+            lock.release().await?;
+            if !wait {
+                return Ok(None);
+            }
+            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn complete_crawl(&self, store: Store, crawl_id: Uuid) -> Result<Option<ListingChanges>> {
+        let queries = match store {
+            Store::Billa => BILLA_LISTING_QUERIES,
+            Store::Spar => SPAR_LISTING_QUERIES,
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        let mut relisted: Vec<Listing> = sqlx::query_as(queries.relisted)
+            .bind(crawl_id)
+            .fetch_all(&mut tx)
+            .await?;
+        sqlx::query(queries.close_prices)
+            .bind(crawl_id)
+            .execute(&mut tx)
+            .await?;
+        let mut removed: Vec<Listing> = sqlx::query_as(queries.delisted)
+            .bind(crawl_id)
+            .fetch_all(&mut tx)
+            .await?;
+        let mut added: Vec<Listing> = sqlx::query_as(queries.added)
+            .bind(crawl_id)
+            .fetch_all(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        for listings in [&mut added, &mut removed, &mut relisted] {
+            listings.sort_by(|a, b| a.article_id.cmp(&b.article_id));
+        }
+
+        Ok(Some(ListingChanges {
+            added,
+            removed,
+            relisted,
+        }))
+    }
+}
+
+/// Inserts a document, `stored` marks that its products and prices are stored with it.
+async fn insert_document(
+    conn: &mut PgConnection,
+    store: Store,
+    crawl_id: Uuid,
+    url: &str,
+    body: &str,
+    stored: bool,
+    blob_store: &BlobStore,
+) -> Result<Uuid> {
+    let hash = blob::save(&mut *conn, body, blob_store).await?;
+
+    let query = match store {
+        Store::Billa => "insert into br_billa_raw (br_rb_blob, br_url, br_bcw_crawl, br_stored) values ( $1, $2, $3, $4 ) RETURNING br_id",
+        Store::Spar => "insert into sr_spar_raw (sr_rb_blob, sr_url, sr_cs_crawl_session, sr_stored) values ( $1, $2, $3, $4 ) returning sr_id",
+    };
+
+    let document_id: (Uuid,) = sqlx::query_as(query)
+        .bind(hash)
+        .bind(url)
+        .bind(crawl_id)
+        .bind(stored)
+        .fetch_one(conn)
+        .await?;
+
+    Ok(document_id.0)
+}
+
+async fn product_id(conn: &mut PgConnection, category_id: Uuid, product: &Product) -> Result<Uuid> {
+    let select = match product.store {
+        Store::Billa => "SELECT bpo_id FROM bpo_billa_product WHERE bpo_billa_id = $1",
+        Store::Spar => "select sp_id from sp_spar_product where sp_spar_id = $1",
+    };
+
+    let product_id: Option<(Uuid,)> = sqlx::query_as(select)
+        .bind(&product.article_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    if let Some(product_id) = product_id {
+        return Ok(product_id.0);
+    }
+
+    let product_id: Option<(Uuid,)> = match &product.details {
+            Details::Billa {
+                badge,
+                price_factor,
+                ..
+            } => {
+                sqlx::query_as("INSERT INTO bpo_billa_product (bpo_online_shop_url, bpo_billa_id, bpo_name, bpo_description, bpo_brand, bpo_badge, bpo_unit, bpo_price_factor, bpo_grammage, bpo_bc_category) VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT (bpo_billa_id) DO NOTHING RETURNING bpo_id")
+                    .bind(&product.url)
+                    .bind(&product.article_id)
+                    .bind(&product.name)
+                    .bind(&product.description)
+                    .bind(&product.brand)
+                    .bind(badge)
+                    .bind(&product.unit)
+                    .bind(price_factor)
+                    .bind(&product.grammage)
+                    .bind(category_id)
+                    .fetch_optional(&mut *conn)
+                    .await?
+            }
+            Details::Spar { .. } => {
+                sqlx::query_as("insert into sp_spar_product (sp_spar_id, sp_description, sp_online_shop_url, sp_name, sp_brand, sp_sc_category) values ( $1, $2, $3, $4, $5, $6 ) on conflict (sp_spar_id) do nothing returning sp_id")
+                    .bind(&product.article_id)
+                    .bind(&product.description)
+                    .bind(&product.url)
+                    .bind(&product.name)
+                    .bind(&product.brand)
+                    .bind(category_id)
+                    .fetch_optional(&mut *conn)
+                    .await?
+            }
+        };
+    let product_id = match product_id {
+        Some(product_id) => product_id,
+        // added by a concurrent crawl of another category in the meantime
+        None => {
+            sqlx::query_as(select)
+                .bind(&product.article_id)
+                .fetch_one(&mut *conn)
+                .await?
+        }
+    };
+
+    Ok(product_id.0)
+}
+
+async fn insert_prices(conn: &mut PgConnection, prices: &[(Uuid, Uuid, Product)]) -> Result<()> {
+    // a product can show up several times in a batch, only its last price is saved
+    let prices = prices
+        .iter()
+        .map(|price| (price.0, price))
+        .collect::<HashMap<_, _>>();
+
+    let mut billa = BillaPrices::default();
+    let mut spar = SparPrices::default();
+    for (product_id, document_id, product) in prices.into_values() {
+        match &product.details {
+            Details::Billa {
+                price_unit,
+                discounted,
+                ..
+            } => {
+                billa.products.push(*product_id);
+                billa.documents.push(*document_id);
+                billa.normal.push(product.price);
+                billa.unit.push(price_unit.clone());
+                billa.discounted.push(*discounted);
+            }
+            Details::Spar { price_per_unit } => {
+                spar.products.push(*product_id);
+                spar.documents.push(*document_id);
+                spar.price.push(product.price);
+                spar.sales_unit.push(product.grammage.clone());
+                spar.price_unit.push(price_per_unit.clone());
+            }
+        }
+    }
+
+    if !billa.products.is_empty() {
+        lock_prices(conn, Store::Billa).await?;
+        for query in BILLA_PRICE_QUERIES {
+            sqlx::query(query)
+                .bind(&billa.products)
+                .bind(&billa.documents)
+                .bind(&billa.normal)
+                .bind(&billa.unit)
+                .bind(&billa.discounted)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+
+    if !spar.products.is_empty() {
+        lock_prices(conn, Store::Spar).await?;
+        for query in SPAR_PRICE_QUERIES {
+            sqlx::query(query)
+                .bind(&spar.products)
+                .bind(&spar.documents)
+                .bind(&spar.price)
+                .bind(&spar.sales_unit)
+                .bind(&spar.price_unit)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the products of the document `document_id` and their prices, run it in the transaction
+/// which saves the document, so a document is never left without its prices.
+pub(crate) async fn store_products(
+    conn: &mut PgConnection,
+    category_id: Uuid,
+    document_id: Uuid,
+    products: &[Product],
+) -> Result<()> {
+    let mut prices = Vec::with_capacity(products.len());
+    for product in products {
+        let product_id = product_id(conn, category_id, product).await?;
+
+        prices.push((product_id, document_id, product.clone()));
+    }
+
+    insert_prices(conn, &prices).await
+}
+
+/// Keeps concurrent transactions from saving prices of `store` until the transaction ends. The
+/// price tables are partitioned, so nothing but this keeps two of them from opening an interval of
+/// the same product.
+async fn lock_prices(conn: &mut PgConnection, store: Store) -> Result<()> {
+    sqlx::query("select pg_advisory_xact_lock(hashtextextended($1, 0))")
+        .bind(format!("austria_online_grocery_store prices {}", store))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// How often a crawl waiting for a store checks if its crawl session in the queue finished.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+fn lock_key(store: Store) -> String {
+    format!("austria_online_grocery_store crawl {}", store)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::query::products;
+    use crate::test_support::{crawl_billa, mock_billa, TestDatabase};
+
+    #[tokio::test]
+    async fn unchanged_prices_extend_their_interval() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+
+        let mut crawls = Vec::new();
+        for _ in 0..2 {
+            let crawl_id = crawl_billa(&db.storage, &server).await;
+            crawls.push(crawl_id);
+        }
+
+        let open: Vec<(Uuid,)> = sqlx::query_as(
+            "select bp_last_bcw_crawl from bp_billa_price where bp_valid_to is null",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(open.len(), 7);
+        assert!(open.iter().all(|(crawl,)| *crawl == crawls[1]));
+
+        // the toast gets more expensive in the third crawl
+        let mut toast = products(&db.pool, Store::Billa, crawls[1])
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|product| product.article_id == "00-384201")
+            .unwrap();
+        toast.price = dec!(2.99);
+        let crawl_id = db.storage.create_crawl().await.unwrap();
+        let document_id = db
+            .storage
+            .save_document(Store::Billa, crawl_id, "toast", "{}")
+            .await
+            .unwrap();
+        let product_id = db
+            .storage
+            .get_or_add_product(Uuid::nil(), &toast)
+            .await
+            .unwrap();
+        db.storage
+            .save_prices(&[(product_id, document_id, toast)])
+            .await
+            .unwrap();
+
+        let intervals: Vec<(Decimal, bool)> = sqlx::query_as(
+            "select bp_normal, bp_valid_to is null from bp_billa_price where bp_bpo_product = $1 order by bp_valid_from",
+        )
+        .bind(product_id)
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(intervals, vec![(dec!(2.79), false), (dec!(2.99), true)]);
+
+        for (crawl_id, count) in [(crawls[0], 7), (crawls[1], 7), (crawl_id, 1)] {
+            let billa = products(&db.pool, Store::Billa, crawl_id).await.unwrap();
+            assert_eq!(billa.len(), count);
+        }
+        let billa = products(&db.pool, Store::Billa, crawl_id).await.unwrap();
+        assert_eq!(billa[0].price, dec!(2.99));
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn complete_crawl_tracks_listings() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        let crawl = || crawl_billa(&db.storage, &server);
+
+        let first = crawl().await;
+        let changes = db
+            .storage
+            .complete_crawl(Store::Billa, first)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(changes.added.len(), 7);
+        assert!(changes.removed.is_empty());
+
+        // the toast isn't part of the second crawl
+        let second = db.storage.create_crawl().await.unwrap();
+        let document_id = db
+            .storage
+            .save_document(Store::Billa, second, "all", "{}")
+            .await
+            .unwrap();
+        let mut prices = Vec::new();
+        for product in products(&db.pool, Store::Billa, first).await.unwrap() {
+            if product.article_id != "00-384201" {
+                let product_id = db
+                    .storage
+                    .get_or_add_product(Uuid::nil(), &product)
+                    .await
+                    .unwrap();
+                prices.push((product_id, document_id, product));
+            }
+        }
+        db.storage.save_prices(&prices).await.unwrap();
+        let changes = db
+            .storage
+            .complete_crawl(Store::Billa, second)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changes.added.is_empty());
+        assert_eq!(
+            changes.removed,
+            [Listing {
+                article_id: "00-384201".to_string(),
+                name: "Ölz Toastbrot".to_string()
+            }]
+        );
+
+        let third = crawl().await;
+        let changes = db
+            .storage
+            .complete_crawl(Store::Billa, third)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert_eq!(changes.relisted.len(), 1);
+
+        let delisted: (i64,) =
+            sqlx::query_as("select count(*) from bpo_billa_product where bpo_delisted is not null")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(delisted.0, 0);
+        for (crawl_id, count) in [(first, 7), (second, 6), (third, 7)] {
+            let billa = products(&db.pool, Store::Billa, crawl_id).await.unwrap();
+            assert_eq!(billa.len(), count);
+        }
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn pages_are_saved_with_their_prices_or_not_at_all() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+
+        let first = crawl_billa(&db.storage, &server).await;
+        let mut page = products(&db.pool, Store::Billa, first).await.unwrap();
+        let category_id = db
+            .storage
+            .get_or_add_category(Store::Billa, "Bread")
+            .await
+            .unwrap();
+
+        let second = db.storage.create_crawl().await.unwrap();
+        page[0].price = dec!(9.99);
+        page[1].article_id = "00-too-long-for-the-column".to_string();
+        assert!(db
+            .storage
+            .save_page(Store::Billa, second, "page", "{}", category_id, &page)
+            .await
+            .is_err());
+
+        let documents: (i64,) =
+            sqlx::query_as("select count(*) from br_billa_raw where br_bcw_crawl = $1")
+                .bind(second)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(documents.0, 0);
+        let prices: (i64,) = sqlx::query_as("select count(*) from bp_billa_price")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(prices.0, 7);
+
+        page.truncate(1);
+        let document_id = db
+            .storage
+            .save_page(Store::Billa, second, "page", "{}", category_id, &page)
+            .await
+            .unwrap();
+        let stored: (bool, i64) = sqlx::query_as(
+            "select br_stored, count(bp_id) from br_billa_raw left join bp_billa_price on bp_br_raw = br_id where br_id = $1 group by br_stored",
+        )
+        .bind(document_id)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(stored, (true, 1));
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn stores_are_locked_between_sessions() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+
+        let billa = db
+            .storage
+            .lock_store(Store::Billa, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db
+            .storage
+            .lock_store(Store::Billa, false)
+            .await
+            .unwrap()
+            .is_none());
+
+        let spar = db.storage.lock_store(Store::Spar, false).await.unwrap();
+        assert!(spar.is_some());
+
+        let storage = db.storage.clone();
+        let waiting = tokio::spawn(async move { storage.lock_store(Store::Billa, true).await });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!waiting.is_finished());
+
+        billa.release().await.unwrap();
+        let billa = waiting.await.unwrap().unwrap().unwrap();
+        billa.release().await.unwrap();
+
+        let billa = db.storage.lock_store(Store::Billa, false).await.unwrap();
+        assert!(billa.is_some());
+
+        drop(billa);
+        drop(spar);
+        db.close().await;
+    }
+}
@@ -0,0 +1,342 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::types::Uuid;
+use sqlx::{Executor, QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+
+use super::Storage;
+use crate::model::{JournalEntry, Listing, ListingChanges, Product, Store};
+
+/// sqlite allows at most 32766 bind parameters per statement
+const PRICE_INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Products of store `$2` with a price in crawl `$1`.
+const SEEN_IN_CRAWL: &str = "select product_id from price join document on document_id = document.id where crawl_id = $1 and store = $2";
+
+/// Opens the sqlite database at `url`, e.g. `sqlite://crawl.db`, and creates the tables if
+/// they don't exist yet.
+pub async fn connect(url: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    let pool = SqlitePoolOptions::new().connect_with(options).await?;
+
+    pool.execute(include_str!("../../ddl.sqlite.sql")).await?;
+
+    Ok(pool)
+}
+
+impl Storage for SqlitePool {
+    async fn create_crawl(&self) -> Result<Uuid> {
+        let crawl_id = Uuid::new_v4();
+
+        sqlx::query("insert into crawl (id) values ( $1 )")
+            .bind(crawl_id)
+            .execute(self)
+            .await?;
+
+        Ok(crawl_id)
+    }
+
+    async fn save_document(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+    ) -> Result<Uuid> {
+        insert_document(&mut *self.acquire().await?, store, crawl_id, url, body).await
+    }
+
+    async fn save_error(&self, store: Store, crawl_id: Uuid, url: &str, error: &str) -> Result<()> {
+        sqlx::query(
+            "insert into document (id, store, crawl_id, url, error) values ( $1, $2, $3, $4, $5 )",
+        )
+        .bind(Uuid::new_v4())
+        .bind(store.to_string())
+        .bind(crawl_id)
+        .bind(url)
+        .bind(error)
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn save_request(&self, crawl_id: Uuid, entry: &JournalEntry) -> Result<()> {
+        sqlx::query(
+            "insert into request (crawl_id, store, url, status, headers, duration_ms, bytes, attempt, user_agent, error) values ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10 )",
+        )
+        .bind(crawl_id)
+        .bind(entry.store.to_string())
+        .bind(&entry.url)
+        .bind(entry.status)
+        .bind(serde_json::to_string(&entry.headers)?)
+        .bind(entry.duration_ms as i64)
+        .bind(entry.bytes as i64)
+        .bind(entry.attempt)
+        .bind(entry.user_agent.as_str())
+        .bind(&entry.error)
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_or_add_category(&self, store: Store, name: &str) -> Result<Uuid> {
+        sqlx::query(
+            "insert into category (id, store, name) values ( $1, $2, $3 ) on conflict do nothing",
+        )
+        .bind(Uuid::new_v4())
+        .bind(store.to_string())
+        .bind(name)
+        .execute(self)
+        .await?;
+
+        let id: (Uuid,) = sqlx::query_as("select id from category where store = $1 and name = $2")
+            .bind(store.to_string())
+            .bind(name)
+            .fetch_one(self)
+            .await?;
+
+        Ok(id.0)
+    }
+
+    async fn get_or_add_product(&self, category_id: Uuid, product: &Product) -> Result<Uuid> {
+        product_id(&mut *self.acquire().await?, category_id, product).await
+    }
+
+    async fn save_prices(&self, prices: &[(Uuid, Uuid, Product)]) -> Result<()> {
+        insert_prices(&mut *self.acquire().await?, prices).await
+    }
+
+    async fn save_page(
+        &self,
+        store: Store,
+        crawl_id: Uuid,
+        url: &str,
+        body: &str,
+        category_id: Uuid,
+        products: &[Product],
+    ) -> Result<Uuid> {
+        let mut tx = self.begin().await?;
+        let document_id = insert_document(&mut tx, store, crawl_id, url, body).await?;
+
+        let mut prices = Vec::with_capacity(products.len());
+        for product in products {
+            let product_id = product_id(&mut tx, category_id, product).await?;
+
+            prices.push((product_id, document_id, product.clone()));
+        }
+        insert_prices(&mut tx, &prices).await?;
+        tx.commit().await?;
+
+        Ok(document_id)
+    }
+
+    async fn complete_crawl(&self, store: Store, crawl_id: Uuid) -> Result<Option<ListingChanges>> {
+        let mut tx = self.begin().await?;
+
+        let mut relisted: Vec<Listing> = sqlx::query_as(&format!(
+            "update product set delisted = null, relisted = (select created from crawl where id = $1)
+            where store = $2 and delisted is not null and id in ({SEEN_IN_CRAWL})
+            returning article_id, name"
+        ))
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .fetch_all(&mut tx)
+        .await?;
+        let mut removed: Vec<Listing> = sqlx::query_as(&format!(
+            "update product set delisted = (select created from crawl where id = $1)
+            where store = $2 and delisted is null and id not in ({SEEN_IN_CRAWL})
+            returning article_id, name"
+        ))
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .fetch_all(&mut tx)
+        .await?;
+        let mut added: Vec<Listing> = sqlx::query_as(&format!(
+            "select article_id, name from product
+            where store = $2 and id in ({SEEN_IN_CRAWL})
+                and id not in (select product_id from price join document on document_id = document.id where crawl_id <> $1)"
+        ))
+        .bind(crawl_id)
+        .bind(store.to_string())
+        .fetch_all(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        for listings in [&mut added, &mut removed, &mut relisted] {
+            listings.sort_by(|a, b| a.article_id.cmp(&b.article_id));
+        }
+
+        Ok(Some(ListingChanges {
+            added,
+            removed,
+            relisted,
+        }))
+    }
+}
+
+async fn insert_document(
+    conn: &mut SqliteConnection,
+    store: Store,
+    crawl_id: Uuid,
+    url: &str,
+    body: &str,
+) -> Result<Uuid> {
+    let document_id = Uuid::new_v4();
+
+    sqlx::query(
+        "insert into document (id, store, crawl_id, url, body) values ( $1, $2, $3, $4, $5 )",
+    )
+    .bind(document_id)
+    .bind(store.to_string())
+    .bind(crawl_id)
+    .bind(url)
+    .bind(body)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(document_id)
+}
+
+async fn product_id(
+    conn: &mut SqliteConnection,
+    category_id: Uuid,
+    product: &Product,
+) -> Result<Uuid> {
+    sqlx::query("insert into product (id, store, article_id, category_id, name, description, brand, url, grammage, unit, details) values ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11 ) on conflict do nothing")
+        .bind(Uuid::new_v4())
+        .bind(product.store.to_string())
+        .bind(&product.article_id)
+        .bind(category_id)
+        .bind(&product.name)
+        .bind(&product.description)
+        .bind(&product.brand)
+        .bind(&product.url)
+        .bind(&product.grammage)
+        .bind(&product.unit)
+        .bind(serde_json::to_string(&product.details)?)
+        .execute(&mut *conn)
+        .await?;
+
+    let id: (Uuid,) = sqlx::query_as("select id from product where store = $1 and article_id = $2")
+        .bind(product.store.to_string())
+        .bind(&product.article_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(id.0)
+}
+
+async fn insert_prices(
+    conn: &mut SqliteConnection,
+    prices: &[(Uuid, Uuid, Product)],
+) -> Result<()> {
+    for chunk in prices.chunks(PRICE_INSERT_CHUNK_SIZE) {
+        let details = chunk
+            .iter()
+            .map(|(_, _, product)| serde_json::to_string(&product.details))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "insert into price (product_id, document_id, price, unit_price, details)",
+        );
+
+        query_builder.push_values(
+            chunk.iter().zip(details),
+            |mut b, ((product_id, document_id, product), details)| {
+                b.push_bind(*product_id);
+                b.push_bind(*document_id);
+                // sqlite has no decimal type, the exact value is kept as text
+                b.push_bind(product.price.to_string());
+                b.push_bind(product.unit_price.map(|price| price.to_string()));
+                b.push_bind(details);
+            },
+        );
+
+        query_builder.build().execute(&mut *conn).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::stores::spar::SparCrawl;
+    use crate::stores::ExecuteCrawler;
+    use crate::test_support::mock_store;
+
+    #[tokio::test]
+    async fn execute_stores_products_and_prices() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = mock_store(
+            "spar",
+            "/fact-finder/rest/v4/search/products_lmos_at",
+            "filter",
+            "category-path:",
+        )
+        .await;
+
+        let pool = connect(&format!(
+            "sqlite://{}",
+            dir.path().join("crawl.db").display()
+        ))
+        .await
+        .unwrap();
+        let crawl_id = pool.create_crawl().await.unwrap();
+        SparCrawl::execute(
+            &pool,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &server.uri(),
+        )
+        .await
+        .unwrap();
+
+        let prices: Vec<(String, String, String, Option<String>)> = sqlx::query_as(
+            "select article_id, category.name, price, unit_price from price join product on product_id = product.id join category on category_id = category.id order by article_id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(prices.len(), 3);
+        assert_eq!(
+            prices[0],
+            (
+                "2020001890507".to_string(),
+                "Vegetables".to_string(),
+                "1.99".to_string(),
+                Some("1.99".to_string())
+            )
+        );
+
+        let documents: (i64,) =
+            sqlx::query_as("select count(*) from document where crawl_id = $1 and store = 'spar'")
+                .bind(crawl_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(documents.0, 15);
+
+        let changes = pool
+            .complete_crawl(Store::Spar, crawl_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(changes.added.len(), 3);
+
+        // nothing is listed anymore in an empty crawl
+        let crawl_id = pool.create_crawl().await.unwrap();
+        let changes = pool
+            .complete_crawl(Store::Spar, crawl_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changes.added.is_empty());
+        assert_eq!(changes.removed.len(), 3);
+    }
+}
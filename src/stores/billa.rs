@@ -1,20 +1,16 @@
-use std::collections::HashMap;
 use std::hash::Hash;
-use std::sync::Arc;
 
 use anyhow::Result;
-use reqwest::Client;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-use sqlx::types::Uuid;
-use sqlx::PgPool;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
-use tokio::sync::Semaphore;
-use tokio::task::JoinSet;
+use tracing::warn;
 
 use super::ExecuteCrawler;
-use crate::utils::random_user_agent;
+use crate::metrics::metrics;
+use crate::model::{self, Store};
 
 #[derive(Debug, Clone, Copy, EnumIter, PartialEq, Eq, Hash)]
 pub enum Category {
@@ -47,14 +43,16 @@ impl Category {
 
 #[derive(Debug)]
 pub struct BillaUrl {
+    base_url: String,
     category: Category,
     page: usize,
     page_size: usize,
 }
 
 impl BillaUrl {
-    pub fn new(category: Category, page: usize) -> Self {
+    pub fn new(base_url: &str, category: Category, page: usize) -> Self {
         BillaUrl {
+            base_url: base_url.trim_end_matches('/').to_string(),
             category,
             page,
             page_size: 40,
@@ -62,7 +60,7 @@ impl BillaUrl {
     }
 
     pub fn as_url(&self) -> String {
-        format!("https://shop.billa.at/api/search/full?category={}&includeSort%5B%5D=rank&page={}&sort=rank&storeId=00-10&pageSize={}", self.category.id(), self.page, self.page_size)
+        format!("{}/api/search/full?category={}&includeSort%5B%5D=rank&page={}&sort=rank&storeId=00-10&pageSize={}", self.base_url, self.category.id(), self.page, self.page_size)
     }
 
     pub fn next_page(&mut self) {
@@ -76,15 +74,15 @@ impl BillaUrl {
 
 #[derive(Debug, serde::Deserialize)]
 pub struct PagingInfo {
-    page: usize,
+    pub page: usize,
     #[serde(rename = "pageSize")]
-    page_size: usize,
+    pub page_size: usize,
     #[serde(rename = "numResults")]
-    numResults: usize,
-    offset: usize,
-    limit: usize,
+    pub num_results: usize,
+    pub offset: usize,
+    pub limit: usize,
     #[serde(rename = "isFirstPage")]
-    is_first_page: bool,
+    pub is_first_page: bool,
     #[serde(rename = "isLastPage")]
     pub is_last_page: bool,
 }
@@ -114,7 +112,7 @@ pub struct Product {
     pub grammage_unit: String,
     #[sqlx(rename = "bpo_price_factor")]
     #[serde(rename = "grammagePriceFactor")]
-    pub grammage_price_factor: f32,
+    pub grammage_price_factor: Decimal,
     #[sqlx(rename = "bpo_grammage")]
     #[serde(rename = "grammage")]
     pub grammage: String,
@@ -130,10 +128,13 @@ impl Hash for Product {
 #[derive(Debug, serde::Deserialize, sqlx::FromRow)]
 pub struct Price {
     #[sqlx(rename = "bp_normal")]
-    pub normal: f32,
+    pub normal: Decimal,
     #[sqlx(rename = "bp_unit")]
     #[serde(deserialize_with = "deserialize_null_default")]
     pub unit: String,
+    #[sqlx(rename = "bp_discounted")]
+    #[serde(rename = "isDiscounted", default)]
+    pub discounted: bool,
 }
 
 fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
@@ -152,220 +153,322 @@ impl ExecuteCrawler for BillaCrawl {
     type Category = self::Category;
     type Product = self::Product;
 
-    async fn get_or_add_categories(pool: &PgPool) -> Result<Arc<HashMap<Self::Category, Uuid>>> {
-        let mut category_map = HashMap::new();
-
-        for category in Self::Category::iter() {
-            let category_string = format!("{:?}", category);
-
-            let id: Result<Option<(Uuid,)>, sqlx::Error> =
-                sqlx::query_as("SELECT bc_id FROM bc_billa_category WHERE bc_text = $1")
-                    .bind(&category_string)
-                    .fetch_optional(pool)
-                    .await;
-            let id = match id.unwrap() {
-                Some(id) => id.0,
-                None => {
-                    let id: (Uuid,) = sqlx::query_as(
-                        "INSERT INTO bc_billa_category (bc_text) VALUES ( $1 ) RETURNING bc_id",
-                    )
-                    .bind(&category_string)
-                    .fetch_one(pool)
-                    .await
-                    .unwrap();
-
-                    id.0
-                }
-            };
-
-            category_map.insert(category, id);
-        }
-
-        Ok(Arc::new(category_map))
-    }
-
-    async fn download_category(
-        crawl_id: Uuid,
-        client: Client,
-        pool: &PgPool,
-        category: Self::Category,
-    ) -> Result<Vec<(Self::Product, Uuid)>> {
-        let mut last_page = false;
-
-        let mut billa_url = BillaUrl::new(category.clone(), 1);
-
-        let mut products = Vec::new();
-
-        while !last_page {
-            println!("{:?}: {}", category, billa_url.page());
-
-            let url = billa_url.as_url();
-            let res = client.get(&url).send().await?;
-
-            if res.status() == 200 {
-                let text = res.text().await?;
-                let body: Value = serde_json::from_str(&text)?;
-
-                let document_id: (Uuid,) = sqlx::query_as(
-                "insert into br_billa_raw (br_raw, br_url, br_bcw_crawl) values ( $1, $2, $3 ) RETURNING br_id",
-                    )
-                    .bind(text)
-                    .bind(url)
-                    .bind(crawl_id)
-                    .fetch_one(pool)
-                    .await?;
-
-                let paging_info: PagingInfo = serde_json::from_value(body["pagingInfo"].clone())?;
-
-                let products_raw = body["tiles"].clone();
-                let arr = products_raw
-                    .as_array()
-                    .map(|items| {
-                        items
-                            .iter()
-                            .map(|item| serde_json::from_value(item["data"].clone()))
-                            .map(|item| item.ok())
-                            .filter(|item| item.is_some())
-                            .map(|item| (item.unwrap(), document_id.0))
-                            .collect::<Vec<_>>()
-                    })
-                    .unwrap_or_default();
-
-                products.extend(arr);
-
-                billa_url.next_page();
+    const STORE: Store = Store::Billa;
 
-                last_page = paging_info.is_last_page;
-            } else {
-                sqlx::query("insert into br_billa_raw (br_url, br_err) values ( $1, $2 )")
-                    .bind(url)
-                    .bind(format!("{:?}", res.text().await?))
-                    .execute(pool)
-                    .await?;
-            }
-        }
+    const BASE_URL: &'static str = "https://shop.billa.at";
 
-        Ok(products)
+    fn to_model(product: &Self::Product, category: Self::Category) -> model::Product {
+        product.to_model(category)
     }
 
-    async fn insert_products(
-        pool: &PgPool,
-        category_map: Arc<HashMap<Self::Category, Uuid>>,
-        category: Self::Category,
-        products: Vec<(Self::Product, Uuid)>,
-    ) -> Result<()> {
-        for (product, document_id) in products {
-            // TODO get bpo_id as option
-            let count: (i64,) =
-                sqlx::query_as("SELECT COUNT(*) FROM bpo_billa_product WHERE bpo_billa_id = $1")
-                    .bind(&product.billa_id)
-                    .fetch_one(pool)
-                    .await?;
-            let count_already_in_db = count.0 != 0;
-
-            let product_id = if count_already_in_db {
-                let product_id: (Uuid,) =
-                    sqlx::query_as("SELECT bpo_id FROM bpo_billa_product WHERE bpo_billa_id = $1")
-                        .bind(&product.billa_id)
-                        .fetch_one(pool)
-                        .await?;
-
-                product_id.0
-            } else {
-                // TODO add category into db and link with it
-                let product_id: (Uuid,) = sqlx::query_as("INSERT INTO bpo_billa_product (bpo_online_shop_url, bpo_billa_id, bpo_name, bpo_description, bpo_brand, bpo_badge, bpo_unit, bpo_price_factor, bpo_grammage, bpo_bc_category) VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING bpo_id")
-                    .bind(product.online_shop_url)
-                    .bind(product.billa_id)
-                    .bind(&product.name)
-                    .bind(product.description)
-                    .bind(product.brand)
-                    .bind(product.grammage_badge)
-                    .bind(product.grammage_unit)
-                    .bind(product.grammage_price_factor)
-                    .bind(product.grammage)
-                    .bind(category_map.get(&category).unwrap().clone())
-                    .fetch_one(pool).await?;
-
-                product_id.0
-            };
-
-            sqlx::query("INSERT INTO bp_billa_price (bp_bpo_product, bp_br_raw, bp_normal, bp_unit) VALUES ($1, $2, $3, $4)")
-                .bind(product_id)
-                .bind(&document_id)
-                .bind(product.price.normal)
-                .bind(product.price.unit)
-                .execute(pool).await?;
-        }
-
-        Ok(())
+    fn page_url(base_url: &str, category: Self::Category, page: usize) -> String {
+        BillaUrl::new(base_url, category, page).as_url()
     }
 
-    async fn execute(pool: &PgPool, crawl_id: Uuid) -> Result<()> {
-        let client = Client::builder()
-            .user_agent(random_user_agent())
-            .gzip(true)
-            .build()?;
-
-        let category_map = BillaCrawl::get_or_add_categories(&pool).await?;
-
-        let semaphore = Arc::new(Semaphore::new(3));
-        let mut set = JoinSet::new();
-
-        for category in Category::iter() {
-            let semaphore = semaphore.clone();
-            let client = client.clone();
-            let pool = pool.clone();
-            let crawl_id = crawl_id.clone();
-
-            set.spawn(async move {
-                let permit = semaphore.acquire().await.unwrap();
-
-                let products =
-                    BillaCrawl::download_category(crawl_id, client, &pool, category).await;
-
-                drop(permit);
-
-                (products, category)
-            });
-        }
-
-        let mut products_lists = Vec::new();
-
-        while let Some(res) = set.join_next().await {
-            match res {
-                Ok((Ok(products), category)) => {
-                    products_lists.push((category, products));
-                }
-                err => println!("err: {:?}", err),
-            }
-        }
-
-        println!("start product inserts");
-
-        let semaphore = Arc::new(Semaphore::new(20));
-        let mut set = JoinSet::new();
+    fn category_of_url(url: &str) -> Option<Self::Category> {
+        Category::iter().find(|category| url.contains(&format!("category={}&", category.id())))
+    }
 
-        for (category, products) in products_lists {
-            let semaphore = semaphore.clone();
-            let pool = pool.clone();
-            let category_map = category_map.clone();
+    fn parse_page(body: &str) -> Result<(Vec<Self::Product>, bool)> {
+        let body: Value = serde_json::from_str(body)?;
+
+        let paging_info: PagingInfo = serde_json::from_value(body["pagingInfo"].clone())?;
+
+        let products_raw = body["tiles"].clone();
+        let products = products_raw
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .map(|item| serde_json::from_value(item["data"].clone()))
+                    .inspect(|item| {
+                        if let Err(err) = item {
+                            warn!(%err, "unparsable product");
+                            metrics()
+                                .parse_failures
+                                .with_label_values(&[&Self::STORE.to_string()])
+                                .inc();
+                        }
+                    })
+                    .filter_map(|item| item.ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
 
-            set.spawn(async move {
-                let permit = semaphore.acquire().await.unwrap();
+        Ok((products, paging_info.is_last_page))
+    }
+}
 
-                let status =
-                    BillaCrawl::insert_products(&pool, category_map, category, products).await;
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rust_decimal_macros::dec;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::model::CategoryProgress;
+    use crate::shutdown::Shutdown;
+    use crate::storage::Storage;
+    use crate::stores::pagination::PaginationError;
+    use crate::test_support::{crawl_billa, fixture, mock_billa, mock_slow_store, TestDatabase};
+
+    #[tokio::test]
+    async fn download_category_follows_all_pages() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        let crawl_id = db.crawl_id().await;
+
+        let products = BillaCrawl::download_category(
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &db.storage,
+            &server.uri(),
+            Category::Vegetables,
+        )
+        .await
+        .unwrap();
+
+        let ids = products
+            .iter()
+            .map(|(product, _)| product.billa_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "00-449237",
+                "00-421350",
+                "00-426071",
+                "00-441530",
+                "00-510036"
+            ]
+        );
+
+        let raw: Vec<(String,)> = sqlx::query_as(
+            "select br_url from br_billa_raw where br_bcw_crawl = $1 and br_err is null order by br_created",
+        )
+        .bind(crawl_id)
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(raw.len(), 2);
+        assert_eq!(
+            raw[0].0,
+            BillaUrl::new(&server.uri(), Category::Vegetables, 1).as_url()
+        );
+
+        db.close().await;
+    }
 
-                drop(permit);
+    #[tokio::test]
+    async fn download_category_replays_recorded_crawl() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let server = mock_billa().await;
+        let base_url = server.uri();
+
+        let recorded = BillaCrawl::download_category(
+            db.crawl_id().await,
+            Fetcher::new(Mode::Record(dir.path().to_path_buf())).unwrap(),
+            &db.storage,
+            &base_url,
+            Category::Vegetables,
+        )
+        .await
+        .unwrap();
+
+        drop(server);
+
+        let replayed = BillaCrawl::download_category(
+            db.crawl_id().await,
+            Fetcher::new(Mode::Replay(dir.path().to_path_buf())).unwrap(),
+            &db.storage,
+            &base_url,
+            Category::Vegetables,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(recorded.len(), 5);
+        assert_eq!(
+            recorded
+                .iter()
+                .map(|(product, _)| &product.billa_id)
+                .collect::<Vec<_>>(),
+            replayed
+                .iter()
+                .map(|(product, _)| &product.billa_id)
+                .collect::<Vec<_>>()
+        );
+
+        db.close().await;
+    }
 
-                status
-            });
-        }
+    #[tokio::test]
+    async fn download_category_stops_on_repeated_page() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(fixture("billa", "B2-1_1.json")),
+            )
+            .mount(&server)
+            .await;
+        let crawl_id = db.crawl_id().await;
+
+        let err = BillaCrawl::download_category(
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &db.storage,
+            &server.uri(),
+            Category::Vegetables,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PaginationError>(),
+            Some(PaginationError::RepeatedPage(2))
+        ));
+
+        let errors: (i64,) = sqlx::query_as(
+            "select count(*) from br_billa_raw where br_bcw_crawl = $1 and br_err is not null",
+        )
+        .bind(crawl_id)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(errors.0, 1);
+
+        db.close().await;
+    }
 
-        while let Some(res) = set.join_next().await {
-            let _ = res;
-        }
+    #[tokio::test]
+    async fn execute_stores_products_and_prices() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+        crawl_billa(&db.storage, &server).await;
+
+        let products: Vec<(String, String, String)> = sqlx::query_as(
+            "select bpo_billa_id, bpo_name, bc_text from bpo_billa_product join bc_billa_category on bpo_bc_category = bc_id order by bpo_billa_id",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(products.len(), 7);
+        assert_eq!(
+            products[0],
+            (
+                "00-384201".to_string(),
+                "Ölz Toastbrot".to_string(),
+                "Bread".to_string()
+            )
+        );
+
+        let prices: Vec<(String, Decimal, String)> = sqlx::query_as(
+            "select bpo_billa_id, bp_normal, bp_unit from bp_billa_price join bpo_billa_product on bp_bpo_product = bpo_id order by bpo_billa_id",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(prices.len(), 7);
+        assert_eq!(
+            prices[1],
+            ("00-421350".to_string(), dec!(2.29), "kg".to_string())
+        );
+        assert_eq!(
+            prices[2],
+            ("00-426071".to_string(), dec!(2.49), "".to_string())
+        );
+
+        db.close().await;
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn crawl_resumes_after_shutdown() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_slow_store(
+            "billa",
+            "/api/search/full",
+            "category",
+            "",
+            Duration::from_millis(500),
+        )
+        .await;
+        let crawl_id = db.crawl_id().await;
+
+        // three categories are downloading their first page when the shutdown is requested
+        let shutdown = Shutdown::new();
+        let requested = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            requested.request();
+        });
+
+        let interrupted = BillaCrawl::crawl(
+            &db.storage,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &server.uri(),
+            &shutdown,
+        )
+        .await
+        .unwrap();
+        assert!(interrupted.interrupted);
+        assert!(!interrupted.complete);
+        assert_eq!(interrupted.pages, 3);
+
+        let progress = db.storage.progress(Store::Billa, crawl_id).await.unwrap();
+        assert_eq!(progress.len(), 9);
+        assert_eq!(
+            progress
+                .values()
+                .filter(|progress| **progress == CategoryProgress::NextPage(1))
+                .count(),
+            6
+        );
+
+        let resumed = BillaCrawl::crawl(
+            &db.storage,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &server.uri(),
+            &Shutdown::new(),
+        )
+        .await
+        .unwrap();
+        assert!(resumed.complete);
+        assert!(!resumed.interrupted);
+        assert_eq!(interrupted.pages + resumed.pages, 10);
+        assert_eq!(interrupted.products + resumed.products, 7);
+
+        // no page was downloaded twice
+        let pages: (i64,) = sqlx::query_as(
+            "select count(*) from br_billa_raw where br_bcw_crawl = $1 and br_err is null",
+        )
+        .bind(crawl_id)
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(pages.0, 10);
+
+        let prices: (i64,) = sqlx::query_as("select count(*) from bp_billa_price")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(prices.0, 7);
+
+        db.close().await;
     }
 }
@@ -1,36 +1,418 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use reqwest::Client;
 use sqlx::types::Uuid;
-use sqlx::PgPool;
 use strum::IntoEnumIterator;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Instant};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+use crate::http::Fetcher;
+use crate::metrics::metrics;
+use crate::model::{CategoryProgress, Product, Store};
+use crate::shutdown::Shutdown;
+use crate::storage::Storage;
 
 pub mod billa;
+pub mod pagination;
 pub mod spar;
 
-pub trait ExecuteCrawler: Debug {
-    type Category: Send + Sync + IntoEnumIterator + Debug;
-    type Product: Send + Sync + Debug;
+use pagination::{with_category_timeout, PaginationError, PaginationGuard};
+
+/// The products of a downloaded page, each with the id of the document it's from.
+#[derive(Debug)]
+pub struct DownloadedPage<P> {
+    pub products: Vec<(P, Uuid)>,
+    /// If the shop has no further pages of the category.
+    pub last: bool,
+}
+
+/// What the shop answered to [`ExecuteCrawler::download_page`].
+#[derive(Debug)]
+pub enum PageDownload<P> {
+    Downloaded(DownloadedPage<P>),
+    /// An unexpected status, the page can be requested again after the delay the shop asked for
+    /// in its `Retry-After` header, if any.
+    Retry(Option<Duration>),
+}
+
+/// The pages of a category downloaded by [`ExecuteCrawler::download_pages`].
+#[derive(Debug)]
+pub struct CategoryDownload<P> {
+    pub products: Vec<(P, Uuid)>,
+    pub pages: usize,
+    pub progress: CategoryProgress,
+}
+
+/// What the crawl of a store did, see [`ExecuteCrawler::crawl`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreOutcome {
+    /// If every category was downloaded and stored.
+    pub complete: bool,
+    /// If a shutdown stopped the crawl before every category was downloaded.
+    pub interrupted: bool,
+    pub pages: usize,
+    pub products: usize,
+}
+
+/// Crawls every category of a store and stores the products with their current prices.
+pub trait ExecuteCrawler: Debug + 'static {
+    type Category: Send + Sync + IntoEnumIterator + Debug + Copy + Eq + Hash + 'static;
+    type Product: Send + Sync + Debug + 'static;
+
+    const STORE: Store;
+
+    /// Origin of the shop API, can be replaced with a local server.
+    const BASE_URL: &'static str;
+
+    fn to_model(product: &Self::Product, category: Self::Category) -> Product;
+
+    fn get_or_add_categories<S: Storage>(
+        storage: &S,
+    ) -> impl Future<Output = Result<Arc<HashMap<Self::Category, Uuid>>>> + Send {
+        async move {
+            let mut category_map = HashMap::new();
+
+            // the iterator of the enum isn't guaranteed to be `Send`
+            let categories = Self::Category::iter().collect::<Vec<_>>();
+
+            for category in categories {
+                let category_string = format!("{:?}", category);
+
+                let id = storage
+                    .get_or_add_category(Self::STORE, &category_string)
+                    .await?;
+
+                category_map.insert(category, id);
+            }
+
+            Ok(Arc::new(category_map))
+        }
+    }
+
+    /// Url of a page of the category, the first page is 1.
+    fn page_url(base_url: &str, category: Self::Category, page: usize) -> String;
+
+    /// Category of a page from its url, see [`ExecuteCrawler::page_url`].
+    fn category_of_url(url: &str) -> Option<Self::Category>;
+
+    /// The products of a downloaded page and if it's the last page of its category.
+    fn parse_page(body: &str) -> Result<(Vec<Self::Product>, bool)>;
+
+    /// Downloads a page of the category and saves it as document together with its products
+    /// and their prices, the request is journaled as the `attempt`th one of the page.
+    ///
+    /// Returns [`PageDownload::Retry`] if the shop answered with an unexpected status, the page
+    /// can be requested again.
+    #[allow(clippy::too_many_arguments)]
+    fn download_page<S: Storage>(
+        crawl_id: Uuid,
+        fetcher: &Fetcher,
+        storage: &S,
+        base_url: &str,
+        category: Self::Category,
+        category_id: Uuid,
+        page: usize,
+        attempt: u32,
+        guard: &mut PaginationGuard,
+    ) -> impl Future<Output = Result<PageDownload<Self::Product>>> + Send {
+        async move {
+            let url = Self::page_url(base_url, category, page);
+            let span = info_span!(
+                "page",
+                page,
+                url = %url,
+                status = field::Empty,
+                product_count = field::Empty,
+            );
+
+            async {
+                let started = Instant::now();
+                let res = fetcher.get(&url).await;
+                let duration = started.elapsed();
+                metrics().observe_request(
+                    Self::STORE,
+                    res.as_ref().ok().map(|res| res.status),
+                    duration,
+                );
+
+                // the journal is for analysis only, the page is stored anyway
+                let entry = fetcher.journal_entry(Self::STORE, &url, &res, duration, attempt);
+                if let Err(err) = storage.save_request(crawl_id, &entry).await {
+                    warn!(?err, "request not journaled");
+                }
+
+                let res = res?;
+                Span::current().record("status", res.status);
+
+                if res.status != 200 {
+                    warn!("unexpected status");
+                    storage
+                        .save_error(Self::STORE, crawl_id, &url, &format!("{:?}", res.body))
+                        .await?;
+
+                    return Ok(PageDownload::Retry(res.retry_after()));
+                }
+
+                let text = res.body;
+                guard.check_page(&text)?;
+
+                let (products, last) = Self::parse_page(&text)?;
+
+                Span::current().record("product_count", products.len());
+                metrics().observe_page(Self::STORE, &format!("{:?}", category), products.len());
 
-    async fn get_or_add_categories(pool: &PgPool) -> Result<Arc<HashMap<Self::Category, Uuid>>>;
+                let models = products
+                    .iter()
+                    .map(|product| Self::to_model(product, category))
+                    .collect::<Vec<_>>();
 
-    async fn download_category(
+                let started = Instant::now();
+                let document_id = storage
+                    .save_page(Self::STORE, crawl_id, &url, &text, category_id, &models)
+                    .await?;
+                metrics()
+                    .insert_duration
+                    .with_label_values(&[&Self::STORE.to_string()])
+                    .observe(started.elapsed().as_secs_f64());
+
+                Ok(PageDownload::Downloaded(DownloadedPage {
+                    products: products
+                        .into_iter()
+                        .map(|product| (product, document_id))
+                        .collect(),
+                    last,
+                }))
+            }
+            .instrument(span)
+            .await
+        }
+    }
+
+    /// Downloads the pages of the category from `first_page` up to the last one, or until
+    /// `shutdown` is requested.
+    fn download_pages<S: Storage>(
         crawl_id: Uuid,
-        client: Client,
-        pool: &PgPool,
+        fetcher: &Fetcher,
+        storage: &S,
+        base_url: &str,
         category: Self::Category,
-    ) -> Result<Vec<(Self::Product, Uuid)>>;
+        first_page: usize,
+        shutdown: &Shutdown,
+    ) -> impl Future<Output = Result<CategoryDownload<Self::Product>>> + Send {
+        async move {
+            let category_id = storage
+                .get_or_add_category(Self::STORE, &format!("{:?}", category))
+                .await?;
+
+            let mut guard = PaginationGuard::default();
+            let mut page = first_page;
+            let mut attempt = 1;
+            let mut pages = 0;
+
+            let mut products = Vec::new();
+
+            let download = async {
+                loop {
+                    if shutdown.is_requested() {
+                        return Ok(CategoryProgress::NextPage(page));
+                    }
+
+                    guard.next_request()?;
+
+                    let downloaded = match Self::download_page(
+                        crawl_id,
+                        fetcher,
+                        storage,
+                        base_url,
+                        category,
+                        category_id,
+                        page,
+                        attempt,
+                        &mut guard,
+                    )
+                    .await?
+                    {
+                        PageDownload::Downloaded(downloaded) => downloaded,
+                        PageDownload::Retry(retry_after) => {
+                            // the next iteration notices a shutdown during the delay
+                            tokio::select! {
+                                _ = sleep(fetcher.retry_delay(attempt, retry_after)) => {}
+                                _ = shutdown.requested() => {}
+                            }
+                            attempt += 1;
+                            continue;
+                        }
+                    };
+
+                    pages += 1;
+                    products.extend(downloaded.products);
+
+                    if downloaded.last {
+                        return Ok(CategoryProgress::Done);
+                    }
 
-    async fn insert_products(
-        pool: &PgPool,
-        category_map: Arc<HashMap<Self::Category, Uuid>>,
+                    page += 1;
+                    attempt = 1;
+                }
+            };
+
+            let progress = match with_category_timeout(download).await {
+                Ok(progress) => progress,
+                Err(err) => {
+                    if let Some(err) = err.downcast_ref::<PaginationError>() {
+                        let url = Self::page_url(base_url, category, page);
+                        storage
+                            .save_error(Self::STORE, crawl_id, &url, &err.to_string())
+                            .await?;
+                    }
+
+                    return Err(err);
+                }
+            };
+
+            Ok(CategoryDownload {
+                products,
+                pages,
+                progress,
+            })
+        }
+    }
+
+    /// Downloads the pages of the category up to the last one.
+    fn download_category<S: Storage>(
+        crawl_id: Uuid,
+        fetcher: Fetcher,
+        storage: &S,
+        base_url: &str,
         category: Self::Category,
-        products: Vec<(Self::Product, Uuid)>,
-    ) -> Result<()>;
+    ) -> impl Future<Output = Result<Vec<(Self::Product, Uuid)>>> + Send {
+        async move {
+            let download = Self::download_pages(
+                crawl_id,
+                &fetcher,
+                storage,
+                base_url,
+                category,
+                1,
+                &Shutdown::new(),
+            )
+            .await?;
+
+            Ok(download.products)
+        }
+    }
+
+    /// Crawls the categories which aren't done yet in `crawl_id`. Once `shutdown` is requested
+    /// no further pages are requested, the downloaded ones are stored already.
+    fn crawl<S: Storage>(
+        storage: &S,
+        crawl_id: Uuid,
+        fetcher: Fetcher,
+        base_url: &str,
+        shutdown: &Shutdown,
+    ) -> impl Future<Output = Result<StoreOutcome>> + Send {
+        async move {
+            let progress = storage.progress(Self::STORE, crawl_id).await?;
+
+            let semaphore = Arc::new(Semaphore::new(3));
+            let mut set = JoinSet::new();
+
+            // the iterator of the enum isn't guaranteed to be `Send`
+            let categories = Self::Category::iter().collect::<Vec<_>>();
+
+            for category in categories {
+                let first_page = match progress.get(&format!("{:?}", category)) {
+                    Some(CategoryProgress::Done) => continue,
+                    Some(CategoryProgress::NextPage(page)) => *page,
+                    None => 1,
+                };
+
+                let semaphore = semaphore.clone();
+                let fetcher = fetcher.clone();
+                let storage = storage.clone();
+                let base_url = base_url.to_string();
+                let shutdown = shutdown.clone();
+
+                set.spawn(
+                    async move {
+                        let permit = semaphore.acquire().await.unwrap();
+
+                        let download = Self::download_pages(
+                            crawl_id, &fetcher, &storage, &base_url, category, first_page,
+                            &shutdown,
+                        )
+                        .await;
+
+                        drop(permit);
+
+                        let download = download?;
+                        storage
+                            .save_progress(
+                                Self::STORE,
+                                crawl_id,
+                                &format!("{:?}", category),
+                                download.progress,
+                            )
+                            .await?;
+
+                        Ok::<_, anyhow::Error>(download)
+                    }
+                    .instrument(info_span!("category", category = ?category)),
+                );
+            }
+
+            let mut outcome = StoreOutcome {
+                complete: true,
+                ..StoreOutcome::default()
+            };
+
+            while let Some(res) = set.join_next().await {
+                match res {
+                    Ok(Ok(download)) => {
+                        outcome.pages += download.pages;
+                        outcome.products += download.products.len();
+
+                        if download.progress != CategoryProgress::Done {
+                            outcome.interrupted = true;
+                            outcome.complete = false;
+                        }
+                    }
+                    err => {
+                        error!(?err, "category failed");
+                        outcome.complete = false;
+                    }
+                }
+            }
+
+            info!(
+                pages = outcome.pages,
+                products = outcome.products,
+                "categories crawled"
+            );
+
+            Ok(outcome)
+        }
+    }
+
+    /// Returns if the crawl is complete, i.e. every category was downloaded and stored.
+    fn execute<S: Storage>(
+        storage: &S,
+        crawl_id: Uuid,
+        fetcher: Fetcher,
+        base_url: &str,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        async move {
+            let outcome =
+                Self::crawl(storage, crawl_id, fetcher, base_url, &Shutdown::new()).await?;
 
-    // TODO implement generic execute function
-    async fn execute(pool: &PgPool, crawl_id: Uuid) -> Result<()>;
+            Ok(outcome.complete)
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+/// Upper bound of requests for a single category, including failed ones.
+pub const MAX_REQUESTS: usize = 500;
+
+/// Upper bound of time spent downloading a single category.
+pub const CATEGORY_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug)]
+pub enum PaginationError {
+    TooManyRequests(usize),
+    RepeatedPage(usize),
+    Timeout(Duration),
+}
+
+impl Display for PaginationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaginationError::TooManyRequests(max) => {
+                write!(
+                    f,
+                    "stopped after {} requests without reaching the last page",
+                    max
+                )
+            }
+            PaginationError::RepeatedPage(page) => {
+                write!(f, "page {} is identical to the previous page", page)
+            }
+            PaginationError::Timeout(duration) => {
+                write!(f, "category not finished after {:?}", duration)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaginationError {}
+
+/// Keeps the paging loops of the crawlers from running forever if a shop never reports its last
+/// page or keeps answering with errors.
+#[derive(Debug)]
+pub struct PaginationGuard {
+    max_requests: usize,
+    requests: usize,
+    pages: usize,
+    last_page: Option<u64>,
+}
+
+impl PaginationGuard {
+    pub fn new(max_requests: usize) -> Self {
+        PaginationGuard {
+            max_requests,
+            requests: 0,
+            pages: 0,
+            last_page: None,
+        }
+    }
+
+    /// Continues the paging of a category at `page`, whose previous page was downloaded by
+    /// another guard, e.g. of another worker, and had the digest `previous_page`.
+    pub fn resume(page: usize, previous_page: Option<u64>) -> Self {
+        PaginationGuard {
+            max_requests: MAX_REQUESTS,
+            requests: page.saturating_sub(1),
+            pages: page.saturating_sub(1),
+            last_page: previous_page,
+        }
+    }
+
+    /// Digest of the last checked page, see [`Self::resume`].
+    pub fn last_page(&self) -> Option<u64> {
+        self.last_page
+    }
+
+    /// Has to be called before every request.
+    pub fn next_request(&mut self) -> Result<(), PaginationError> {
+        if self.requests >= self.max_requests {
+            return Err(PaginationError::TooManyRequests(self.max_requests));
+        }
+
+        self.requests += 1;
+
+        Ok(())
+    }
+
+    /// Has to be called with the body of every successful response.
+    pub fn check_page(&mut self, body: &str) -> Result<(), PaginationError> {
+        // stable across processes, unlike the hashers of std
+        let digest = u64::from_be_bytes(Sha256::digest(body.as_bytes())[..8].try_into().unwrap());
+
+        self.pages += 1;
+
+        if self.last_page == Some(digest) {
+            return Err(PaginationError::RepeatedPage(self.pages));
+        }
+
+        self.last_page = Some(digest);
+
+        Ok(())
+    }
+}
+
+impl Default for PaginationGuard {
+    fn default() -> Self {
+        PaginationGuard::new(MAX_REQUESTS)
+    }
+}
+
+/// Runs the download of a category with [`CATEGORY_TIMEOUT`] as upper bound.
+pub async fn with_category_timeout<T>(download: impl Future<Output = Result<T>>) -> Result<T> {
+    match tokio::time::timeout(CATEGORY_TIMEOUT, download).await {
+        Ok(result) => result,
+        Err(_) => Err(PaginationError::Timeout(CATEGORY_TIMEOUT).into()),
+    }
+}
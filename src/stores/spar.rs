@@ -1,19 +1,13 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-
 use anyhow::Result;
-use reqwest::Client;
+use rust_decimal::Decimal;
 use serde_json::Value;
-use sqlx::types::Uuid;
-use sqlx::{PgPool, Postgres, QueryBuilder};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
-use tokio::sync::Semaphore;
-use tokio::task::JoinSet;
-use tokio::time::Instant;
+use tracing::warn;
 
 use super::ExecuteCrawler;
-use crate::utils::random_user_agent;
+use crate::metrics::metrics;
+use crate::model::{self, Store};
 
 #[derive(Debug, Clone, Copy, EnumIter, PartialEq, Eq, Hash)]
 pub enum Category {
@@ -56,14 +50,16 @@ impl Category {
 
 #[derive(Debug)]
 pub struct SparUrl {
+    base_url: String,
     category: Category,
     page: usize,
     page_size: usize,
 }
 
 impl SparUrl {
-    pub fn new(category: Category, page: usize) -> Self {
+    pub fn new(base_url: &str, category: Category, page: usize) -> Self {
         SparUrl {
+            base_url: base_url.trim_end_matches('/').to_string(),
             category,
             page,
             page_size: 80,
@@ -71,30 +67,34 @@ impl SparUrl {
     }
 
     pub fn as_url(&self) -> String {
-        format!("https://search-spar.spar-ics.com/fact-finder/rest/v4/search/products_lmos_at?query=*&q=*&page={}&hitsPerPage={}&filter=category-path:{}", self.page, self.page_size, self.category.id())
+        format!("{}/fact-finder/rest/v4/search/products_lmos_at?query=*&q=*&page={}&hitsPerPage={}&filter=category-path:{}", self.base_url, self.page, self.page_size, self.category.id())
     }
 
     pub fn next_page(&mut self) {
         self.page += 1;
     }
+
+    pub fn page(&self) -> usize {
+        self.page
+    }
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
 pub struct Product {
-    description: String,
+    pub description: String,
     #[serde(rename = "sales-unit")]
-    sales_unit: String,
+    pub sales_unit: String,
     pub title: String,
     #[serde(rename = "code-internal")]
     pub id_internal: String,
-    price: f32,
-    brand: Vec<String>,
-    url: String,
+    pub price: Decimal,
+    pub brand: Vec<String>,
+    pub url: String,
     pub name: String,
     #[serde(rename = "product-number")]
     pub product_number: String,
     #[serde(rename = "price-per-unit")]
-    price_per_unit: String,
+    pub price_per_unit: String,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -112,224 +112,139 @@ impl ExecuteCrawler for SparCrawl {
     type Category = self::Category;
     type Product = self::Product;
 
-    async fn get_or_add_categories(pool: &PgPool) -> Result<Arc<HashMap<Self::Category, Uuid>>> {
-        let mut category_map = HashMap::new();
-
-        for category in Self::Category::iter() {
-            let category_string = format!("{:?}", category);
+    const STORE: Store = Store::Spar;
 
-            let id: Option<(Uuid,)> =
-                sqlx::query_as("select sc_id from sc_spar_category where sc_text = $1")
-                    .bind(&category_string)
-                    .fetch_optional(pool)
-                    .await?;
-            let id = match id {
-                Some(id) => id.0,
-                None => {
-                    let id: (Uuid,) = sqlx::query_as(
-                        "insert into sc_spar_category (sc_text) values ( $1 ) returning sc_id",
-                    )
-                    .bind(&category_string)
-                    .fetch_one(pool)
-                    .await
-                    .unwrap();
-
-                    id.0
-                }
-            };
-
-            category_map.insert(category, id);
-        }
+    const BASE_URL: &'static str = "https://search-spar.spar-ics.com";
 
-        Ok(Arc::new(category_map))
+    fn to_model(product: &Self::Product, category: Self::Category) -> model::Product {
+        product.to_model(category)
     }
 
-    async fn download_category(
-        crawl_id: Uuid,
-        client: Client,
-        pool: &PgPool,
-        category: Self::Category,
-    ) -> Result<Vec<(Self::Product, Uuid)>> {
-        let mut spar_url = SparUrl::new(category.clone(), 1);
-
-        let mut products = Vec::new();
-
-        loop {
-            let url = spar_url.as_url();
-            let res = client.get(&url).send().await?;
-
-            if res.status() == 200 {
-                let text = res.text().await?;
-
-                let body: Value = serde_json::from_str(&text)?;
+    fn page_url(base_url: &str, category: Self::Category, page: usize) -> String {
+        SparUrl::new(base_url, category, page).as_url()
+    }
 
-                let document_id: (Uuid,) = sqlx::query_as("insert into sr_spar_raw (sr_raw, sr_url, sr_cs_crawl_session) values ( $1, $2, $3 ) returning sr_id")
-                    .bind(text)
-                    .bind(url)
-                    .bind(crawl_id)
-                    .fetch_one(pool).await?;
+    fn category_of_url(url: &str) -> Option<Self::Category> {
+        // the filter is at the end of the url, where `F1` can't match `F13`
+        Category::iter().find(|category| url.ends_with(&format!("category-path:{}", category.id())))
+    }
 
-                let hits_raw = body["hits"].clone();
-                let arr = hits_raw
-                    .as_array()
-                    .map(|items| {
-                        items
-                            .iter()
-                            .map(|item| serde_json::from_value(item["masterValues"].clone()))
-                            .map(|item| item.ok())
-                            .filter(|item| item.is_some())
-                            .map(|item| (item.unwrap(), document_id.0))
-                            .collect::<Vec<_>>()
+    fn parse_page(body: &str) -> Result<(Vec<Self::Product>, bool)> {
+        let body: Value = serde_json::from_str(body)?;
+
+        let hits_raw = body["hits"].clone();
+        let products = hits_raw
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .map(|item| serde_json::from_value(item["masterValues"].clone()))
+                    .inspect(|item| {
+                        if let Err(err) = item {
+                            warn!(%err, "unparsable product");
+                            metrics()
+                                .parse_failures
+                                .with_label_values(&[&Self::STORE.to_string()])
+                                .inc();
+                        }
                     })
-                    .unwrap_or_default();
+                    .filter_map(|item| item.ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
 
-                products.extend(arr);
+        let paging_info: Page = serde_json::from_value(body["paging"].clone())?;
 
-                let paging_info: Page = serde_json::from_value(body["paging"].clone())?;
-                if paging_info.current >= paging_info.count {
-                    break;
-                }
-
-                spar_url.next_page();
-            }
-        }
-
-        Ok(products)
+        Ok((products, paging_info.current >= paging_info.count))
     }
+}
 
-    async fn insert_products(
-        pool: &PgPool,
-        category_map: Arc<HashMap<Self::Category, Uuid>>,
-        category: Self::Category,
-        products: Vec<(Self::Product, Uuid)>,
-    ) -> Result<()> {
-        let mut products_with_id = Vec::with_capacity(products.len());
-
-        for (product, document_id) in products {
-            let product_id: Option<(Uuid,)> =
-                sqlx::query_as("select sp_id from sp_spar_product where sp_spar_id = $1")
-                    .bind(&product.id_internal)
-                    .fetch_optional(pool)
-                    .await?;
-            let product_id = match product_id {
-                Some(product_id) => product_id.0,
-                None => {
-                    let brand_name = product.brand.join(";");
-
-                    let product_id: (Uuid,) = sqlx::query_as("insert into sp_spar_product (sp_spar_id, sp_description, sp_online_shop_url, sp_name, sp_brand, sp_sc_category) values ( $1, $2, $3, $4, $5, $6 ) returning sp_id")
-                        .bind(&product.id_internal)
-                        .bind(&product.description)
-                        .bind(&product.url)
-                        .bind(&product.name)
-                        .bind(brand_name)
-                        .bind(category_map.get(&category).unwrap().clone())
-                        .fetch_one(pool)
-                        .await?;
-
-                    product_id.0
-                }
-            };
-
-            products_with_id.push((product, document_id, product_id));
-        }
-
-        let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("insert into spr_spar_price (spr_price, spr_sales_unit, spr_price_unit, spr_sp_product, spr_sr_raw)");
-
-        query_builder.push_values(
-            products_with_id.iter().take(16),
-            |mut b, (product, document_id, product_id)| {
-                b.push_bind(&product.price);
-                b.push_bind(&product.sales_unit);
-                b.push_bind(&product.price_per_unit);
-                b.push_bind(product_id);
-                b.push_bind(document_id);
-            },
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::test_support::{mock_store, TestDatabase};
+
+    #[tokio::test]
+    async fn execute_stores_products_and_prices() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_store(
+            "spar",
+            "/fact-finder/rest/v4/search/products_lmos_at",
+            "filter",
+            "category-path:",
+        )
+        .await;
+        let crawl_id = db.crawl_id().await;
+
+        SparCrawl::execute(
+            &db.storage,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &server.uri(),
+        )
+        .await
+        .unwrap();
+
+        let products: Vec<(String, String, String, String)> = sqlx::query_as(
+            "select sp_spar_id, sp_name, sp_brand, sc_text from sp_spar_product join sc_spar_category on sp_sc_category = sc_id order by sp_spar_id",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            products,
+            [
+                (
+                    "2020001890507".to_string(),
+                    "SPAR Natur*pur Bio-Karotten".to_string(),
+                    "SPAR Natur*pur".to_string(),
+                    "Vegetables".to_string()
+                ),
+                (
+                    "2020003040115".to_string(),
+                    "Paradeiser Rispen".to_string(),
+                    "".to_string(),
+                    "Vegetables".to_string()
+                ),
+                (
+                    "6010200000101".to_string(),
+                    "S-BUDGET Bananen".to_string(),
+                    "S-BUDGET".to_string(),
+                    "Vegetables".to_string()
+                ),
+            ]
         );
 
-        let query = query_builder.build();
-        query.execute(pool).await?;
-
-        Ok(())
-    }
-
-    async fn execute(pool: &PgPool, crawl_id: Uuid) -> Result<()> {
-        let client = Client::builder()
-            .user_agent(random_user_agent())
-            .gzip(true)
-            .build()?;
-
-        let category_map = Self::get_or_add_categories(&pool).await?;
-
-        let semaphore = Arc::new(Semaphore::new(3));
-        let mut set = JoinSet::new();
-
-        for category in Self::Category::iter() {
-            let semaphore = semaphore.clone();
-            let client = client.clone();
-            let pool = pool.clone();
-            let crawl_id = crawl_id.clone();
-
-            set.spawn(async move {
-                let permit = semaphore.acquire().await.unwrap();
-
-                println!("start with: {:?}", category);
-
-                let products = Self::download_category(crawl_id, client, &pool, category).await;
-
-                drop(permit);
-
-                println!("finish with: {:?}", category);
-
-                (products, category)
-            });
-        }
-
-        let mut products_lists = Vec::new();
-
-        while let Some(res) = set.join_next().await {
-            match res {
-                Ok((Ok(products), category)) => products_lists.push((category, products)),
-                err => println!("err: {:?}", err),
-            }
-        }
-
-        println!(
-            "products: {}",
-            products_lists
-                .iter()
-                .map(|(_, item)| item.len())
-                .sum::<usize>()
+        let prices: Vec<(String, Decimal, String, String)> = sqlx::query_as(
+            "select sp_spar_id, spr_price, spr_sales_unit, spr_price_unit from spr_spar_price join sp_spar_product on spr_sp_product = sp_id order by sp_spar_id",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(prices.len(), 3);
+        assert_eq!(
+            prices[2],
+            (
+                "6010200000101".to_string(),
+                dec!(1.49),
+                "KG".to_string(),
+                "1.49 €/kg".to_string()
+            )
         );
-        println!("Start with inserting into db");
-
-        let semaphore = Arc::new(Semaphore::new(20));
-        let mut set = JoinSet::new();
-
-        let now = Instant::now();
-
-        for (category, products) in products_lists {
-            let semaphore = semaphore.clone();
-            let pool = pool.clone();
-            let category_map = category_map.clone();
-
-            set.spawn(async move {
-                let permit = semaphore.acquire().await.unwrap();
-
-                let status = Self::insert_products(&pool, category_map, category, products).await;
-
-                drop(permit);
-
-                status
-            });
-        }
-
-        while let Some(res) = set.join_next().await {
-            let _ = res;
-        }
 
-        let duration = now.elapsed();
-        println!("took: {:?} ms", duration.as_millis());
+        let pages: (i64,) =
+            sqlx::query_as("select count(*) from sr_spar_raw where sr_cs_crawl_session = $1")
+                .bind(crawl_id)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(pages.0, 15);
 
-        Ok(())
+        db.close().await;
     }
 }
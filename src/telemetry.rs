@@ -0,0 +1,228 @@
+//! Structured logging of the crawler with spans per crawl session, store, category and page.
+//!
+//! The logs are written to stderr, so the output of the commands on stdout stays untouched. Which
+//! events are logged is chosen with `RUST_LOG`, e.g. `RUST_LOG=austria_online_grocery_store=debug`,
+//! the default is `info`.
+
+use anyhow::Result;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    /// One JSON object per line with the fields of the event and its spans.
+    Json,
+}
+
+/// Keeps the exporter of the traces alive, the remaining traces are sent when it's dropped.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("err: otlp shutdown {:?}", err);
+            }
+        }
+    }
+}
+
+/// Logs to stderr in `format` and exports the traces to the OTLP/HTTP endpoint `otlp`, e.g.
+/// `http://localhost:4318/v1/traces`, if given.
+pub fn init(format: LogFormat, otlp: Option<&str>) -> Result<Telemetry> {
+    let (otlp, telemetry): (Option<Box<dyn Layer<_> + Send + Sync>>, _) = match otlp {
+        #[cfg(feature = "otlp")]
+        Some(endpoint) => {
+            let (layer, provider) = otlp_layer(endpoint)?;
+
+            (
+                Some(layer),
+                Telemetry {
+                    provider: Some(provider),
+                },
+            )
+        }
+        #[cfg(not(feature = "otlp"))]
+        Some(_) => anyhow::bail!("--otlp needs a build with the otlp feature"),
+        None => (None, Telemetry::default()),
+    };
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(layer(format, std::io::stderr))
+        .with(otlp)
+        .with(filter)
+        .try_init()?;
+
+    Ok(telemetry)
+}
+
+/// Events in `format` written to `writer`, closed spans are logged with their duration.
+pub fn layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Json => layer.json().with_span_list(true).boxed(),
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<(
+    Box<dyn Layer<S> + Send + Sync>,
+    opentelemetry_sdk::trace::SdkTracerProvider,
+)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer).boxed();
+
+    Ok((layer, provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tracing::Instrument;
+
+    use super::*;
+    use crate::http::{Fetcher, Mode};
+    use crate::storage::{FileStorage, Storage};
+    use crate::stores::billa::BillaCrawl;
+    use crate::stores::ExecuteCrawler;
+    use crate::test_support::mock_billa;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn json_logs_carry_span_fields() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let _guard = tracing_subscriber::registry()
+            .with(layer(LogFormat::Json, move || writer.clone()))
+            .set_default();
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::open(dir.path()).await.unwrap();
+        let server = mock_billa().await;
+
+        let crawl_id = storage.create_crawl().await.unwrap();
+        BillaCrawl::execute(
+            &storage,
+            crawl_id,
+            Fetcher::new(Mode::Live).unwrap(),
+            &server.uri(),
+        )
+        .instrument(tracing::info_span!("crawl", %crawl_id))
+        .await
+        .unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        let page = lines
+            .iter()
+            .find(|line| {
+                line["fields"]["message"] == "close"
+                    && line["span"]["name"] == "page"
+                    && line["span"]["url"].as_str().unwrap().contains("B2-2")
+            })
+            .unwrap();
+        assert_eq!(page["span"]["status"], 200);
+        assert_eq!(page["span"]["product_count"], 2);
+        assert!(page["fields"]["time.busy"].is_string());
+
+        let spans = page["spans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|span| span["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(spans, ["crawl", "category"]);
+        assert_eq!(page["spans"][0]["crawl_id"], crawl_id.to_string());
+        assert_eq!(page["spans"][1]["category"], "Bread");
+    }
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn traces_are_exported_to_collector() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let collector = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/traces"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+
+        let (layer, provider) = otlp_layer(&format!("{}/v1/traces", collector.uri())).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("crawl", crawl_id = "test").in_scope(|| {
+                tracing::info!("crawl started");
+            });
+        });
+
+        tokio::task::spawn_blocking(move || provider.shutdown().unwrap())
+            .await
+            .unwrap();
+    }
+}
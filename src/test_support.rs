@@ -0,0 +1,193 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::types::Uuid;
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::http::{Fetcher, Mode};
+use crate::storage::postgres::PgStorage;
+use crate::storage::Storage;
+use crate::stores::billa::BillaCrawl;
+use crate::stores::ExecuteCrawler;
+
+/// Connection string of a postgres server the tests are allowed to create databases on.
+pub const DATABASE_URL_ENV: &str = "TEST_DATABASE_URL";
+
+/// Set by CI services, the tests needing a database fail without one then.
+const CI_ENV: &str = "CI";
+
+pub fn fixtures_dir(store: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(store)
+}
+
+pub fn fixture(store: &str, name: &str) -> String {
+    std::fs::read_to_string(fixtures_dir(store).join(name)).unwrap()
+}
+
+/// Starts a local server answering like the shop API with the fixtures of `store`.
+///
+/// A fixture named `<category>_<page>.json` is served for requests to `path` whose query
+/// parameters `category_param` and `page` match, every other request gets `empty.json`.
+pub async fn mock_store(
+    store: &str,
+    api_path: &str,
+    category_param: &str,
+    category_prefix: &str,
+) -> MockServer {
+    mock_slow_store(
+        store,
+        api_path,
+        category_param,
+        category_prefix,
+        Duration::ZERO,
+    )
+    .await
+}
+
+/// [`mock_store`] with the fixtures of billa.
+pub async fn mock_billa() -> MockServer {
+    mock_store("billa", "/api/search/full", "category", "").await
+}
+
+/// Crawls billa from `server` into a new crawl session of `storage`, returns its id. Panics
+/// unless every category was crawled.
+pub async fn crawl_billa<S: Storage>(storage: &S, server: &MockServer) -> Uuid {
+    let crawl_id = storage.create_crawl().await.unwrap();
+    let complete = BillaCrawl::execute(
+        storage,
+        crawl_id,
+        Fetcher::new(Mode::Live).unwrap(),
+        &server.uri(),
+    )
+    .await
+    .unwrap();
+    assert!(complete, "crawl {} is incomplete", crawl_id);
+
+    crawl_id
+}
+
+/// Like [`mock_store`], but every response takes `delay`.
+pub async fn mock_slow_store(
+    store: &str,
+    api_path: &str,
+    category_param: &str,
+    category_prefix: &str,
+    delay: Duration,
+) -> MockServer {
+    let server = MockServer::start().await;
+
+    for entry in std::fs::read_dir(fixtures_dir(store)).unwrap() {
+        let file_name = entry.unwrap().file_name().into_string().unwrap();
+
+        let Some((category, page)) = file_name
+            .strip_suffix(".json")
+            .and_then(|name| name.rsplit_once('_'))
+        else {
+            continue;
+        };
+
+        Mock::given(method("GET"))
+            .and(path(api_path))
+            .and(query_param(
+                category_param,
+                format!("{}{}", category_prefix, category),
+            ))
+            .and(query_param("page", page))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(fixture(store, &file_name))
+                    .set_delay(delay),
+            )
+            .mount(&server)
+            .await;
+    }
+
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string(fixture(store, "empty.json"))
+                .set_delay(delay),
+        )
+        .with_priority(u8::MAX)
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// A freshly created database with the schema of `ddl.sql`, dropped again by [`Self::close`].
+pub struct TestDatabase {
+    pub pool: PgPool,
+    /// The storage of the crawlers on `pool`.
+    pub storage: PgStorage,
+    options: PgConnectOptions,
+    name: String,
+}
+
+impl TestDatabase {
+    /// Returns `None` if no test database server is configured, which fails the test on CI
+    /// instead, so the tests needing a database can't be skipped there unnoticed.
+    pub async fn create() -> Option<Self> {
+        let Ok(url) = std::env::var(DATABASE_URL_ENV) else {
+            if std::env::var_os(CI_ENV).is_some() {
+                panic!("{} is not set on CI", DATABASE_URL_ENV);
+            }
+
+            eprintln!("{} is not set, skipping test", DATABASE_URL_ENV);
+            return None;
+        };
+
+        let options = PgConnectOptions::from_str(&url).unwrap();
+        let name = format!("crawler_test_{}", rand::random::<u32>());
+
+        let mut connection = PgConnection::connect_with(&options).await.unwrap();
+        connection
+            .execute(format!("create database {}", name).as_str())
+            .await
+            .unwrap();
+        connection.close().await.unwrap();
+
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect_with(options.clone().database(&name))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../ddl.sql")).await.unwrap();
+        crate::partition::maintain(&pool, None).await.unwrap();
+
+        Some(TestDatabase {
+            storage: PgStorage::new(pool.clone()),
+            pool,
+            options,
+            name,
+        })
+    }
+
+    pub async fn crawl_id(&self) -> sqlx::types::Uuid {
+        let crawl_id: (sqlx::types::Uuid,) =
+            sqlx::query_as("INSERT INTO bcw_billa_crawl DEFAULT VALUES RETURNING bcw_id")
+                .fetch_one(&self.pool)
+                .await
+                .unwrap();
+
+        crawl_id.0
+    }
+
+    pub async fn close(self) {
+        self.pool.close().await;
+
+        let mut connection = PgConnection::connect_with(&self.options).await.unwrap();
+        connection
+            .execute(format!("drop database {} with (force)", self.name).as_str())
+            .await
+            .unwrap();
+        connection.close().await.unwrap();
+    }
+}
@@ -0,0 +1,490 @@
+//! Watchlist of products or search terms with rules which raise alerts after a crawl.
+//!
+//! The rules are evaluated against the prices stored by a crawl session, only prices which
+//! changed or are new in that session can raise an alert.
+
+pub mod sink;
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tracing::error;
+
+use crate::model::Store;
+use crate::output::{Format, Table};
+use crate::query::ts_query;
+use crate::watch::sink::Sink;
+
+/// When a watched product raises an alert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// The price is below the amount.
+    Below(Decimal),
+    /// The price dropped by more than the percentage.
+    Drop(Decimal),
+    /// The product went on promotion. Only Billa tells which prices are promotions.
+    Promotion,
+}
+
+impl Rule {
+    fn name(&self) -> &'static str {
+        match self {
+            Rule::Below(_) => "below",
+            Rule::Drop(_) => "drop",
+            Rule::Promotion => "promotion",
+        }
+    }
+
+    fn threshold(&self) -> Option<Decimal> {
+        match self {
+            Rule::Below(threshold) | Rule::Drop(threshold) => Some(*threshold),
+            Rule::Promotion => None,
+        }
+    }
+
+    fn from_parts(name: &str, threshold: Option<Decimal>) -> Result<Self> {
+        let threshold = || threshold.ok_or_else(|| anyhow!("rule {} needs a threshold", name));
+
+        let rule = match name {
+            "below" => Rule::Below(threshold()?),
+            "drop" => Rule::Drop(threshold()?),
+            "promotion" => Rule::Promotion,
+            _ => bail!("unknown rule {}", name),
+        };
+
+        Ok(rule)
+    }
+
+    pub fn matches(&self, change: &PriceChange) -> bool {
+        match self {
+            Rule::Below(threshold) => change.price < *threshold,
+            Rule::Drop(percent) => match change.previous_price {
+                Some(previous) if !previous.is_zero() => {
+                    (previous - change.price) / previous * Decimal::ONE_HUNDRED > *percent
+                }
+                _ => false,
+            },
+            Rule::Promotion => change.discounted && !change.previously_discounted,
+        }
+    }
+}
+
+/// Parses `below:<price>`, `drop:<percent>` or `promotion`.
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, threshold) = match s.split_once(':') {
+            Some((name, threshold)) => {
+                let threshold = threshold
+                    .trim_end_matches('%')
+                    .parse()
+                    .map_err(|_| anyhow!("invalid threshold {:?}", threshold))?;
+
+                (name, Some(threshold))
+            }
+            None => (s, None),
+        };
+
+        Rule::from_parts(name, threshold)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Below(price) => write!(f, "below:{}", price),
+            Rule::Drop(percent) => write!(f, "drop:{}%", percent),
+            Rule::Promotion => write!(f, "promotion"),
+        }
+    }
+}
+
+impl Serialize for Rule {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Which products a rule is watching, a watch without article id and search watches every
+/// product of its store, or of both stores without a store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Watch {
+    pub id: Uuid,
+    pub store: Option<Store>,
+    pub article_id: Option<String>,
+    /// Words of the name, brand or description, matched like the search command does.
+    pub search: Option<String>,
+    pub rule: Rule,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WatchRow {
+    wl_id: Uuid,
+    wl_store: Option<String>,
+    wl_article_id: Option<String>,
+    wl_search: Option<String>,
+    wl_rule: String,
+    wl_threshold: Option<Decimal>,
+}
+
+impl TryFrom<WatchRow> for Watch {
+    type Error = anyhow::Error;
+
+    fn try_from(row: WatchRow) -> Result<Self> {
+        Ok(Watch {
+            id: row.wl_id,
+            store: row.wl_store.as_deref().map(str::parse).transpose()?,
+            article_id: row.wl_article_id,
+            search: row.wl_search,
+            rule: Rule::from_parts(&row.wl_rule, row.wl_threshold.map(|t| t.normalize()))?,
+        })
+    }
+}
+
+pub async fn add_watch(
+    pool: &PgPool,
+    store: Option<Store>,
+    article_id: Option<&str>,
+    search: Option<&str>,
+    rule: Rule,
+) -> Result<Watch> {
+    if article_id.is_some() && store.is_none() {
+        bail!("a watched article needs its store");
+    }
+    if let Some(search) = search {
+        if ts_query(search).is_none() {
+            bail!("search {:?} has no words to watch", search);
+        }
+    }
+
+    let row: WatchRow = sqlx::query_as(
+        "insert into wl_watch (wl_store, wl_article_id, wl_search, wl_rule, wl_threshold)
+        values ($1, $2, $3, $4, $5)
+        returning wl_id, wl_store, wl_article_id, wl_search, wl_rule, wl_threshold",
+    )
+    .bind(store.map(|store| store.to_string()))
+    .bind(article_id)
+    .bind(search)
+    .bind(rule.name())
+    .bind(rule.threshold())
+    .fetch_one(pool)
+    .await?;
+
+    row.try_into()
+}
+
+pub async fn watches(pool: &PgPool) -> Result<Vec<Watch>> {
+    let rows: Vec<WatchRow> = sqlx::query_as(
+        "select wl_id, wl_store, wl_article_id, wl_search, wl_rule, wl_threshold
+        from wl_watch
+        order by wl_created, wl_id",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter().map(Watch::try_from).collect()
+}
+
+/// Returns if there was a watch with the id.
+pub async fn remove_watch(pool: &PgPool, id: Uuid) -> Result<bool> {
+    let result = sqlx::query("delete from wl_watch where wl_id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub fn render_watches(watches: &[Watch], format: Format) -> Result<String> {
+    let mut table = Table::new(["id", "store", "article", "search", "rule"]);
+    for watch in watches {
+        table.push([
+            watch.id.to_string(),
+            watch
+                .store
+                .map(|store| store.to_string())
+                .unwrap_or_default(),
+            watch.article_id.clone().unwrap_or_default(),
+            watch.search.clone().unwrap_or_default(),
+            watch.rule.to_string(),
+        ]);
+    }
+
+    let output = match format {
+        Format::Json => serde_json::to_string_pretty(watches)?,
+        Format::Table => table.to_text(),
+        Format::Markdown => table.to_markdown(),
+    };
+
+    Ok(output)
+}
+
+/// A price inserted by a crawl session together with the one it replaced.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct PriceChange {
+    pub store: String,
+    pub article_id: String,
+    pub name: String,
+    pub price: Decimal,
+    /// `None` for a product seen for the first time or again after it was delisted.
+    pub previous_price: Option<Decimal>,
+    pub discounted: bool,
+    pub previously_discounted: bool,
+}
+
+/// Prices whose interval starts with the crawl session `$1` and that are watched by a watch of
+/// the store `$2`, article id `$3` and text as `tsquery` `$4`.
+const NEW_PRICES: &str = "
+    select store, article_id, name, price, previous_price, discounted, previously_discounted
+    from (
+        select 'billa' as store, bpo_billa_id as article_id, bpo_name as name, price.bp_normal as price, previous.bp_normal as previous_price, price.bp_discounted as discounted, coalesce(previous.bp_discounted, false) as previously_discounted, bpo_search as search
+        from bp_billa_price price
+        join bcw_billa_crawl on price.bp_last_bcw_crawl = bcw_id and price.bp_valid_from = bcw_created
+        join bpo_billa_product on price.bp_bpo_product = bpo_id
+        left join bp_billa_price previous on previous.bp_bpo_product = price.bp_bpo_product and previous.bp_valid_to = price.bp_valid_from
+        where bcw_id = $1
+        union all
+        select 'spar' as store, sp_spar_id as article_id, sp_name as name, price.spr_price as price, previous.spr_price as previous_price, false as discounted, false as previously_discounted, sp_search as search
+        from spr_spar_price price
+        join bcw_billa_crawl on price.spr_last_cs_crawl_session = bcw_id and price.spr_valid_from = bcw_created
+        join sp_spar_product on price.spr_sp_product = sp_id
+        left join spr_spar_price previous on previous.spr_sp_product = price.spr_sp_product and previous.spr_valid_to = price.spr_valid_from
+        where bcw_id = $1
+    ) prices
+    where ($2::text is null or store = $2)
+        and ($3::text is null or article_id = $3)
+        and ($4::text is null or search @@ to_tsquery('german', $4))
+    order by store, article_id";
+
+/// A price of a crawl session which matched the rule of a watch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub watch_id: Uuid,
+    pub crawl_id: Uuid,
+    pub store: Store,
+    pub article_id: String,
+    pub name: String,
+    pub price: Decimal,
+    pub previous_price: Option<Decimal>,
+    pub rule: Rule,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.store, self.article_id, self.name, self.price
+        )?;
+        if let Some(previous_price) = self.previous_price {
+            write!(f, " (was {})", previous_price)?;
+        }
+
+        write!(f, ", {}", self.rule)
+    }
+}
+
+/// Evaluates every watch against the prices inserted by the crawl session `crawl_id`.
+pub async fn evaluate(pool: &PgPool, crawl_id: Uuid) -> Result<Vec<Alert>> {
+    let mut alerts = Vec::new();
+
+    for watch in watches(pool).await? {
+        let ts_query = watch.search.as_deref().and_then(ts_query);
+        if watch.search.is_some() && ts_query.is_none() {
+            continue;
+        }
+
+        let changes: Vec<PriceChange> = sqlx::query_as(NEW_PRICES)
+            .bind(crawl_id)
+            .bind(watch.store.map(|store| store.to_string()))
+            .bind(&watch.article_id)
+            .bind(&ts_query)
+            .fetch_all(pool)
+            .await?;
+
+        for change in changes {
+            let change = PriceChange {
+                price: change.price.normalize(),
+                previous_price: change.previous_price.map(|price| price.normalize()),
+                ..change
+            };
+
+            if watch.rule.matches(&change) {
+                alerts.push(Alert {
+                    watch_id: watch.id,
+                    crawl_id,
+                    store: change.store.parse()?,
+                    article_id: change.article_id,
+                    name: change.name,
+                    price: change.price,
+                    previous_price: change.previous_price,
+                    rule: watch.rule,
+                });
+            }
+        }
+    }
+
+    Ok(alerts)
+}
+
+/// Evaluates the watchlist against the crawl session `crawl_id` and sends the alerts to every
+/// sink, a sink which fails doesn't keep the others from getting them.
+pub async fn send_alerts(pool: &PgPool, crawl_id: Uuid, sinks: &[Sink]) -> Result<()> {
+    if sinks.is_empty() {
+        return Ok(());
+    }
+
+    let alerts = evaluate(pool, crawl_id).await?;
+    if !alerts.is_empty() {
+        for sink in sinks {
+            if let Err(err) = sink.send(&alerts).await {
+                error!(?sink, ?err, "sending alerts failed");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::model::Details;
+    use crate::query::products;
+    use crate::storage::Storage;
+    use crate::test_support::{crawl_billa, mock_billa, TestDatabase};
+
+    fn change(price: Decimal, previous_price: Option<Decimal>, discounted: bool) -> PriceChange {
+        PriceChange {
+            store: "billa".to_string(),
+            article_id: "1".to_string(),
+            name: "product 1".to_string(),
+            price,
+            previous_price,
+            discounted,
+            previously_discounted: false,
+        }
+    }
+
+    #[test]
+    fn rules_parse_and_match() {
+        assert_eq!(
+            "below:1.99".parse::<Rule>().unwrap(),
+            Rule::Below(dec!(1.99))
+        );
+        assert_eq!("drop:10%".parse::<Rule>().unwrap(), Rule::Drop(dec!(10)));
+        assert_eq!("promotion".parse::<Rule>().unwrap(), Rule::Promotion);
+        assert!("below".parse::<Rule>().is_err());
+        assert!("above:1".parse::<Rule>().is_err());
+        assert_eq!(Rule::Drop(dec!(10)).to_string(), "drop:10%");
+
+        assert!(Rule::Below(dec!(2)).matches(&change(dec!(1.99), None, false)));
+        assert!(!Rule::Below(dec!(2)).matches(&change(dec!(2), None, false)));
+
+        let drop = Rule::Drop(dec!(10));
+        assert!(drop.matches(&change(dec!(0.89), Some(dec!(1.00)), false)));
+        assert!(!drop.matches(&change(dec!(0.90), Some(dec!(1.00)), false)));
+        assert!(!drop.matches(&change(dec!(0.50), None, false)));
+
+        assert!(Rule::Promotion.matches(&change(dec!(1), Some(dec!(1.5)), true)));
+        let still_discounted = PriceChange {
+            previously_discounted: true,
+            ..change(dec!(1), Some(dec!(1.2)), true)
+        };
+        assert!(!Rule::Promotion.matches(&still_discounted));
+    }
+
+    #[tokio::test]
+    async fn alerts_of_new_prices() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let server = mock_billa().await;
+
+        let below = add_watch(&db.pool, None, None, Some("toast"), Rule::Below(dec!(2.50)))
+            .await
+            .unwrap();
+        let drop = add_watch(
+            &db.pool,
+            Some(Store::Billa),
+            Some("00-384201"),
+            None,
+            Rule::Drop(dec!(10)),
+        )
+        .await
+        .unwrap();
+        let promotion = add_watch(&db.pool, Some(Store::Billa), None, None, Rule::Promotion)
+            .await
+            .unwrap();
+        // it would never match
+        assert!(
+            add_watch(&db.pool, None, None, Some("!!!"), Rule::Promotion)
+                .await
+                .is_err()
+        );
+        assert_eq!(watches(&db.pool).await.unwrap().len(), 3);
+
+        let first = crawl_billa(&db.storage, &server).await;
+        assert!(evaluate(&db.pool, first).await.unwrap().is_empty());
+
+        // the toast gets cheaper and another product goes on promotion in the second crawl
+        let mut changed = Vec::new();
+        let second = db.storage.create_crawl().await.unwrap();
+        let document_id = db
+            .storage
+            .save_document(Store::Billa, second, "changed", "{}")
+            .await
+            .unwrap();
+        for mut product in products(&db.pool, Store::Billa, first).await.unwrap() {
+            match product.article_id.as_str() {
+                "00-384201" => product.price = dec!(2.49),
+                "00-536990" => {
+                    if let Details::Billa { discounted, .. } = &mut product.details {
+                        *discounted = true;
+                    }
+                }
+                _ => continue,
+            }
+
+            let product_id = db
+                .storage
+                .get_or_add_product(Uuid::nil(), &product)
+                .await
+                .unwrap();
+            changed.push((product_id, document_id, product));
+        }
+        db.storage.save_prices(&changed).await.unwrap();
+
+        let alerts = evaluate(&db.pool, second).await.unwrap();
+        let fired = alerts
+            .iter()
+            .map(|alert| (alert.watch_id, alert.article_id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fired,
+            [
+                (below.id, "00-384201"),
+                (drop.id, "00-384201"),
+                (promotion.id, "00-536990")
+            ]
+        );
+        assert_eq!(alerts[1].previous_price, Some(dec!(2.79)));
+        assert_eq!(
+            alerts[1].to_string(),
+            "billa 00-384201 Ölz Toastbrot: 2.49 (was 2.79), drop:10%"
+        );
+
+        assert!(remove_watch(&db.pool, below.id).await.unwrap());
+        assert!(!remove_watch(&db.pool, below.id).await.unwrap());
+
+        db.close().await;
+    }
+}
@@ -0,0 +1,340 @@
+//! Where alerts are delivered to.
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use reqwest::Url;
+use serde_json::json;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use super::Alert;
+
+/// A sink parsed from `file:<path>`, an `http(s)://` webhook url or
+/// `smtp://<host>:<port>?from=<address>&to=<address>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    /// Appends every alert as JSON line to the file.
+    File(PathBuf),
+    /// Posts the alerts as `{"alerts": [...]}` to the url.
+    Webhook(Url),
+    Smtp(SmtpSink),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpSink {
+    /// `host:port` of the mail server.
+    pub address: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl FromStr for Sink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("file:") {
+            return Ok(Sink::File(PathBuf::from(path)));
+        }
+
+        let url = Url::parse(s).with_context(|| format!("invalid sink {:?}", s))?;
+        let sink = match url.scheme() {
+            "http" | "https" => Sink::Webhook(url),
+            "smtp" => {
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| anyhow!("smtp sink needs a host"))?;
+                let query = |key| {
+                    url.query_pairs()
+                        .filter(|(name, _)| name == key)
+                        .map(|(_, value)| value.into_owned())
+                        .collect::<Vec<_>>()
+                };
+
+                let (Some(from), to) = (query("from").pop(), query("to")) else {
+                    bail!("smtp sink needs a from address");
+                };
+                if to.is_empty() {
+                    bail!("smtp sink needs a to address");
+                }
+
+                Sink::Smtp(SmtpSink {
+                    address: format!("{}:{}", host, url.port().unwrap_or(25)),
+                    from,
+                    to,
+                })
+            }
+            scheme => bail!("unknown sink {}", scheme),
+        };
+
+        Ok(sink)
+    }
+}
+
+impl Sink {
+    pub async fn send(&self, alerts: &[Alert]) -> Result<()> {
+        match self {
+            Sink::File(path) => {
+                let mut lines = String::new();
+                for alert in alerts {
+                    lines.push_str(&serde_json::to_string(alert)?);
+                    lines.push('\n');
+                }
+
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(lines.as_bytes()).await?;
+                file.flush().await?;
+            }
+            Sink::Webhook(url) => {
+                reqwest::Client::new()
+                    .post(url.clone())
+                    .json(&json!({ "alerts": alerts }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Sink::Smtp(smtp) => smtp.send(alerts).await?,
+        }
+
+        Ok(())
+    }
+}
+
+impl SmtpSink {
+    /// Sends a single plain text mail without authentication or TLS, meant for a mail server
+    /// on the local network.
+    async fn send(&self, alerts: &[Alert]) -> Result<()> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        expect_reply(&mut reader, 220).await?;
+
+        let mut commands = vec![
+            "HELO localhost".to_string(),
+            format!("MAIL FROM:<{}>", self.from),
+        ];
+        commands.extend(self.to.iter().map(|to| format!("RCPT TO:<{}>", to)));
+        for command in commands {
+            writer
+                .write_all(format!("{}\r\n", command).as_bytes())
+                .await?;
+            expect_reply(&mut reader, 250).await?;
+        }
+
+        writer.write_all(b"DATA\r\n").await?;
+        expect_reply(&mut reader, 354).await?;
+        writer.write_all(self.message(alerts).as_bytes()).await?;
+        expect_reply(&mut reader, 250).await?;
+
+        writer.write_all(b"QUIT\r\n").await?;
+        expect_reply(&mut reader, 221).await?;
+
+        Ok(())
+    }
+
+    /// The mail ending with the line `.`, lines starting with a dot get another one. The names of
+    /// the products are sent as UTF-8 without encoding.
+    fn message(&self, alerts: &[Alert]) -> String {
+        let mut message = format!(
+            "Date: {}\r\nFrom: <{}>\r\nTo: {}\r\nSubject: {} price alerts\r\n\
+            MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\r\n",
+            Utc::now().to_rfc2822(),
+            self.from,
+            self.to
+                .iter()
+                .map(|to| format!("<{}>", to))
+                .collect::<Vec<_>>()
+                .join(", "),
+            alerts.len()
+        );
+        for alert in alerts {
+            let line = alert.to_string();
+            let dot = if line.starts_with('.') { "." } else { "" };
+
+            let _ = write!(message, "{}{}\r\n", dot, line);
+        }
+        message.push_str(".\r\n");
+
+        message
+    }
+}
+
+/// Reads a reply of the server, of several lines if they are separated by `<code>-`.
+async fn expect_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R, code: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("smtp server closed the connection");
+        }
+
+        if !line.starts_with(&code.to_string()) {
+            bail!(
+                "smtp server replied {:?}, expected {}",
+                line.trim_end(),
+                code
+            );
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use sqlx::types::Uuid;
+    use tokio::net::TcpListener;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::model::Store;
+    use crate::watch::Rule;
+
+    fn alerts() -> Vec<Alert> {
+        vec![Alert {
+            watch_id: Uuid::nil(),
+            crawl_id: Uuid::nil(),
+            store: Store::Billa,
+            article_id: "00-384201".to_string(),
+            name: "Ölz Toastbrot".to_string(),
+            price: dec!(2.49),
+            previous_price: Some(dec!(2.79)),
+            rule: Rule::Drop(dec!(10)),
+        }]
+    }
+
+    #[test]
+    fn parse_sinks() {
+        assert_eq!(
+            "file:alerts.jsonl".parse::<Sink>().unwrap(),
+            Sink::File(PathBuf::from("alerts.jsonl"))
+        );
+        assert!(matches!(
+            "https://example.com/hook".parse::<Sink>().unwrap(),
+            Sink::Webhook(_)
+        ));
+        assert_eq!(
+            "smtp://localhost?from=crawler@localhost&to=a@localhost&to=b@localhost"
+                .parse::<Sink>()
+                .unwrap(),
+            Sink::Smtp(SmtpSink {
+                address: "localhost:25".to_string(),
+                from: "crawler@localhost".to_string(),
+                to: vec!["a@localhost".to_string(), "b@localhost".to_string()],
+            })
+        );
+        assert!("smtp://localhost?from=crawler@localhost"
+            .parse::<Sink>()
+            .is_err());
+        assert!("ftp://localhost".parse::<Sink>().is_err());
+    }
+
+    #[tokio::test]
+    async fn file_sink_appends_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = Sink::File(dir.path().join("alerts.jsonl"));
+
+        sink.send(&alerts()).await.unwrap();
+        sink.send(&alerts()).await.unwrap();
+
+        let content = std::fs::read_to_string(dir.path().join("alerts.jsonl")).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let alert: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(alert["article_id"], "00-384201");
+        assert_eq!(alert["previous_price"], "2.79");
+        assert_eq!(alert["rule"], "drop:10%");
+    }
+
+    #[tokio::test]
+    async fn webhook_sink_posts_alerts() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(body_partial_json(
+                json!({ "alerts": [{ "article_id": "00-384201", "price": "2.49" }] }),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let sink: Sink = format!("{}/hook", server.uri()).parse().unwrap();
+        sink.send(&alerts()).await.unwrap();
+
+        let failing: Sink = format!("{}/unknown", server.uri()).parse().unwrap();
+        assert!(failing.send(&alerts()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn smtp_sink_talks_to_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // answers every command like a mail server and returns what the client sent
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut received = String::new();
+
+            writer.write_all(b"220 localhost\r\n").await.unwrap();
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+
+                let reply: &[u8] = if data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("DATA") {
+                    data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 bye\r\n"
+                } else if line.starts_with("HELO") {
+                    b"250-localhost\r\n250 hello\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+
+            received
+        });
+
+        let sink: Sink = format!("smtp://{}?from=crawler@localhost&to=me@localhost", address)
+            .parse()
+            .unwrap();
+        sink.send(&alerts()).await.unwrap();
+
+        let received = server.await.unwrap();
+        assert!(received.starts_with(
+            "HELO localhost\r\nMAIL FROM:<crawler@localhost>\r\nRCPT TO:<me@localhost>\r\nDATA\r\n"
+        ));
+        assert!(received.contains("Subject: 1 price alerts\r\n"));
+        assert!(received.contains("\r\nDate: "));
+        assert!(received.contains("\r\nContent-Type: text/plain; charset=utf-8\r\n"));
+        assert!(received.contains("\r\nContent-Transfer-Encoding: 8bit\r\n\r\n"));
+        assert!(received
+            .contains("billa 00-384201 Ölz Toastbrot: 2.49 (was 2.79), drop:10%\r\n.\r\nQUIT\r\n"));
+    }
+}